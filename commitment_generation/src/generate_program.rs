@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::io::{BufReader, Write};
 use std::{
     fs::{File, OpenOptions},
@@ -20,11 +21,18 @@ use std::{
     path::{Path, PathBuf},
 };
 use zk_iot::json_file::{ClassDataJson, LineValue};
-use zk_iot::parser::{match_reg, parse_line};
-
+use zk_iot::parser::{Gate, RiscvReg};
+
+/// Writes the device program: the input program with the gate outputs of the code block
+/// stored after each gate and gathered into z before `proofGenerator` is called
+///
+/// Only the lines `gates` were parsed from store an output, so labels, branches, stores
+/// and the other lines the parser skips leave z untouched. The block is padded with
+/// `addi s1, s1, 0` up to the `n_g` gates of the class.
 pub fn generate_new_program(
     input_path: &str,
     line_range: LineValue,
+    gates: &[Gate],
     class_data: ClassDataJson,
 ) -> Result<()> {
     // Open the input file
@@ -41,15 +49,27 @@ pub fn generate_new_program(
         .truncate(true)
         .open(output_path)?;
 
-    let LineValue::Range(range) = line_range;
+    let range = match line_range {
+        LineValue::Range(range) => range,
+        LineValue::Ranges(ranges) => match ranges[..] {
+            [range] => range,
+            _ => return Err(anyhow!("the generated program supports a single code_block range")),
+        },
+    };
+
+    let add_no_op_number = n_g.checked_sub(gates.len() as u64).ok_or_else(|| {
+        anyhow!("the code block has {} gates, but the class holds {}", gates.len(), n_g)
+    })?;
 
-    let diff = (range.1 - range.0) as u64;
-    let add_no_op_number = n_g - diff - 1;
+    // Destination register of the gate parsed from each line
+    let gate_lines: HashMap<usize, RiscvReg> =
+        gates.iter().map(|gate| (gate.source_line, gate.des_reg)).collect();
 
     insert_assembly_instructions(
         &mut output_file,
         reader,
         range,
+        &gate_lines,
         (n_g + n_i + 1).try_into()?,
         add_no_op_number,
     )?;
@@ -61,6 +81,7 @@ fn insert_assembly_instructions(
     output_file: &mut File,
     reader: BufReader<File>,
     line_range: (usize, usize),
+    gate_lines: &HashMap<usize, RiscvReg>,
     z_vec_len: usize,
     add_no_op_number: u64,
 ) -> Result<()> {
@@ -80,24 +101,14 @@ fn insert_assembly_instructions(
         writeln!(output_file, "{}", instruction)?;
 
         if num >= line_range.0 && num <= line_range.1 {
-            // Parsing the destination register from the instruction
-            let parse_line = parse_line(&instruction, num)?;
-
-            let registers = parse_line.1;
-            let des = registers[0];
-            let des_reg_num =
-                match_reg(des).ok_or_else(|| anyhow!("Match register faild"))? as usize;
-            array_offset_pair.push((des_reg_num, space_size[des_reg_num]));
-            
-            let x_reg = &format!("x{}", des_reg_num);
-            writeln!(output_file, "la t0, {x_reg}_array")?;
-            writeln!(output_file, "sw {x_reg}, {}(t0)", space_size[des_reg_num])?;
-
-            space_size[des_reg_num] += 4;
+            // Storing the destination register of the gate parsed from this line
+            if let Some(&des_reg) = gate_lines.get(&num) {
+                store_gate_output(output_file, des_reg as usize, &mut space_size, &mut array_offset_pair)?;
+            }
         }
 
         if num == line_range.1 {
-            insert_addi_0(output_file, add_no_op_number)?;
+            insert_addi_0(output_file, add_no_op_number, &mut space_size, &mut array_offset_pair)?;
             insert_z_array(output_file)?;
             insert_z_array_population_code(output_file)?;
 
@@ -118,9 +129,33 @@ fn insert_assembly_instructions(
     Ok(())
 }
 
-fn insert_addi_0(output_file: &mut File, add_no_op_number: u64) -> Result<()> {
+/// Stores register `des_reg_num` in its array, recording where for the z population code
+fn store_gate_output(
+    output_file: &mut File,
+    des_reg_num: usize,
+    space_size: &mut [usize],
+    array_offset_pair: &mut Vec<(usize, usize)>,
+) -> Result<()> {
+    array_offset_pair.push((des_reg_num, space_size[des_reg_num]));
+
+    let x_reg = &format!("x{}", des_reg_num);
+    writeln!(output_file, "la t0, {x_reg}_array")?;
+    writeln!(output_file, "sw {x_reg}, {}(t0)", space_size[des_reg_num])?;
+
+    space_size[des_reg_num] += 4;
+    Ok(())
+}
+
+/// Pads the block with no-op gates, each storing its output like the gates of the block
+fn insert_addi_0(
+    output_file: &mut File,
+    add_no_op_number: u64,
+    space_size: &mut [usize],
+    array_offset_pair: &mut Vec<(usize, usize)>,
+) -> Result<()> {
     for _ in 0..add_no_op_number {
         writeln!(output_file, "addi s1, s1, 0")?;
+        store_gate_output(output_file, RiscvReg::S1 as usize, space_size, array_offset_pair)?;
     }
     Ok(())
 }
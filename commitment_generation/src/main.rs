@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use ahp::setup::Setup;
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 use generate_program::generate_new_program;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use utils::read_json_file;
+use utils::read_json_file_validated;
 
 use clap::Parser;
 use parser::*;
@@ -28,9 +30,9 @@ mod generate_program;
 
 const PROGRAM_PARAMS_PATH: &str = "data/program_params.json";
 const PROGRAM_COMMITMENT_PATH: &str = "data/program_commitment.json";
+const COMMITMENT_CACHE_PATH: &str = "data/commitment_cache.json";
 const CLASS_TABLE: &str = "class.json";
 
-// TODO: get class numebr from args
 /// A program for commitment generation
 #[derive(Parser, Debug)]
 #[command(name = "CommitmentGenerator")]
@@ -47,9 +49,28 @@ struct Args {
     /// Path to the device configuration
     #[arg(required = true)]
     device_config_path: String,
+
+    /// Class to use instead of the smallest one that fits the program
+    #[arg(long)]
+    class: Option<u8>,
+
+    /// Where to write the program parameters
+    #[arg(long, default_value = PROGRAM_PARAMS_PATH)]
+    program_params_path: String,
+
+    /// Where to write the program commitment
+    #[arg(long, default_value = PROGRAM_COMMITMENT_PATH)]
+    program_commitment_path: String,
+
+    /// Where to keep the commitment cache
+    #[arg(long, default_value = COMMITMENT_CACHE_PATH)]
+    commitment_cache_path: String,
 }
 
 fn main() -> Result<()> {
+    // Show info messages unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     // Parse the command-line arguments
     let args = Args::parse();
 
@@ -66,38 +87,40 @@ fn main() -> Result<()> {
     let mut lines_scope: Vec<u64> = classes_data.iter().map(|v| v.1.n_g).collect();
     lines_scope.sort();
 
-    let device_config: DeviceConfigJson = read_json_file(device_config_path)?;
+    let device_config: DeviceConfigJson = read_json_file_validated(device_config_path)?;
 
     // Restore setup data from the specified JSON file
     let setup_json = Setup::restore(setup_path).with_context(|| "Error retrieving setup data")?;
 
     // Convert line ranges to individual line numbers.
-    let lines = DeviceConfigJson::convert_lines(device_config.code_block);
+    let lines = DeviceConfigJson::convert_lines(&device_config.code_block)?;
 
     // Parse opcodes based on the specified line numbers
     let gates = parse_from_lines(lines, &PathBuf::from(program_path))
         .with_context(|| "Error parsing instructions")?;
 
     let gates = ahp::commitment_generation::Commitment::process_gates(gates);
+    ahp::commitment_generation::Commitment::ensure_gates(&gates)?;
 
-    // Get the class number based on the length of the gates
-    let class_number = &get_class_number(gates.len());
+    // Use the requested class, or pick one based on the length of the gates
+    let (class_number, class_data) = select_class(&classes_data, gates.len(), args.class)?;
 
     println_dbg!("class: {}", class_number);
 
-    let p = classes_data[class_number].p;
+    let p = class_data.p;
 
     // Generate new assembly file at program_commitment_path/program_new.s
     generate_new_program(
         program_path,
-        device_config.code_block,
-        classes_data[class_number],
+        device_config.code_block.clone(),
+        &gates,
+        class_data,
     )?;
 
     // .: Commitment :.
-    let commitment = ahp::commitment_generation::Commitment::new(classes_data[class_number])
-        .gen_matrices(gates, classes_data[class_number].n_i.try_into()?, p)
-        .gen_polynomials(p)
+    let mut commitment = ahp::commitment_generation::Commitment::new(class_data)
+        .gen_cached(gates, class_data.n_i.try_into()?, p, &args.commitment_cache_path)
+        .with_context(|| "Error updating commitment cache")?
         .build();
 
     let commitment_polys = commitment.get_polynomials_commitment(&setup_json.get_ck(), p);
@@ -105,26 +128,59 @@ fn main() -> Result<()> {
     let _ = ProgramParamsJson::new(
         &commitment.matrices,
         &commitment.points_px,
-        classes_data[class_number],
+        class_data,
         p
     )
-    .store(PROGRAM_PARAMS_PATH)?;
+    .store(&args.program_params_path)?;
 
     // Store the commitment data in a JSON file
     commitment
         .store(
-            PROGRAM_COMMITMENT_PATH,
-            *class_number,
-            classes_data[class_number],
+            &args.program_commitment_path,
+            class_number,
+            class_data,
             device_config,
         )
         .with_context(|| "Error storing commitment data")?;
 
-    println!("Commitment file generated successfully");
+    log::info!("Commitment file generated successfully");
 
     Ok(())
 }
 
+/// Returns `requested` if that class can hold `gate_count` gates, or the smallest
+/// class that fits when no class was requested, along with its class data
+///
+/// Either way the class must be in `classes_data`: a program too large for every
+/// class in the table is an error listing the classes there are.
+fn select_class(
+    classes_data: &HashMap<u8, ClassDataJson>,
+    gate_count: usize,
+    requested: Option<u8>,
+) -> Result<(u8, ClassDataJson)> {
+    let class_number = requested.unwrap_or_else(|| get_class_number(gate_count));
+
+    let class_data = classes_data.get(&class_number).ok_or_else(|| {
+        let mut available: Vec<u8> = classes_data.keys().copied().collect();
+        available.sort_unstable();
+        anyhow!(
+            "Class {} doesn't exist in {} (available classes: {:?})",
+            class_number,
+            CLASS_TABLE,
+            available
+        )
+    })?;
+    if (class_data.n_g as usize) < gate_count {
+        return Err(anyhow!(
+            "Class {} holds {} gates, but the program has {}",
+            class_number,
+            class_data.n_g,
+            gate_count
+        ));
+    }
+    Ok((class_number, *class_data))
+}
+
 fn get_class_number(len: usize) -> u8 {
     if len == 1 {
         return 1;
@@ -136,3 +192,44 @@ fn get_class_number(len: usize) -> u8 {
     }
     (number as f64).log2() as u8
 }
+
+#[cfg(test)]
+mod main_test {
+    use super::*;
+
+    fn classes() -> HashMap<u8, ClassDataJson> {
+        [(2, 4), (3, 8)]
+            .into_iter()
+            .map(|(class_number, n_g)| {
+                let class_data = ClassDataJson { n_g, n_i: 32, n: 37, m: 8, p: 1678321, g: 11 };
+                (class_number, class_data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn explicit_class_too_small() {
+        let err = select_class(&classes(), 5, Some(2)).unwrap_err();
+        assert_eq!(err.to_string(), "Class 2 holds 4 gates, but the program has 5");
+
+        let err = select_class(&classes(), 5, Some(9)).unwrap_err();
+        assert_eq!(err.to_string(), "Class 9 doesn't exist in class.json (available classes: [2, 3])");
+    }
+
+    #[test]
+    fn explicit_class_larger_than_needed() {
+        assert_eq!(select_class(&classes(), 3, Some(3)).unwrap().0, 3);
+        assert_eq!(select_class(&classes(), 3, None).unwrap().0, 2);
+    }
+
+    #[test]
+    fn circuit_beyond_class_table() {
+        // 9 gates need class 4, past the largest class of the table
+        let err = select_class(&classes(), 9, None).unwrap_err();
+        assert_eq!(err.to_string(), "Class 4 doesn't exist in class.json (available classes: [2, 3])");
+
+        let (class_number, class_data) = select_class(&classes(), 8, None).unwrap();
+        assert_eq!(class_number, 3);
+        assert_eq!(class_data.n_g, 8);
+    }
+}
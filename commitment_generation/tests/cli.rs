@@ -0,0 +1,146 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs the commitment generation binary on small programs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use zk_iot::ahp::setup::Setup;
+use zk_iot::json_file::ClassDataJson;
+
+/// Workspace root, where the binary reads `class.json` from
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap()
+}
+
+/// Creates a scratch directory for `test` holding a class 2 setup in `setup2.json`
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zkiot_cli_{}_{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let class_data = ClassDataJson::get_class_data(root().join("class.json").to_str().unwrap(), 2).unwrap();
+    let mut setup = Setup::default();
+    setup.generate_keys(class_data.d_ahp(), class_data.p, class_data.g);
+    setup.store(dir.join("setup2.json").to_str().unwrap(), 2).unwrap();
+    dir
+}
+
+#[test]
+fn writes_to_custom_output_paths() {
+    let root = root();
+    let dir = scratch_dir("paths");
+    let path = |file: &str| -> PathBuf { dir.join(file) };
+
+    let program = path("sample.s");
+    fs::copy(root.join("tests/fixtures/sample.s"), &program).unwrap();
+
+    let default_params = root.join("data/program_params.json");
+    let modified_before = fs::metadata(&default_params).and_then(|meta| meta.modified()).ok();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_commitment_generation"))
+        .current_dir(root)
+        .arg(&program)
+        .arg(path("setup2.json"))
+        .arg(root.join("tests/fixtures/device_config.json"))
+        .arg("--program-params-path")
+        .arg(path("params.json"))
+        .arg("--program-commitment-path")
+        .arg(path("commitment.json"))
+        .arg("--commitment-cache-path")
+        .arg(path("cache.json"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for file in ["params.json", "commitment.json", "cache.json", "sample_new.s"] {
+        assert!(path(file).is_file(), "{} wasn't written", file);
+    }
+    let modified_after = fs::metadata(&default_params).and_then(|meta| meta.modified()).ok();
+    assert_eq!(modified_before, modified_after, "the default program params were overwritten");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn skips_labels_branches_and_ret_in_the_code_block() {
+    let dir = scratch_dir("function");
+    let path = |file: &str| -> PathBuf { dir.join(file) };
+
+    let program = [
+        "compute:",
+        "    addi a0, a0, 5",
+        "    beq a0, a1, done",
+        "    mul a1, a0, a2",
+        "    nop",
+        "done:",
+        "    add a0, a1, a2",
+        "    ret",
+    ];
+    fs::write(path("function.s"), program.join("\n")).unwrap();
+    let device_config = fs::read_to_string(root().join("tests/fixtures/device_config.json"))
+        .unwrap()
+        .replace("[1, 4]", "[1, 8]");
+    fs::write(path("device_config.json"), device_config).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_commitment_generation"))
+        .current_dir(root())
+        .arg(path("function.s"))
+        .arg(path("setup2.json"))
+        .arg(path("device_config.json"))
+        .arg("--program-params-path")
+        .arg(path("params.json"))
+        .arg("--program-commitment-path")
+        .arg(path("commitment.json"))
+        .arg("--commitment-cache-path")
+        .arg(path("cache.json"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Only the three gates store their output, and one no-op gate pads the block to
+    // the four gates of class 2
+    let generated = fs::read_to_string(path("function_new.s")).unwrap();
+    let block: Vec<&str> = generated
+        .lines()
+        .skip_while(|line| *line != "jal store_register_instances")
+        .skip(1)
+        .take_while(|line| *line != "la a0, z_array")
+        .collect();
+    assert_eq!(
+        block,
+        [
+            "compute:",
+            "    addi a0, a0, 5",
+            "la t0, x10_array",
+            "sw x10, 4(t0)",
+            "    beq a0, a1, done",
+            "    mul a1, a0, a2",
+            "la t0, x11_array",
+            "sw x11, 4(t0)",
+            "    nop",
+            "done:",
+            "    add a0, a1, a2",
+            "la t0, x10_array",
+            "sw x10, 8(t0)",
+            "    ret",
+            "addi s1, s1, 0",
+            "la t0, x9_array",
+            "sw x9, 4(t0)",
+        ]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -12,17 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 use json_file::ClassDataJson;
 use json_file::DeviceConfigJson;
 use json_file::ProgramParamsJson;
-use utils::read_json_file;
+use utils::read_json_file_validated;
 use zk_iot::*;
 use std::fs::File;
 use std::io::{self, BufRead};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use zk_iot::ahp::challenge_hasher::hasher_by_name;
+use zk_iot::ahp::commitment_generation::CommitmentJson;
+use zk_iot::ahp::proof_generation::ProofGeneration;
+use zk_iot::ahp::proof_generation::ProofGenerationJson;
+use zk_iot::ahp::proof_generation::WitnessJson;
+use zk_iot::ahp::setup::SetupJson;
 use zk_iot::ahp::{self, setup::Setup};
 
 const PROGRAM_PARAMS_PATH: &str = "data/program_params.json";
@@ -31,15 +37,57 @@ const DEVICE_CONFIG_PATH: &str = "data/device_config.json";
 const CLASS_TABLE: &str = "class.json";
 const PROOF_PATH: &str = "data/proof.json";
 
+/// Files read and written by the proof generation
+#[derive(Debug, Clone)]
+pub struct ProofPaths {
+    pub program_params: String,
+    pub program_commitment: String,
+    pub device_config: String,
+    pub proof: String,
+    /// Where to save the witness the proof was generated from, if anywhere
+    pub witness: Option<String>,
+    /// Witness saved by an earlier run to prove again instead of `z_vec.txt`, if any
+    pub replay_witness: Option<String>,
+    /// Directory to dump the prover's intermediate polynomials to, if anywhere
+    pub intermediates: Option<String>,
+    /// Name of the challenge hasher (see `hasher_by_name`), SHA-256 if unset
+    pub hasher: Option<String>,
+}
+
+impl Default for ProofPaths {
+    fn default() -> Self {
+        Self {
+            program_params: PROGRAM_PARAMS_PATH.to_string(),
+            program_commitment: PROGRAM_COMMITMENT_PATH.to_string(),
+            device_config: DEVICE_CONFIG_PATH.to_string(),
+            proof: PROOF_PATH.to_string(),
+            witness: None,
+            replay_witness: None,
+            intermediates: None,
+            hasher: None,
+        }
+    }
+}
+
 
 // Exported for use in assembly
 #[export_name = "proofGenerator"]
 pub fn main_proof_gen(setup_path: &str) -> Result<()> {
+    main_proof_gen_with(setup_path, &ProofPaths::default())
+}
+
+/// Same as `main_proof_gen`, with the input and output files taken from `paths`
+pub fn main_proof_gen_with(setup_path: &str, paths: &ProofPaths) -> Result<()> {
     // Load commitment data from the commitment file
-    let commitment_json = ahp::commitment_generation::Commitment::restore(PROGRAM_COMMITMENT_PATH)
+    let commitment_json = ahp::commitment_generation::Commitment::restore(&paths.program_commitment)
         .with_context(|| "Error loading commitment data")?;
     let class_number = commitment_json.info.class;
 
+    // Make sure the commitment belongs to this device
+    let device_config: DeviceConfigJson = read_json_file_validated(&paths.device_config)
+        .with_context(|| "Error loading device config")?;
+    commitment_json.verify_id(&device_config)?;
+
     // Load class data from the JSON file
     let class_data =
         ClassDataJson::get_class_data(CLASS_TABLE, class_number).with_context(|| "Error loading class data")?;
@@ -48,31 +96,143 @@ pub fn main_proof_gen(setup_path: &str) -> Result<()> {
     let setup_json = Setup::restore(setup_path).with_context(|| "Error retrieving setup data")?;
 
     // Load matrices
-    let program_params = ProgramParamsJson::restore(PROGRAM_PARAMS_PATH)?;
+    let program_params = ProgramParamsJson::restore(&paths.program_params)?;
+
+    let proof_json = match &paths.replay_witness {
+        Some(path) => {
+            let witness = WitnessJson::restore(path).with_context(|| "Error loading the witness to replay")?;
+            if (witness.class, witness.p) != (class_number, class_data.p) {
+                bail!(
+                    "witness {} is for class {} (p = {}), but the commitment is for class {} (p = {})",
+                    path,
+                    witness.class,
+                    witness.p,
+                    class_number,
+                    class_data.p
+                );
+            }
+            let proof_generation = configure(witness.prover(), paths)?;
+            generate_proof_with(
+                &proof_generation,
+                witness.random_b,
+                &setup_json,
+                &commitment_json,
+                class_data,
+                program_params,
+                witness.z_vec,
+            )?
+        }
+        None => {
+            let z_vec: Vec<u64> = read_vector_from_file();
+            let random_b = ProofGeneration::gen_random_b(&class_data);
+
+            // Keep the exact witness and randomness, so the proof can be re-derived from them
+            let proof_generation = match &paths.witness {
+                Some(path) => {
+                    let witness =
+                        WitnessJson::new(class_number, class_data.p, &z_vec, random_b, ProofGeneration::gen_seed());
+                    witness.store(path).with_context(|| "Error storing the witness")?;
+                    witness.prover()
+                }
+                None => ProofGeneration::new(),
+            };
+            let proof_generation = configure(proof_generation, paths)?;
+            generate_proof_with(
+                &proof_generation,
+                random_b,
+                &setup_json,
+                &commitment_json,
+                class_data,
+                program_params,
+                z_vec,
+            )?
+        }
+    };
+
+    // Store the generated proof data in a JSON file
+    proof_json.store(&paths.proof).with_context(|| "Error storing proof data")?;
+    log::info!("ProofGeneration file generated successfully");
+
+    Ok(())
+}
+
+/// Generates the proof of `z_vec` for the program behind `commitment` and returns it
+///
+/// Nothing is read from or written to disk, so a service can hand the proof on directly.
+pub fn generate_proof_in_memory(
+    setup: &SetupJson,
+    commitment: &CommitmentJson,
+    class_data: ClassDataJson,
+    params: ProgramParamsJson,
+    z_vec: Vec<u64>,
+) -> Result<ProofGenerationJson> {
+    let random_b = ProofGeneration::gen_random_b(&class_data);
+    generate_proof_with(&ProofGeneration::new(), random_b, setup, commitment, class_data, params, z_vec)
+}
+
+/// Proves the witness saved in `witness` again, with the randomness recorded there
+///
+/// Gives the same proof as the run that saved the witness.
+pub fn generate_proof_from_witness(
+    setup: &SetupJson,
+    commitment: &CommitmentJson,
+    class_data: ClassDataJson,
+    params: ProgramParamsJson,
+    witness: &WitnessJson,
+) -> Result<ProofGenerationJson> {
+    generate_proof_with(
+        &witness.prover(),
+        witness.random_b,
+        setup,
+        commitment,
+        class_data,
+        params,
+        witness.z_vec.clone(),
+    )
+}
 
-    let z_vec: Vec<u64> = read_vector_from_file();
+/// Makes `proof_generation` dump its intermediates and use the hasher `paths` asks for
+fn configure(proof_generation: ProofGeneration, paths: &ProofPaths) -> Result<ProofGeneration> {
+    let proof_generation = match &paths.intermediates {
+        Some(dir) => proof_generation.with_intermediates_dir(dir),
+        None => proof_generation,
+    };
+    Ok(match &paths.hasher {
+        Some(name) => proof_generation.with_hasher(hasher_by_name(name)?),
+        None => proof_generation,
+    })
+}
 
+/// Same as `generate_proof_in_memory`, with the prover configured by `proof_generation`
+fn generate_proof_with(
+    proof_generation: &ProofGeneration,
+    random_b: u64,
+    setup: &SetupJson,
+    commitment: &CommitmentJson,
+    class_data: ClassDataJson,
+    params: ProgramParamsJson,
+    z_vec: Vec<u64>,
+) -> Result<ProofGenerationJson> {
     // .: Proof Generation :.
-    let proof_generation = ahp::proof_generation::ProofGeneration::new();
     // Set timer 
     let timer = std::time::Instant::now();
     let proof_data = proof_generation.generate_proof(
-        &setup_json.get_ck(),
+        &setup.get_ck(),
         class_data,
-        program_params,
-        commitment_json.clone(),
+        params,
+        commitment.clone(),
         z_vec,
+        random_b,
         class_data.p
-    );
-    println!("Proof timer: {:.2} milliseconds", timer.elapsed().as_millis() as f64);
-
-    // Store the generated proof data in a JSON file
-    proof_generation
-        .store(PROOF_PATH, proof_data, class_number, commitment_json.info.commitment_id)
-        .with_context(|| "Error storing proof data")?;
-    println!("ProofGeneration file generated successfully");
+    )?;
+    log::info!("Proof timer: {:.2} milliseconds", timer.elapsed().as_millis() as f64);
 
-    Ok(())
+    Ok(proof_generation.to_json(
+        proof_data,
+        commitment.info.class,
+        commitment.info.commitment_id.clone(),
+        class_data.p,
+    ))
 }
 
 
@@ -95,4 +255,110 @@ fn read_vector_from_file() -> Vec<u64> {
     }
 
     values
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_proof_generation {
+    use zk_iot::ahp::proof_verification::Verification;
+    use zk_iot::ahp::test_utils::fixture;
+    use zk_iot::ahp::test_utils::CLASS_NUMBER;
+
+    use super::*;
+
+    /// Everything the prover needs for the shared fixture's program of class 2
+    struct Inputs {
+        class_data: ClassDataJson,
+        setup: SetupJson,
+        commitment_json: CommitmentJson,
+        params: ProgramParamsJson,
+        z_vec: Vec<u64>,
+    }
+
+    fn inputs() -> Inputs {
+        let fixture = fixture();
+        Inputs {
+            class_data: fixture.class_data,
+            setup: SetupJson::new(&fixture.ck, CLASS_NUMBER),
+            commitment_json: fixture.commitment_json,
+            params: fixture.program_params,
+            z_vec: fixture.z_vec,
+        }
+    }
+
+    fn verify(inputs: &Inputs, proof: &ProofGenerationJson) -> bool {
+        Verification::new(proof)
+            .verify(
+                (&inputs.setup.get_ck(), inputs.setup.get_vk()),
+                inputs.class_data,
+                inputs.commitment_json.get_polys_px(),
+                proof.get_x_vec(),
+                inputs.class_data.g,
+                inputs.class_data.p,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn proof_in_memory_verifies() {
+        let inputs = inputs();
+        let proof = generate_proof_in_memory(
+            &inputs.setup,
+            &inputs.commitment_json,
+            inputs.class_data,
+            inputs.params.clone(),
+            inputs.z_vec.clone(),
+        )
+        .unwrap();
+        assert_eq!(proof.hasher.as_deref(), Some("sha256"));
+        assert!(verify(&inputs, &proof));
+    }
+
+    #[test]
+    fn replayed_witness_reproduces_the_proof() {
+        let inputs = inputs();
+        let class_data = inputs.class_data;
+
+        // Fresh randomness, recorded as main_proof_gen_with does with a witness path
+        let witness = WitnessJson::new(
+            CLASS_NUMBER,
+            class_data.p,
+            &inputs.z_vec,
+            ProofGeneration::gen_random_b(&class_data),
+            ProofGeneration::gen_seed(),
+        );
+        let path = std::env::temp_dir().join(format!("zkiot_replay_witness_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        witness.store(path).unwrap();
+        let prove = |witness: &WitnessJson| {
+            generate_proof_from_witness(&inputs.setup, &inputs.commitment_json, class_data, inputs.params.clone(), witness)
+                .unwrap()
+        };
+        let proof = prove(&witness);
+
+        let replayed = WitnessJson::restore(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(replayed, witness);
+        assert_eq!(prove(&replayed), proof);
+        assert!(verify(&inputs, &proof));
+    }
+
+    #[test]
+    fn configure_picks_the_hasher() {
+        let inputs = inputs();
+        let paths = ProofPaths { hasher: Some("poseidon".to_string()), ..ProofPaths::default() };
+        let prover = configure(ProofGeneration::new(), &paths).unwrap();
+        let proof = generate_proof_with(
+            &prover,
+            2,
+            &inputs.setup,
+            &inputs.commitment_json,
+            inputs.class_data,
+            inputs.params.clone(),
+            inputs.z_vec.clone(),
+        )
+        .unwrap();
+        assert_eq!(proof.hasher.as_deref(), Some("poseidon"));
+
+        let paths = ProofPaths { hasher: Some("blake3".to_string()), ..ProofPaths::default() };
+        assert!(configure(ProofGeneration::new(), &paths).is_err());
+    }
+}
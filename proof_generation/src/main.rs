@@ -14,15 +14,73 @@
 
 
 use anyhow::Result;
-use proof_generation::main_proof_gen;
-use std::env;
+use clap::Parser;
+use proof_generation::{main_proof_gen_with, ProofPaths};
+
+/// A program for proof generation
+#[derive(Parser, Debug)]
+#[command(name = "ProofGenerator")]
+#[command(about = "Generates a proof based on the program commitment and setup files")]
+struct Args {
+    /// Path to the setup file
+    #[arg(required = true)]
+    setup_path: String,
+
+    /// Path to the program parameters
+    #[arg(long)]
+    program_params_path: Option<String>,
+
+    /// Path to the program commitment
+    #[arg(long)]
+    program_commitment_path: Option<String>,
+
+    /// Path to the device configuration
+    #[arg(long)]
+    device_config_path: Option<String>,
+
+    /// Where to write the proof
+    #[arg(long)]
+    proof_path: Option<String>,
+
+    /// Also write the witness the proof was generated from, e.g. to witness_used.json
+    #[arg(long)]
+    witness_path: Option<String>,
+
+    /// Prove a witness saved with --witness-path again, reproducing that run's proof
+    #[arg(long, conflicts_with = "witness_path")]
+    replay_witness: Option<String>,
+
+    /// Also write every intermediate AHP polynomial, as JSON, to a file in this directory
+    #[arg(long, value_name = "DIR")]
+    dump_intermediates: Option<String>,
+
+    /// Hash function to derive the challenges with: sha256 (default) or poseidon
+    #[arg(long, value_name = "NAME")]
+    hasher: Option<String>,
+}
 
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    // Show info messages unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = Args::parse();
+
+    // Paths that aren't given keep their defaults
+    let defaults = ProofPaths::default();
+    let paths = ProofPaths {
+        program_params: args.program_params_path.unwrap_or(defaults.program_params),
+        program_commitment: args.program_commitment_path.unwrap_or(defaults.program_commitment),
+        device_config: args.device_config_path.unwrap_or(defaults.device_config),
+        proof: args.proof_path.unwrap_or(defaults.proof),
+        witness: args.witness_path,
+        replay_witness: args.replay_witness,
+        intermediates: args.dump_intermediates,
+        hasher: args.hasher,
+    };
 
     // Initiate the proof generation process
-    main_proof_gen(&args[1])?;
+    main_proof_gen_with(&args.setup_path, &paths)?;
 
 
     Ok(())
@@ -16,9 +16,10 @@
 
 use anyhow::Context;
 use anyhow::Result;
+use zk_iot::ahp::challenge_hasher::hasher_by_name;
 use zk_iot::ahp::commitment_generation::Commitment;
 use zk_iot::ahp::proof_generation::ProofGeneration;
-use zk_iot::ahp::proof_verification::Verification;
+use zk_iot::ahp::proof_verification::LightVerifier;
 use zk_iot::ahp::setup::Setup;
 use clap::Parser;
 use zk_iot::json_file::ClassDataJson;
@@ -39,9 +40,17 @@ struct Args {
     /// Path to the setup file
     #[arg(required = true)]
     setup_path: String,
+
+    /// Hash function the challenges were derived with: sha256 or poseidon
+    /// (default: the one the proof records, sha256 if it records none)
+    #[arg(long, value_name = "NAME")]
+    hasher: Option<String>,
 }
 
 fn main() -> Result<()> {
+    // Show info messages unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     // Parse the command-line arguments
     let args = Args::parse();
 
@@ -68,18 +77,23 @@ fn main() -> Result<()> {
     let commitment_json = Commitment::restore(program_commitment_path)
         .with_context(|| "Error loading commitment data")?;
 
+    let hasher = match &args.hasher {
+        Some(name) => hasher_by_name(name)?,
+        None => proof_generation.challenge_hasher()?,
+    };
+
     // .: Verification :.
-    let verification = Verification::new(&proof_generation);
+    let verification = LightVerifier::new(&proof_generation).with_hasher(hasher);
     let verification_result = verification.verify(
-        (&setup_json.get_ck(), setup_json.get_vk()), 
+        setup_json.get_vk(),
         class_data, 
         commitment_json.get_polys_px(), 
         proof_generation.get_x_vec(),
         class_data.g,
         class_data.p
-    );
+    )?;
 
-    eprintln!("Verification result: {}", verification_result);
+    log::info!("Verification result: {}", verification_result);
 
     Ok(())
 }
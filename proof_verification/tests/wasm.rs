@@ -0,0 +1,59 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds the verifier for the browser.
+
+use std::path::Path;
+use std::process::Command;
+
+const TARGET: &str = "wasm32-unknown-unknown";
+
+/// Whether rustup lists `TARGET` as installed for the active toolchain
+fn target_installed() -> bool {
+    Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().any(|line| line.trim() == TARGET))
+        .unwrap_or(false)
+}
+
+#[test]
+fn verifier_builds_for_wasm32() {
+    // Skipped locally without the target; CI (where `CI` is set) must have it installed
+    if !target_installed() {
+        assert!(
+            std::env::var_os("CI").is_none(),
+            "the {} target is missing: `rustup target add {}`",
+            TARGET,
+            TARGET
+        );
+        eprintln!("skipping: install the target with `rustup target add {}`", TARGET);
+        return;
+    }
+
+    // A separate target directory keeps this build from waiting on the one running the tests
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let output = Command::new(env!("CARGO"))
+        .current_dir(root)
+        .args(["build", "--target", TARGET, "-p", "proof_verification"])
+        .env("CARGO_TARGET_DIR", root.join("target").join("wasm32-check"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "the verifier doesn't build for {}:\n{}",
+        TARGET,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
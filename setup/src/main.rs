@@ -13,38 +13,55 @@
 // limitations under the License.
 
 
+use std::path::PathBuf;
+
 use anyhow::{Result, Context};
+use clap::Parser;
 use zk_iot::{ahp::setup::Setup, json_file::ClassDataJson, println_dbg};
 
 
 const CLASS_TABLE: &str = "class.json";
+const SETUP_DIR: &str = "data";
+
+/// A program for generating the setup files
+#[derive(Parser, Debug)]
+#[command(name = "Setup")]
+#[command(about = "Generates one setup file per class of the class table")]
+struct Args {
+    /// Directory the `setup<class>.json` files are written to
+    #[arg(long, default_value = SETUP_DIR)]
+    setup_dir: PathBuf,
+}
 
 fn main() -> Result<()> {
+    // Show info messages unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // Parse the command-line arguments
+    let args = Args::parse();
+
     let mut setup = Setup::default();
     
     // Load class data from the JSON file
     let class_data =
         ClassDataJson::get_all_class_data(CLASS_TABLE).with_context(|| "Error loading class data")?;
+    ClassDataJson::validate_all(&class_data)?;
 
     // Create a setup file for each entry in class_data
     for (class_number, metadata) in class_data {
-        // Calculate the D_AHP value using the formula: D_AHP = 12 * n_g
-
-        let d_ahp_vec: Vec<u64> = vec![3 * metadata.n_g + 2 * metadata.n_i + 2, 12 * metadata.n_g];
-        let d_ahp = *d_ahp_vec.iter().max().unwrap();
-
-        let inx = d_ahp_vec.iter().position(|v| *v == d_ahp).unwrap();
-        println_dbg!("class_number {class_number}: inx {}, number: {}", inx, d_ahp_vec[inx]);
+        // Calculate the D_AHP value: max(3 * n_g + 2 * n_i + 2, 12 * n_g)
+        let d_ahp = metadata.d_ahp();
+        println_dbg!("class_number {class_number}: d_ahp: {}", d_ahp);
 
         // Generate cryptographic keys for the setup
         setup.generate_keys(d_ahp, metadata.p, metadata.g);
 
         // Save the generated setup data to a JSON file
         setup
-            .store(&format!("data/setup{}.json", class_number), class_number)
+            .store(&args.setup_dir.join(format!("setup{}.json", class_number)).to_string_lossy(), class_number)
             .with_context(|| "Error saving setup file")?;
     }
     
-    println!("Setup file generated successfully");
+    log::info!("Setup file generated successfully");
     Ok(())
 }
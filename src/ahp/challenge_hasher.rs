@@ -0,0 +1,215 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hash functions the `Transcript` can derive its challenges with.
+//!
+//! The prover and the verifier must use the same hasher: a proof checked with
+//! another hasher sees different challenges and fails. Proof files record the
+//! name of their hasher, which the verifier compares with its own.
+
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::field::fmath;
+use crate::utils::sha2_hash_lower_32bit_parts;
+
+/// Turns the bytes of a transcript entry into a challenge
+pub trait ChallengeHasher: std::fmt::Debug + Send + Sync {
+    /// Name recorded in proof files, e.g. `"sha256"`
+    fn name(&self) -> &'static str;
+
+    /// Hashes `input` into a challenge for the field of modulus `p`
+    fn hash_to_field(&self, input: &[u8], p: u64) -> u64;
+}
+
+impl<H: ChallengeHasher + ?Sized> ChallengeHasher for Arc<H> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn hash_to_field(&self, input: &[u8], p: u64) -> u64 {
+        (**self).hash_to_field(input, p)
+    }
+}
+
+/// Names of the hashers `hasher_by_name` knows, the default first
+pub const HASHER_NAMES: [&str; 2] = ["sha256", "poseidon"];
+
+/// Returns the hasher that records `name` in proof files
+///
+/// # Errors
+/// Fails if no hasher goes by that name.
+pub fn hasher_by_name(name: &str) -> Result<Arc<dyn ChallengeHasher>> {
+    match name {
+        "sha256" => Ok(Arc::new(Sha256Hasher)),
+        "poseidon" => Ok(Arc::new(PoseidonHasher)),
+        _ => bail!("unknown challenge hasher {}, expected one of {}", name, HASHER_NAMES.join(", ")),
+    }
+}
+
+/// SHA-256, keeping the lower 32 bits of the digest
+///
+/// The value isn't reduced modulo `p`, which is how every proof so far derived its
+/// challenges; this is the default hasher.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Hasher;
+
+impl ChallengeHasher for Sha256Hasher {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash_to_field(&self, input: &[u8], _p: u64) -> u64 {
+        sha2_hash_lower_32bit_parts(&[input])
+    }
+}
+
+/// Width of the Poseidon state: a rate of 2 elements and a capacity of 1
+const POSEIDON_WIDTH: usize = 3;
+/// Number of full rounds, half of them before the partial rounds
+const POSEIDON_FULL_ROUNDS: usize = 8;
+/// Number of partial rounds, whose S-box only touches the first element
+const POSEIDON_PARTIAL_ROUNDS: usize = 57;
+
+/// Poseidon sponge over the field of the proof
+///
+/// The permutation has width 3, 8 full and 57 partial rounds and the S-box x^alpha
+/// with the smallest alpha in 3, 5, 7, ... coprime to p - 1. The MDS matrix is the
+/// Cauchy matrix 1 / (i + 3 + j), and the round constants are SHA-256 of
+/// `poseidon_rc` and the constant's index, reduced modulo p.
+///
+/// The input is absorbed in big-endian chunks small enough to stay below p, followed
+/// by a single 1, and the challenge is the first element of the final state.
+///
+/// These parameters are custom to this crate, not a standardized Poseidon instance:
+/// the challenges won't match those of another Poseidon implementation, such as the
+/// one on the Merkle side, even over the same field.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoseidonHasher;
+
+impl PoseidonHasher {
+    /// Smallest odd alpha >= 3 for which x^alpha is a permutation of F_p
+    fn alpha(p: u64) -> u64 {
+        let gcd = |mut a: u64, mut b: u64| {
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            a
+        };
+        (3..).step_by(2).find(|&alpha| gcd(alpha, p - 1) == 1).unwrap()
+    }
+
+    /// Applies the Poseidon permutation to `state`
+    fn permute(state: &mut [u64; POSEIDON_WIDTH], p: u64) {
+        let alpha = Self::alpha(p);
+        let mds: Vec<Vec<u64>> = (0..POSEIDON_WIDTH)
+            .map(|i| {
+                (0..POSEIDON_WIDTH)
+                    .map(|j| fmath::inverse_mul((i + POSEIDON_WIDTH + j) as u64 % p, p))
+                    .collect()
+            })
+            .collect();
+
+        let half_full = POSEIDON_FULL_ROUNDS / 2;
+        let rounds = POSEIDON_FULL_ROUNDS + POSEIDON_PARTIAL_ROUNDS;
+        for round in 0..rounds {
+            for (i, value) in state.iter_mut().enumerate() {
+                let index = (round * POSEIDON_WIDTH + i) as u64;
+                let constant = sha2_hash_lower_32bit_parts(&[b"poseidon_rc", &index.to_be_bytes()]) % p;
+                *value = fmath::add(*value, constant, p);
+            }
+
+            let full = round < half_full || round >= rounds - half_full;
+            let sboxes = if full { POSEIDON_WIDTH } else { 1 };
+            for value in state.iter_mut().take(sboxes) {
+                *value = fmath::pow(*value, alpha, p);
+            }
+
+            let old = *state;
+            for (value, row) in state.iter_mut().zip(&mds) {
+                *value = row
+                    .iter()
+                    .zip(&old)
+                    .fold(0, |acc, (&m, &x)| fmath::add(acc, fmath::mul(m, x, p), p));
+            }
+        }
+    }
+}
+
+impl ChallengeHasher for PoseidonHasher {
+    fn name(&self) -> &'static str {
+        "poseidon"
+    }
+
+    fn hash_to_field(&self, input: &[u8], p: u64) -> u64 {
+        // Chunks of `chunk_len` bytes are below 2^(bits of p - 1) <= p
+        let chunk_len = ((63 - p.leading_zeros() as usize) / 8).max(1);
+        let mut elements: Vec<u64> = input
+            .chunks(chunk_len)
+            .map(|chunk| chunk.iter().fold(0, |acc, &byte| (acc << 8) | byte as u64) % p)
+            .collect();
+        elements.push(1);
+
+        // The capacity starts at the input length, so inputs differing only by
+        // trailing zero bytes don't collide
+        let mut state = [0, 0, input.len() as u64 % p];
+        for block in elements.chunks(POSEIDON_WIDTH - 1) {
+            for (value, &element) in state.iter_mut().zip(block) {
+                *value = fmath::add(*value, element, p);
+            }
+            Self::permute(&mut state, p);
+        }
+        state[0]
+    }
+}
+
+#[cfg(test)]
+mod test_challenge_hasher {
+    use super::*;
+    use crate::utils::sha2_hash_lower_32bit;
+
+    const P: u64 = 2060801;
+
+    #[test]
+    fn test_hashers() {
+        assert_eq!(Sha256Hasher.hash_to_field(b"alpha:42", P), sha2_hash_lower_32bit("alpha:42"));
+        assert_eq!(PoseidonHasher::alpha(P), 3);
+        assert_eq!(PoseidonHasher::alpha(181), 7);
+
+        let inputs: [&[u8]; 4] = [b"", b"alpha:", b"alpha:\0", b"beta_1:\0\0\0\0\0\0\0\x07"];
+        let hashes: Vec<u64> = inputs.iter().map(|input| PoseidonHasher.hash_to_field(input, P)).collect();
+        assert!(hashes.iter().all(|&h| h < P));
+        for (i, &h) in hashes.iter().enumerate() {
+            assert_eq!(h, PoseidonHasher.hash_to_field(inputs[i], P));
+            assert!(hashes[..i].iter().all(|&other| other != h), "{:?}", hashes);
+        }
+    }
+
+    #[test]
+    fn test_hasher_by_name() {
+        for name in HASHER_NAMES {
+            let hasher = hasher_by_name(name).unwrap();
+            assert_eq!(hasher.name(), name);
+        }
+        assert_eq!(
+            hasher_by_name("poseidon").unwrap().hash_to_field(b"z:", P),
+            PoseidonHasher.hash_to_field(b"z:", P)
+        );
+
+        let err = hasher_by_name("blake3").unwrap_err();
+        assert_eq!(err.to_string(), "unknown challenge hasher blake3, expected one of sha256, poseidon");
+    }
+}
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
@@ -19,13 +21,19 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
 
+use crate::field::fmath;
+use crate::json_file::read_term;
 use crate::json_file::write_term;
 use crate::json_file::ClassDataJson;
 use crate::json_file::DeviceConfigJson;
 use crate::json_file::DeviceInfo;
 use crate::math::*;
 use crate::matrices::Matrices;
+use crate::matrices::MatrixId;
 use crate::parser::Gate;
 use crate::parser::Instructions;
 use crate::parser::RiscvReg;
@@ -46,6 +54,35 @@ pub struct Commitment {
 
     /// val, row, col
     pub points_px: Vec<HashMap<u64, u64>>,
+
+    /// Collected only when enabled with `CommitmentBuilder::with_metrics`
+    pub metrics: Option<Metrics>,
+}
+
+/// Statistics of one matrix collected while building a commitment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatrixMetrics {
+    /// Number of nonzero entries of the matrix
+    pub nonzero: usize,
+    /// Time spent interpolating the row, col and val polynomials
+    pub interpolation: Duration,
+    /// Time spent committing the row, col and val polynomials
+    pub commitment: Duration,
+}
+
+/// Per-matrix statistics of a commitment, useful to right-size a class
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub a: MatrixMetrics,
+    pub b: MatrixMetrics,
+    pub c: MatrixMetrics,
+}
+
+impl Metrics {
+    /// Metrics of matrices A, B and C, in that order
+    fn matrices_mut(&mut self) -> [&mut MatrixMetrics; 3] {
+        [&mut self.a, &mut self.b, &mut self.c]
+    }
 }
 
 impl Commitment {
@@ -53,8 +90,8 @@ impl Commitment {
     pub fn new(class_data: ClassDataJson) -> CommitmentBuilder {
         let numebr_t_zero = class_data.get_matrix_t_zeros() as u64;
 
-        let set_h = generate_set(class_data.n, class_data, class_data.p);
-        let set_k = generate_set(class_data.m, class_data, class_data.p);
+        let set_h = generate_set(class_data.set_h_len(), class_data, class_data.p);
+        let set_k = generate_set(class_data.set_k_len(), class_data, class_data.p);
 
         println_dbg!("$p: {}", class_data.p);
         println_dbg!("$g: {}", class_data.g);
@@ -65,6 +102,10 @@ impl Commitment {
         let matrix_size = class_data.get_matrix_size();
         let matrices = Matrices::new(matrix_size.try_into().unwrap());
 
+        // Rows are [1, x, w]: the layout `gen_matrices` and the prover index into
+        debug_assert_eq!(numebr_t_zero, class_data.n_i + 1);
+        debug_assert_eq!(matrix_size as u64, class_data.n_g + class_data.n_i + 1);
+
         CommitmentBuilder {
             commitm: Commitment {
                 set_h,
@@ -73,19 +114,172 @@ impl Commitment {
                 matrices,
                 polys_px: vec![],
                 points_px: vec![],
+                metrics: None,
             },
         }
     }
 
+    /// Assembles a commitment from pieces that were already generated, e.g. restored
+    /// from `ProgramParamsJson`, without parsing the program again
+    ///
+    /// Fails unless there are nine polynomials and nine point maps (one of each per
+    /// matrix), the matrices are square of the size of `set_h`, `numebr_t_zero` lies
+    /// inside them and every row and column map covers `set_k`.
+    pub fn from_parts(
+        matrices: Matrices,
+        points_px: Vec<HashMap<u64, u64>>,
+        polys_px: Vec<FPoly>,
+        set_h: Vec<u64>,
+        set_k: Vec<u64>,
+        numebr_t_zero: usize,
+    ) -> Result<Commitment> {
+        if polys_px.len() != 9 || points_px.len() != 9 {
+            return Err(anyhow!(
+                "expected 9 polynomials and 9 point maps, got {} and {}",
+                polys_px.len(),
+                points_px.len()
+            ));
+        }
+
+        let size = matrices.size;
+        for (name, matrix) in [("A", &matrices.a), ("B", &matrices.b), ("C", &matrices.c)] {
+            if matrix.nrows() != size || matrix.ncols() != size {
+                return Err(anyhow!(
+                    "matrix {} is {}x{}, expected {}x{}",
+                    name,
+                    matrix.nrows(),
+                    matrix.ncols(),
+                    size,
+                    size
+                ));
+            }
+        }
+        if set_h.len() != size {
+            return Err(anyhow!("set_h has {} values, but the matrices have {} rows", set_h.len(), size));
+        }
+        if numebr_t_zero == 0 || numebr_t_zero > size {
+            return Err(anyhow!("numebr_t_zero = {} is outside 1..={}", numebr_t_zero, size));
+        }
+
+        // Maps are ordered val, row, col per matrix; row and col are padded to all of set_k
+        for (i, points) in points_px.iter().enumerate() {
+            let covers_set_k = points.len() == set_k.len() && set_k.iter().all(|k| points.contains_key(k));
+            if i % 3 != 0 && !covers_set_k {
+                return Err(anyhow!(
+                    "point map {} has {} points, expected one for each of the {} values of set_k",
+                    i,
+                    points.len(),
+                    set_k.len()
+                ));
+            }
+        }
+
+        Ok(Commitment {
+            set_h,
+            set_k,
+            numebr_t_zero,
+            matrices,
+            polys_px,
+            points_px,
+            metrics: None,
+        })
+    }
+
     /// Generates a commitment based on the AHP commitment generation process.
     /// For more details, see:
     /// [AHP Commitment Generation Documentation](https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/2-commitment-phase#id-2-3-ahp-commitment)
-    pub fn get_polynomials_commitment(&self, commitment_key: &Vec<u64>, p: u64) -> Vec<u64> {
-        let commitment = compute_all_commitment(&self.polys_px, commitment_key, p);
+    ///
+    /// When metrics are enabled, the time spent committing each matrix is recorded in them.
+    pub fn get_polynomials_commitment(&mut self, commitment_key: &[u64], p: u64) -> Vec<u64> {
+        let commitment = match self.metrics.as_mut() {
+            None => compute_all_commitment(&self.polys_px, commitment_key, p),
+            Some(metrics) => {
+                let mut commitment = vec![];
+                for (polys, matrix) in self.polys_px.chunks(3).zip(metrics.matrices_mut()) {
+                    let (res, elapsed) = timed(true, || compute_all_commitment(polys, commitment_key, p));
+                    matrix.commitment = elapsed;
+                    commitment.extend(res);
+                }
+                commitment
+            }
+        };
         println_dbg!("com_ahp: {:?}", commitment);
         commitment
     }
 
+    /// Writes matrices A, B and C to `a.mtx`, `b.mtx` and `c.mtx` in `dir`
+    ///
+    /// The files use the Matrix Market coordinate format with 1-based indices and the
+    /// field elements as integer values, so they can be loaded with e.g. `scipy.io.mmread`.
+    pub fn export_matrices_mm(&self, dir: &Path) -> Result<()> {
+        let Matrices { a, b, c, .. } = &self.matrices;
+        for (name, matrix) in [("a", a), ("b", b), ("c", c)] {
+            let entries = Matrices::to_sparse_coordinate_form(matrix);
+
+            let mut writer = BufWriter::new(File::create(dir.join(format!("{}.mtx", name)))?);
+            writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+            writeln!(writer, "{} {} {}", matrix.nrows(), matrix.ncols(), entries.len())?;
+            for (i, j, val) in entries {
+                writeln!(writer, "{} {} {}", i + 1, j + 1, val)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Fails if `gates` is empty, since a circuit without gates has no constraints
+    /// and no class to size its matrices and sets from
+    pub fn ensure_gates(gates: &[Gate]) -> Result<()> {
+        if gates.is_empty() {
+            return Err(anyhow!(
+                "no constraints to prove: the code block contains no supported arithmetic instructions"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Source line of the gate behind row `row` of the matrices
+    ///
+    /// Returns `None` for the rows of x, for padding rows, and for gates that weren't
+    /// parsed from assembly.
+    pub fn source_line_of_row(&self, gates: &[Gate], row: usize) -> Option<usize> {
+        row.checked_sub(self.numebr_t_zero)
+            .and_then(|i| gates.get(i))
+            .map(|gate| gate.source_line)
+            .filter(|&line| line != 0)
+    }
+
+    /// Source lines of the constraints at `points` of H, e.g. from `FPoly::nonvanishing_points`
+    ///
+    /// The k-th element of H carries row k of the matrices.
+    pub fn source_lines_at(&self, gates: &[Gate], points: &[u64]) -> Vec<usize> {
+        points
+            .iter()
+            .filter_map(|x| self.set_h.iter().position(|h| h == x))
+            .filter_map(|row| self.source_line_of_row(gates, row))
+            .collect()
+    }
+
+    /// Checks `z_vec` against the matrices built from `gates`
+    ///
+    /// Like `assert_r1cs`, but the error names the assembly line of the first violated constraint.
+    pub fn check_witness(&self, gates: &[Gate], z_vec: &Vec<u64>, p: u64) -> Result<()> {
+        let Matrices { a, b, c, .. } = &self.matrices;
+        let Some(&(row, ab, c)) = r1cs_violations(a, b, c, z_vec, p)?.first() else {
+            return Ok(());
+        };
+        match self.source_line_of_row(gates, row) {
+            Some(line) => Err(anyhow!(
+                "constraint from line {} failed: (A·z)[{}] * (B·z)[{}] = {}, but (C·z)[{}] = {}",
+                line, row, row, ab, row, c
+            )),
+            None => Err(anyhow!(
+                "constraint {} failed: (A·z)[{}] * (B·z)[{}] = {}, but (C·z)[{}] = {}",
+                row, row, row, ab, row, c
+            )),
+        }
+    }
+
     pub fn process_gates(gates: Vec<Gate>) -> Vec<Gate> {
         let mut gate_res = vec![];
         for gate in gates.clone() {
@@ -138,6 +332,67 @@ impl Commitment {
         gate_res
     }
 
+    /// Optional pass that shrinks `gates` while keeping every final register value
+    ///
+    /// Gates whose operands are both immediates are folded into a constant, which later
+    /// gates then read as an immediate; a register still holding such a constant at the
+    /// end is set by one gate `c + 0`. Gates whose result is overwritten before it is read
+    /// are dropped. The witness of the optimized circuit no longer has one entry per
+    /// instruction, so the device program must be generated from the same gates.
+    pub fn optimize_gates(gates: Vec<Gate>, p: u64) -> Vec<Gate> {
+        // Constant folding; each constant keeps the source line of the gate that produced it
+        let mut consts: HashMap<RiscvReg, (u64, usize)> = HashMap::new();
+        let mut folded = vec![];
+        for mut gate in gates {
+            if gate.val_left.is_none() {
+                gate.val_left = consts.get(&gate.reg_left).map(|&(val, _)| val);
+            }
+            if gate.val_right.is_none() {
+                gate.val_right = consts.get(&gate.reg_right).map(|&(val, _)| val);
+            }
+
+            match (gate.val_left, gate.val_right) {
+                (Some(left), Some(right)) => {
+                    let out = match gate.instr {
+                        Instructions::Add | Instructions::Addi => fmath::add(left, right, p),
+                        Instructions::Mul => fmath::mul(left, right, p),
+                    };
+                    consts.insert(gate.des_reg, (out, gate.source_line));
+                }
+                _ => {
+                    consts.remove(&gate.des_reg);
+                    folded.push(gate);
+                }
+            }
+        }
+
+        let mut consts: Vec<_> = consts.into_iter().collect();
+        consts.sort_by_key(|&(reg, _)| reg as usize);
+        folded.extend(consts.into_iter().map(|(reg, (val, line))| {
+            Gate::new(Some(val), Some(0), reg, RiscvReg::Zero, RiscvReg::Zero, Instructions::Add)
+                .at_line(line)
+        }));
+
+        // Dead-gate elimination, walking backwards: a register in `overwritten` is
+        // written again before anything reads it
+        let mut overwritten: HashSet<RiscvReg> = HashSet::new();
+        let mut live = vec![];
+        for gate in folded.into_iter().rev() {
+            if !overwritten.insert(gate.des_reg) {
+                continue;
+            }
+            if gate.val_left.is_none() {
+                overwritten.remove(&gate.reg_left);
+            }
+            if gate.val_right.is_none() {
+                overwritten.remove(&gate.reg_right);
+            }
+            live.push(gate);
+        }
+        live.reverse();
+        live
+    }
+
     /// Store in Json file
     pub fn store(
         &self,
@@ -161,7 +416,7 @@ impl Commitment {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 /// A struct representing a commitment in JSON format, containing points and polynomial data.
 pub struct CommitmentJson {
     #[serde(flatten)]
@@ -214,14 +469,7 @@ impl CommitmentJson {
         // Extract values for CommitmentJson from the Commitment struct
         let polys_px_t: Vec<Vec<u64>> = polys_px.iter().map(|p| write_term(p)).collect();
 
-        let concat_device_config_values = format!(
-            "{}{}{}{}",
-            device_confic.iot_developer_name,
-            device_confic.iot_device_name,
-            device_confic.device_hardware_version,
-            device_confic.firmware_version
-        );
-        let commitment_id = utils::sha2_hash(&concat_device_config_values);
+        let commitment_id = Self::compute_id(&device_confic);
 
         let info = DeviceInfo::new(
             // device_confic.class,  // FIXME: for now we are not using this, use class_number instead
@@ -253,25 +501,50 @@ impl CommitmentJson {
         }
     }
 
-    /// Converts a vector of u64 values into a polynomial.
-    fn convert_poly(v: &Vec<u64>) -> FPoly {
-        let mut poly = FPoly::new(v.iter().rev().map(|&x| x).collect());
-        poly.trim();
-        poly
+    /// Computes the commitment id of a device: the SHA-256 hash of its concatenated
+    /// developer name, device name, hardware version and firmware version.
+    pub fn compute_id(device_config: &DeviceConfigJson) -> String {
+        let concat_device_config_values = format!(
+            "{}{}{}{}",
+            device_config.iot_developer_name,
+            device_config.iot_device_name,
+            device_config.device_hardware_version,
+            device_config.firmware_version
+        );
+        utils::sha2_hash(&concat_device_config_values)
     }
 
+    /// Checks that this commitment was generated for `device_config`.
+    ///
+    /// Returns an error if the stored commitment id differs from the one recomputed
+    /// from the device configuration.
+    pub fn verify_id(&self, device_config: &DeviceConfigJson) -> Result<()> {
+        let expected = Self::compute_id(device_config);
+        if self.info.commitment_id != expected {
+            return Err(anyhow!(
+                "commitment id mismatch: the commitment has {} but device {} ({}) gives {}",
+                self.info.commitment_id,
+                device_config.iot_device_name,
+                device_config.iot_developer_name,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+
     /// Retrieves the polynomial data as a vector of `Poly` instances.
     pub fn get_polys_px(&self) -> Vec<FPoly> {
         vec![
-            Self::convert_poly(&self.row_a),
-            Self::convert_poly(&self.col_a),
-            Self::convert_poly(&self.val_a),
-            Self::convert_poly(&self.row_b),
-            Self::convert_poly(&self.col_b),
-            Self::convert_poly(&self.val_b),
-            Self::convert_poly(&self.row_c),
-            Self::convert_poly(&self.col_c),
-            Self::convert_poly(&self.val_c),
+            read_term(&self.row_a),
+            read_term(&self.col_a),
+            read_term(&self.val_a),
+            read_term(&self.row_b),
+            read_term(&self.col_b),
+            read_term(&self.val_b),
+            read_term(&self.row_c),
+            read_term(&self.col_c),
+            read_term(&self.val_c),
         ]
     }
 }
@@ -307,27 +580,27 @@ impl CommitmentBuilder {
     /// For further details, please refer to the documentation:
     /// [Documentation Link](https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/2-commitment-phase)
     pub fn gen_matrices(&mut self, gates: Vec<Gate>, ni: usize, p: u64) -> Self {
+        // Gate outputs start right after the rows of x
+        let t_zero = self.commitm.numebr_t_zero;
+        debug_assert_eq!(t_zero, ni + 1, "ni doesn't match the class the commitment was created with");
+
         // Create copies of matrices A, B, and C
         let a_mat = &mut self.commitm.matrices.a;
         let b_mat = &mut self.commitm.matrices.b;
         let c_mat = &mut self.commitm.matrices.c;
 
-        //  FIXME: Currently broken and not working
-        // Initialize HashMap to track last register indices
+        // Column of the latest value of each register. Every gate output gets its own
+        // witness column, so a register written again simply points to the new column.
         let mut regs_data: HashMap<RiscvReg, usize> = HashMap::new();
 
-        // Vector to store pairs of left and right register indices for each gate
-        // let reg_index_pairs = Self::generate_gate_index(&gates, ni);
-
         // Iterate over gates
         for (counter, gate) in gates.iter().enumerate() {
             println_dbg!("Gate Loop: {} ------------", counter);
 
             // Set index
-            let _inx = 1 + ni + counter;
+            let _inx = t_zero + counter;
 
             // Get index
-            // let (mut _li, mut _ri) = reg_index_pairs[counter];
             let (mut _li, mut _ri) = Self::get_register_index(&mut regs_data, gate, _inx);
 
             // Get left and right values (index is zero if value exists)
@@ -347,9 +620,21 @@ impl CommitmentBuilder {
                     println_dbg!("B[{}, {}] = {}", _inx, _li, left_val);
                     println_dbg!("B[{}, {}] = {}", _inx, _ri, right_val);
 
+                    // Both operands may read the same column (e.g. `add a0, a0, a0`)
                     a_mat[(_inx, 0)] = 1;
-                    b_mat[(_inx, _li)] = left_val % p;
-                    b_mat[(_inx, _ri)] = right_val % p;
+                    b_mat[(_inx, _li)] = fmath::add(b_mat[(_inx, _li)], left_val, p);
+                    b_mat[(_inx, _ri)] = fmath::add(b_mat[(_inx, _ri)], right_val, p);
+                }
+                Instructions::Mul if gate.is_square() => {
+                    println_dbg!("Gate: Mul (square)");
+                    println_dbg!("A[{}, {}] = B[{}, {}] = 1", _inx, _li, _inx, _li);
+
+                    // Both operands read the one column of the register, also when the
+                    // gate overwrites it (`mul a0, a0, a0`): its index was looked up
+                    // before the destination was recorded
+                    debug_assert_eq!(_li, _ri);
+                    a_mat[(_inx, _li)] = 1;
+                    b_mat[(_inx, _li)] = 1;
                 }
                 Instructions::Mul => {
                     println_dbg!("Gate: Mul");
@@ -377,82 +662,6 @@ impl CommitmentBuilder {
         self.clone()
     }
 
-    fn generate_gate_index(gates: &Vec<Gate>, ni: usize) -> Vec<(usize, usize)> {
-        fn get_index(
-            tmp_z: &Vec<(RiscvReg, usize)>,
-            reg: RiscvReg,
-            inx: usize,
-            counter: usize,
-            des_reg: RiscvReg,
-            reg_set: &HashSet<RiscvReg>,
-        ) -> usize {
-            match tmp_z[..=counter].iter().rposition(|&v| {
-                v.0 == reg && (v.1 != inx || v.0 != des_reg) && reg_set.contains(&v.0)
-            }) {
-                Some(p) => p + 33,
-                None => reg as usize + 1,
-            }
-        }
-
-        // Vector to store pairs of left and right register indices for each gate
-        let mut reg_index_pairs: Vec<(usize, usize)> = vec![];
-
-        let mut reg_pairs: Vec<(RiscvReg, RiscvReg, RiscvReg)> = vec![];
-
-        let mut w: Vec<(RiscvReg, usize)> = vec![];
-        let mut y: Vec<(RiscvReg, usize)> = vec![];
-
-        let mut tmp_z = vec![];
-
-        for (counter, gate) in gates.iter().enumerate() {
-            let _inx = 1 + ni + counter;
-            let des_reg = gate.des_reg;
-            match y.iter().position(|v| v.0 == des_reg) {
-                Some(pos) => {
-                    let tmp = y.remove(pos);
-                    w.push(tmp);
-                    y.push((des_reg, _inx));
-                }
-                None => {
-                    y.push((des_reg, _inx));
-                }
-            }
-            println_dbg!("i: {counter} -- {w:?} + {y:?}");
-
-            w.sort_by(|a, b| a.1.cmp(&b.1));
-            y.sort_by(|a, b| a.1.cmp(&b.1));
-            tmp_z = [w.clone(), y.clone()].concat();
-        }
-        println_dbg!("tmp_z: {tmp_z:?}");
-
-        let mut reg_set = HashSet::new();
-
-        for (counter, gate) in gates.iter().enumerate() {
-            let _inx = 1 + ni + counter;
-
-            reg_set.insert(gate.des_reg);
-
-            println_dbg!("inx: {_inx}");
-            println_dbg!("set: {:?}", reg_set);
-
-            let _li = get_index(&tmp_z, gate.reg_left, _inx, counter, gate.des_reg, &reg_set);
-            let _ri = get_index(
-                &tmp_z,
-                gate.reg_right,
-                _inx,
-                counter,
-                gate.des_reg,
-                &reg_set,
-            );
-            println_dbg!("li = {_li}, ri = {_ri}");
-            reg_index_pairs.push((_li, _ri));
-            reg_pairs.push((gate.des_reg, gate.reg_left, gate.reg_right));
-        }
-
-        println_dbg!("vec: {:?}", reg_index_pairs);
-        reg_index_pairs
-    }
-
     /// Retrieves register indices and updates the register data map
     fn get_register_index(
         regs_data: &mut HashMap<RiscvReg, usize>,
@@ -492,51 +701,100 @@ impl CommitmentBuilder {
         }
     }
 
+    /// Checks that each matrix has at most `set_k.len()` nonzero entries
+    ///
+    /// `set_k` is sized from the class's `m`, not from the circuit, and every matrix is
+    /// encoded over it on its own: `gen_polynomials` maps the nonzeros to the first
+    /// points of `set_k` and pads the rest deterministically, which only works when they
+    /// fit. The error names the first matrix that doesn't, see `ensure_fits_set_k`.
+    pub fn check_set_k_capacity(&self) -> Result<()> {
+        for which in [MatrixId::A, MatrixId::B, MatrixId::C] {
+            ensure_fits_set_k(self.commitm.matrices.get(which), &self.commitm.set_k)
+                .with_context(|| format!("matrix {:?} doesn't fit set_k", which))?;
+        }
+        Ok(())
+    }
+
+    /// Records the nonzero entries of each matrix in the metrics, when they are enabled
+    fn count_nonzero(&mut self) {
+        if let Some(metrics) = self.commitm.metrics.as_mut() {
+            let Matrices { a, b, c, .. } = &self.commitm.matrices;
+            for (matrix, mat) in metrics.matrices_mut().into_iter().zip([a, b, c]) {
+                matrix.nonzero = Matrices::to_sparse_coordinate_form(mat).len();
+            }
+        }
+    }
+
     /// Generates polynomials from matrix data and updates the commitment structure
-    pub fn gen_polynomials(&mut self, p: u64) -> Self {
+    ///
+    /// Fails if a matrix has more nonzero entries than `set_k` can hold, i.e. when the
+    /// class's `m` is too small for the circuit, see `check_set_k_capacity`.
+    pub fn gen_polynomials(&mut self, p: u64) -> Result<Self> {
+        self.check_set_k_capacity()?;
         let set_h = &self.commitm.set_h;
         let set_k = &self.commitm.set_k;
 
         // Collect row, column, and value points from matrix A
         let (points_row_p_a, points_col_p_a, points_val_p_a) =
-            get_matrix_points(&self.commitm.matrices.a, set_h, set_k, p);
+            get_matrix_points(&self.commitm.matrices.a, set_h, set_k, p)?;
         // Collect row, column, and value points from matrix B
         let (points_row_p_b, points_col_p_b, points_val_p_b) =
-            get_matrix_points(&self.commitm.matrices.b, set_h, set_k, p);
+            get_matrix_points(&self.commitm.matrices.b, set_h, set_k, p)?;
         // Collect row, column, and value points from matrix C.
         let (points_row_p_c, points_col_p_c, points_val_p_c) =
-            get_matrix_points(&self.commitm.matrices.c, set_h, set_k, p);
+            get_matrix_points(&self.commitm.matrices.c, set_h, set_k, p)?;
 
-        let a_row_px = sigma_yi_li(&points_row_p_a, &self.commitm.set_k, p);
+        let enabled = self.commitm.metrics.is_some();
+
+        let ((a_row_px, a_col_px, a_val_px), time_a) = timed(enabled, || {
+            (
+                sigma_yi_li(&points_row_p_a, set_k, p),
+                sigma_yi_li(&points_col_p_a, set_k, p),
+                sigma_yi_li(&points_val_p_a, set_k, p),
+            )
+        });
         println_dbg!("a_row_px: ");
         println_dbg!("{}", a_row_px);
-        let a_col_px = sigma_yi_li(&points_col_p_a, &self.commitm.set_k, p);
         println_dbg!("a_col_px: ");
         println_dbg!("{}", a_col_px);
-        let a_val_px = sigma_yi_li(&points_val_p_a, &self.commitm.set_k, p);
         println_dbg!("a_val_px: ");
         println_dbg!("{}", a_val_px);
 
-        let b_row_px = sigma_yi_li(&points_row_p_b, &self.commitm.set_k, p);
+        let ((b_row_px, b_col_px, b_val_px), time_b) = timed(enabled, || {
+            (
+                sigma_yi_li(&points_row_p_b, set_k, p),
+                sigma_yi_li(&points_col_p_b, set_k, p),
+                sigma_yi_li(&points_val_p_b, set_k, p),
+            )
+        });
         println_dbg!("b_row_px: ");
         println_dbg!("{}", b_row_px);
-        let b_col_px = sigma_yi_li(&points_col_p_b, &self.commitm.set_k, p);
         println_dbg!("b_col_px: ");
         println_dbg!("{}", b_col_px);
-        let b_val_px = sigma_yi_li(&points_val_p_b, &self.commitm.set_k, p);
         println_dbg!("b_val_px: ");
         println_dbg!("{}", b_val_px);
 
-        let c_row_px = sigma_yi_li(&points_row_p_c, &self.commitm.set_k, p);
+        let ((c_row_px, c_col_px, c_val_px), time_c) = timed(enabled, || {
+            (
+                sigma_yi_li(&points_row_p_c, set_k, p),
+                sigma_yi_li(&points_col_p_c, set_k, p),
+                sigma_yi_li(&points_val_p_c, set_k, p),
+            )
+        });
         println_dbg!("c_row_px: ");
         println_dbg!("{}", c_row_px);
-        let c_col_px = sigma_yi_li(&points_col_p_c, &self.commitm.set_k, p);
         println_dbg!("c_col_px: ");
         println_dbg!("{}", c_col_px);
-        let c_val_px = sigma_yi_li(&points_val_p_c, &self.commitm.set_k, p);
         println_dbg!("c_val_px: ");
         println_dbg!("{}", c_val_px);
 
+        self.count_nonzero();
+        if let Some(metrics) = self.commitm.metrics.as_mut() {
+            for (matrix, time) in metrics.matrices_mut().into_iter().zip([time_a, time_b, time_c]) {
+                matrix.interpolation = time;
+            }
+        }
+
         let polys_pxs = vec![
             a_row_px, a_col_px, a_val_px, b_row_px, b_col_px, b_val_px, c_row_px, c_col_px,
             c_val_px,
@@ -569,6 +827,49 @@ impl CommitmentBuilder {
         self.commitm.points_px = points_vector;
         self.commitm.polys_px = polys_pxs;
 
+        Ok(self.clone())
+    }
+
+    /// Hash identifying the circuit built from `gates` over the builder's sets
+    ///
+    /// Covers the gates as well as every parameter the matrices and polynomials
+    /// depend on, so a change of class or field also invalidates the cache.
+    pub fn gates_hash(&self, gates: &[Gate], ni: usize, p: u64) -> String {
+        let key = serde_json::to_string(&(gates, ni, p, &self.commitm.set_h, &self.commitm.set_k))
+            .expect("gates are always serializable");
+        sha2_hash(&key)
+    }
+
+    /// Runs `gen_matrices` and `gen_polynomials`, or loads their result from `cache_path`
+    /// when it was built from the same gates.
+    ///
+    /// On a miss (no cache file, or one built from other gates) the result is generated
+    /// and written to `cache_path`, replacing any stale entry. A hit is checked against
+    /// `set_k` as `gen_polynomials` would and fills the nonzero counts of the metrics; the
+    /// interpolation times stay zero, as nothing is interpolated.
+    pub fn gen_cached(&mut self, gates: Vec<Gate>, ni: usize, p: u64, cache_path: &str) -> Result<Self> {
+        Commitment::ensure_gates(&gates)?;
+        let gates_hash = self.gates_hash(&gates, ni, p);
+
+        if let Some(cache) = CommitmentCacheJson::load(cache_path, &gates_hash) {
+            println_dbg!("commitment cache hit: {}", cache_path);
+            self.commitm.matrices = cache.matrices;
+            self.commitm.points_px = cache.points_px;
+            self.commitm.polys_px = cache.polys_px.iter().map(|v| read_term(v)).collect();
+            self.check_set_k_capacity()?;
+            self.count_nonzero();
+            return Ok(self.clone());
+        }
+
+        self.gen_matrices(gates, ni, p);
+        self.gen_polynomials(p)?;
+        CommitmentCacheJson::new(gates_hash, &self.commitm).store(cache_path)?;
+        Ok(self.clone())
+    }
+
+    /// Enables the collection of per-matrix `Metrics`, returned in `Commitment::metrics`
+    pub fn with_metrics(&mut self) -> Self {
+        self.commitm.metrics = Some(Metrics::default());
         self.clone()
     }
 
@@ -580,11 +881,188 @@ impl CommitmentBuilder {
     }
 }
 
+/// Cached matrices and polynomials of one circuit, keyed by `CommitmentBuilder::gates_hash`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitmentCacheJson {
+    pub gates_hash: String,
+    matrices: Matrices,
+    points_px: Vec<HashMap<u64, u64>>,
+    polys_px: Vec<Vec<u64>>,
+}
+
+impl CommitmentCacheJson {
+    /// Creates a cache entry for `commitment`, built from the gates hashed into `gates_hash`
+    pub fn new(gates_hash: String, commitment: &Commitment) -> Self {
+        Self {
+            gates_hash,
+            matrices: commitment.matrices.clone(),
+            points_px: commitment.points_px.clone(),
+            polys_px: commitment.polys_px.iter().map(write_term).collect(),
+        }
+    }
+
+    /// Loads the entry at `path`, or `None` if it is missing, unreadable or built from other gates
+    pub fn load(path: &str, gates_hash: &str) -> Option<Self> {
+        read_json_file::<Self>(path)
+            .ok()
+            .filter(|cache| cache.gates_hash == gates_hash)
+    }
+
+    /// Store in Json file
+    pub fn store(&self, path: &str) -> Result<()> {
+        let file = File::create(path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test_matrices {
     use super::*;
+    use crate::matrices::FMatrix;
     use crate::parser::Instructions::*;
 
+    /// Runs `gates` on registers starting at 7 * (reg + 1) + 3 and returns the final values
+    fn final_registers(gates: &[Gate], p: u64) -> Vec<u64> {
+        let mut regs: Vec<u64> = (0..32).map(|reg| (7 * (reg + 1) + 3) % p).collect();
+        for gate in gates {
+            let left = gate.val_left.unwrap_or(regs[gate.reg_left as usize]);
+            let right = gate.val_right.unwrap_or(regs[gate.reg_right as usize]);
+            regs[gate.des_reg as usize] = match gate.instr {
+                Add | Addi => fmath::add(left, right, p),
+                Mul => fmath::mul(left, right, p),
+            };
+        }
+        regs
+    }
+
+    #[test]
+    fn optimize_gates_keeps_final_registers() {
+        use crate::ahp::test_utils::fixture_with;
+        use RiscvReg::*;
+
+        let p = 1678321;
+        let gates = vec![
+            Gate::new(Some(3), Some(4), T0, Zero, Zero, Add),  // t0 = 7, folded
+            Gate::new(None, Some(2), T0, T0, Zero, Mul),       // t0 = 14, folded
+            Gate::new(None, None, A0, A0, T0, Add),            // reads t0 as an immediate
+            Gate::new(None, Some(5), A1, A0, Zero, Mul),       // dead: a1 is overwritten
+            Gate::new(None, None, A1, A2, A2, Mul),
+            Gate::new(Some(1), Some(1), T0, Zero, Zero, Add),  // t0 = 2, set once at the end
+            Gate::new(None, Some(9), A2, A0, Zero, Addi),
+        ];
+
+        let optimized = Commitment::optimize_gates(gates.clone(), p);
+        assert_eq!(optimized.len(), 4);
+        assert_eq!(optimized[0].val_right, Some(14));
+        assert_eq!(final_registers(&optimized, p), final_registers(&gates, p));
+
+        // Four gates fit class 2, which the original seven don't
+        let fixture = fixture_with(optimized);
+        assert!(fixture.verify(&fixture.prove(2)));
+
+        // Nothing to fold or drop
+        let gates = crate::ahp::test_utils::sample_gates();
+        let optimized = Commitment::optimize_gates(gates.clone(), p);
+        assert_eq!(optimized.len(), gates.len());
+        assert_eq!(final_registers(&optimized, p), final_registers(&gates, p));
+    }
+
+    #[test]
+    fn padding_is_reproducible() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (ni, p) = (class_data.n_i as usize, class_data.p);
+        let generate = || {
+            Commitment::new(class_data)
+                .gen_matrices(crate::ahp::test_utils::sample_gates(), ni, p)
+                .gen_polynomials(p)
+                .unwrap()
+                .build()
+        };
+
+        let commitment = generate();
+        assert_eq!(commitment.points_px, generate().points_px);
+        assert_eq!(commitment.polys_px, generate().polys_px);
+
+        // Points past the nonzeros of A are padded with set_h[i % |H|], and val stays zero there
+        let (set_h, set_k) = (&commitment.set_h, &commitment.set_k);
+        let (val_a, row_a) = (&commitment.points_px[0], &commitment.points_px[1]);
+        let nonzero = Matrices::to_sparse_coordinate_form(&commitment.matrices.a).len();
+        assert!(nonzero < set_k.len());
+        for (i, k) in set_k.iter().enumerate().skip(nonzero) {
+            assert_eq!(row_a[k], set_h[i % set_h.len()]);
+            assert_eq!(val_a.get(k).copied().unwrap_or(0), 0);
+        }
+    }
+
+    #[test]
+    fn from_parts_matches_generated() {
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::test_utils::sample_gates;
+
+        let fixture = fixture();
+        let class_data = fixture.class_data;
+        let p = class_data.p;
+        let mut generated = Commitment::new(class_data)
+            .gen_matrices(sample_gates(), class_data.n_i as usize, p)
+            .gen_polynomials(p)
+            .unwrap()
+            .build();
+
+        // Matrices and points as the prover restores them from the program parameters
+        let (a, b, c) = fixture.program_params.get_matrices(&class_data, p).unwrap();
+        let matrices = Matrices { a, b, c, size: class_data.get_matrix_size() };
+        let (set_h, set_k) = (generated.set_h.clone(), generated.set_k.clone());
+        let points_px = fixture.program_params.get_points_px(&set_k, p);
+        let polys_px = generated.polys_px.clone();
+        let from_parts = |points_px: Vec<HashMap<u64, u64>>, polys_px: Vec<FPoly>| {
+            Commitment::from_parts(
+                matrices.clone(),
+                points_px,
+                polys_px,
+                set_h.clone(),
+                set_k.clone(),
+                class_data.get_matrix_t_zeros(),
+            )
+        };
+
+        let mut rebuilt = from_parts(points_px.clone(), fixture.commitment_json.get_polys_px()).unwrap();
+        assert_eq!(rebuilt.matrices, generated.matrices);
+        assert_eq!(
+            rebuilt.get_polynomials_commitment(&fixture.ck, p),
+            generated.get_polynomials_commitment(&fixture.ck, p)
+        );
+
+        let err = from_parts(points_px[..8].to_vec(), polys_px.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "expected 9 polynomials and 9 point maps, got 9 and 8");
+
+        let mut short = points_px.clone();
+        short[1].remove(&set_k[0]);
+        let err = from_parts(short, polys_px).unwrap_err();
+        assert!(err.to_string().starts_with("point map 1 has 7 points"), "{}", err);
+    }
+
+    #[test]
+    fn class_layout_matches_commitment() {
+
+        // Larger classes would allocate dense matrices of several GB
+        for class_number in 1..=6 {
+            let class_data = ClassDataJson::get_class_data("class.json", class_number).unwrap();
+            let (n_g, n_i) = (class_data.n_g as usize, class_data.n_i as usize);
+            assert_eq!(class_data.get_matrix_size(), n_g + n_i + 1);
+            assert_eq!(class_data.get_matrix_t_zeros(), n_i + 1);
+
+            let commitment = Commitment::new(class_data).build();
+            assert_eq!(commitment.numebr_t_zero, class_data.get_matrix_t_zeros());
+            assert_eq!(commitment.set_h.len() as u64, class_data.set_h_len());
+            assert_eq!(commitment.set_h.len(), class_data.get_matrix_size());
+            assert_eq!(commitment.set_k.len() as u64, class_data.set_k_len());
+            assert_eq!(commitment.matrices.size, class_data.get_matrix_size());
+            assert_eq!(commitment.matrices.a.nrows(), class_data.get_matrix_size());
+        }
+    }
+
     #[test]
     fn gen_matrices() {
         let class_data = ClassDataJson {
@@ -603,6 +1081,7 @@ mod test_matrices {
                 reg_left: 0.into(),
                 reg_right: 0.into(),
                 instr: Addi,
+                source_line: 0,
             },
             Gate {
                 val_left: None,
@@ -611,6 +1090,7 @@ mod test_matrices {
                 reg_left: 1.into(),
                 reg_right: 0.into(),
                 instr: Mul,
+                source_line: 0,
             },
             Gate {
                 val_left: None,
@@ -619,6 +1099,7 @@ mod test_matrices {
                 reg_left: 1.into(),
                 reg_right: 0.into(),
                 instr: Addi,
+                source_line: 0,
             },
             Gate {
                 val_left: None,
@@ -627,6 +1108,7 @@ mod test_matrices {
                 reg_left: 0.into(),
                 reg_right: 0.into(),
                 instr: Mul,
+                source_line: 0,
             },
         ];
         let commitment =
@@ -656,4 +1138,295 @@ mod test_matrices {
         assert_eq!(mat[(35, 35)], 1);
         assert_eq!(mat[(36, 36)], 1);
     }
+
+    #[test]
+    fn verify_commitment_id() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let device_config: DeviceConfigJson = read_json_file("data/device_config.json").unwrap();
+
+        let polys_px = vec![FPoly::one(); 9];
+        let commitment_json = CommitmentJson::new(&polys_px, 2, class_data, device_config.clone());
+        assert!(commitment_json.verify_id(&device_config).is_ok());
+
+        let mut other_device = device_config;
+        other_device.firmware_version = "2.0".to_string();
+        let err = commitment_json.verify_id(&other_device).unwrap_err();
+        assert!(err.to_string().contains("commitment id mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn gen_cached_reuses_matrices() {
+        use crate::ahp::test_utils::sample_gates;
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (ni, p) = (class_data.n_i as usize, class_data.p);
+        let path = std::env::temp_dir().join(format!("zkiot_commitment_cache_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let fresh = Commitment::new(class_data).gen_matrices(sample_gates(), ni, p).gen_polynomials(p).unwrap().build();
+
+        // The first build misses and fills the cache
+        let mut builder = Commitment::new(class_data);
+        let gates_hash = builder.gates_hash(&sample_gates(), ni, p);
+        assert!(CommitmentCacheJson::load(path, &gates_hash).is_none());
+        let first = builder.gen_cached(sample_gates(), ni, p, path).unwrap().build();
+        assert!(CommitmentCacheJson::load(path, &gates_hash).is_some());
+
+        // The second build loads the same gates back from the cache
+        let second = Commitment::new(class_data).gen_cached(sample_gates(), ni, p, path).unwrap().build();
+        for commitment in [&first, &second] {
+            assert_eq!(commitment.matrices, fresh.matrices);
+            assert_eq!(commitment.points_px, fresh.points_px);
+            assert_eq!(
+                commitment.polys_px.iter().map(write_term).collect::<Vec<_>>(),
+                fresh.polys_px.iter().map(write_term).collect::<Vec<_>>()
+            );
+        }
+
+        // A hit fills the metrics like a fresh build
+        let measured = Commitment::new(class_data).with_metrics().gen_cached(sample_gates(), ni, p, path).unwrap().build();
+        let fresh_metrics = Commitment::new(class_data)
+            .with_metrics()
+            .gen_matrices(sample_gates(), ni, p)
+            .gen_polynomials(p)
+            .unwrap()
+            .build()
+            .metrics
+            .unwrap();
+        let metrics = measured.metrics.unwrap();
+        assert_eq!(
+            [metrics.a.nonzero, metrics.b.nonzero, metrics.c.nonzero],
+            [fresh_metrics.a.nonzero, fresh_metrics.b.nonzero, fresh_metrics.c.nonzero]
+        );
+
+        // A hit whose matrices overflow set_k is rejected, as gen_polynomials rejects them
+        let mut cache = CommitmentCacheJson::load(path, &gates_hash).unwrap();
+        let size = cache.matrices.size;
+        cache.matrices.a = FMatrix::new(vec![vec![1; size]; size]);
+        cache.store(path).unwrap();
+        let err = Commitment::new(class_data).gen_cached(sample_gates(), ni, p, path).unwrap_err();
+        assert!(err.to_string().contains("matrix A doesn't fit set_k"), "{}", err);
+        let _ = std::fs::remove_file(path);
+
+        // Other gates miss the cache and overwrite it
+        let mut gates = sample_gates();
+        gates[0].val_right = Some(6);
+        let mut builder = Commitment::new(class_data);
+        let other_hash = builder.gates_hash(&gates, ni, p);
+        assert_ne!(other_hash, gates_hash);
+        let other = builder.gen_cached(gates, ni, p, path).unwrap().build();
+        assert_ne!(other.matrices, fresh.matrices);
+        assert!(CommitmentCacheJson::load(path, &gates_hash).is_none());
+        assert!(CommitmentCacheJson::load(path, &other_hash).is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_circuit_is_rejected() {
+        let err = Commitment::ensure_gates(&[]).unwrap_err();
+        assert!(err.to_string().starts_with("no constraints to prove"), "{}", err);
+        assert!(Commitment::ensure_gates(&crate::ahp::test_utils::sample_gates()).is_ok());
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let path = std::env::temp_dir().join(format!("zkiot_empty_circuit_{}.json", std::process::id()));
+        let err = Commitment::new(class_data)
+            .gen_cached(vec![], class_data.n_i as usize, class_data.p, path.to_str().unwrap())
+            .unwrap_err();
+        assert!(err.to_string().starts_with("no constraints to prove"), "{}", err);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn gen_matrices_register_reuse() {
+        use crate::ahp::test_utils::witness;
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (ni, p) = (class_data.n_i as usize, class_data.p);
+
+        // a0 is written by every gate and read twice by the last two
+        let gates = vec![
+            Gate::new(None, Some(5), RiscvReg::A0, RiscvReg::A0, RiscvReg::Zero, Addi),
+            Gate::new(None, None, RiscvReg::A0, RiscvReg::A0, RiscvReg::A0, Mul),
+            Gate::new(None, None, RiscvReg::A0, RiscvReg::A0, RiscvReg::A0, Add),
+            Gate::new(None, None, RiscvReg::A1, RiscvReg::A0, RiscvReg::A1, Add),
+        ];
+        let commitment = Commitment::new(class_data).gen_matrices(gates.clone(), ni, p).build();
+
+        // Registers start at z[reg + 1] = 7 * (reg + 1) + 3, so a0 = 80 and a1 = 87
+        let z_vec = witness(&gates, ni, p);
+        assert_eq!(z_vec[1 + ni..], [85, 85 * 85, 2 * 85 * 85, 2 * 85 * 85 + 87]);
+
+        // Each write gets its own column and `add a0, a0, a0` doubles the single operand
+        let w = 1 + ni;
+        assert_eq!(commitment.matrices.a[(w + 1, w)], 1);
+        assert_eq!(commitment.matrices.b[(w + 1, w)], 1);
+        assert_eq!(commitment.matrices.b[(w + 2, w + 1)], 2);
+        assert_eq!(commitment.matrices.b[(w + 3, w + 2)], 1);
+
+        let Matrices { a, b, c, .. } = &commitment.matrices;
+        assert_r1cs(a, b, c, &z_vec, p).unwrap();
+    }
+
+    #[test]
+    fn metrics_count_nonzero_entries() {
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::test_utils::sample_gates;
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (ni, p) = (class_data.n_i as usize, class_data.p);
+        let ck = fixture().ck;
+
+        let mut plain = Commitment::new(class_data).gen_matrices(sample_gates(), ni, p).gen_polynomials(p).unwrap().build();
+        let mut measured = Commitment::new(class_data)
+            .with_metrics()
+            .gen_matrices(sample_gates(), ni, p)
+            .gen_polynomials(p)
+            .unwrap()
+            .build();
+        assert!(plain.metrics.is_none());
+        assert_eq!(
+            plain.get_polynomials_commitment(&ck, p),
+            measured.get_polynomials_commitment(&ck, p)
+        );
+
+        // addi: A[w, 0], B[w, rs] and B[w, 0]; mul: A[w, rs1] and B[w, rs2]; add: A[w, 0], B[w, rs1] and B[w, rs2]
+        // where every gate also sets C[w, w]
+        let metrics = measured.metrics.unwrap();
+        assert_eq!(metrics.a.nonzero, 4);
+        assert_eq!(metrics.b.nonzero, 2 + 1 + 2 + 2);
+        assert_eq!(metrics.c.nonzero, 4);
+    }
+
+    #[test]
+    fn export_matrices_mm_round_trip() {
+        use crate::ahp::test_utils::sample_gates;
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let commitment = Commitment::new(class_data)
+            .gen_matrices(sample_gates(), class_data.n_i as usize, class_data.p)
+            .build();
+
+        let dir = std::env::temp_dir().join(format!("zkiot_mtx_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        commitment.export_matrices_mm(&dir).unwrap();
+
+        let Matrices { a, b, c, size } = &commitment.matrices;
+        for (name, matrix) in [("a", a), ("b", b), ("c", c)] {
+            let text = std::fs::read_to_string(dir.join(format!("{}.mtx", name))).unwrap();
+            let mut lines = text.lines();
+            assert_eq!(lines.next(), Some("%%MatrixMarket matrix coordinate integer general"));
+
+            let header: Vec<usize> = lines.next().unwrap().split(' ').map(|v| v.parse().unwrap()).collect();
+            let mut read_back = FMatrix::zeros(*size, *size);
+            for line in lines {
+                let entry: Vec<u64> = line.split(' ').map(|v| v.parse().unwrap()).collect();
+                read_back[(entry[0] as usize - 1, entry[1] as usize - 1)] = entry[2];
+            }
+
+            let entries = Matrices::to_sparse_coordinate_form(matrix);
+            assert_eq!(header, [*size, *size, entries.len()]);
+            assert!(!entries.is_empty());
+            assert_eq!(&read_back, matrix, "matrix {}", name);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failing_constraint_names_source_line() {
+        use crate::ahp::test_utils::{witness, CLASS_NUMBER};
+        use crate::parser::parse_from_lines;
+
+        let program = "\
+    addi    a0, a0, 5
+    nop
+    mul     a1, a0, a2
+    addi    a2, a1, 9
+    add     a0, a1, a2
+";
+        let path = std::env::temp_dir().join(format!("zkiot_source_line_{}.s", std::process::id()));
+        std::fs::write(&path, program).unwrap();
+        let gates = parse_from_lines((1..=5).collect(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = gates.iter().map(|g| g.source_line).collect();
+        assert_eq!(lines, [1, 3, 4, 5]);
+
+        let class_data = ClassDataJson::get_class_data("class.json", CLASS_NUMBER).unwrap();
+        let (n_i, p) = (class_data.n_i as usize, class_data.p);
+        let commitment = Commitment::new(class_data)
+            .gen_matrices(gates.clone(), n_i, p)
+            .build();
+
+        let mut z_vec = witness(&gates, n_i, p);
+        assert!(commitment.check_witness(&gates, &z_vec, p).is_ok());
+
+        // Break the output of `mul a1, a0, a2`
+        let row = commitment.numebr_t_zero + 1;
+        z_vec[row] = fmath::add(z_vec[row], 1, p);
+        let err = commitment.check_witness(&gates, &z_vec, p).unwrap_err();
+        assert!(err.to_string().starts_with("constraint from line 3 failed"), "{}", err);
+
+        assert_eq!(commitment.source_lines_at(&gates, &[commitment.set_h[row]]), [3]);
+        assert_eq!(commitment.source_line_of_row(&gates, 0), None);
+    }
+
+    #[test]
+    fn gen_matrices_square() {
+        use crate::ahp::test_utils::{fixture_with, witness};
+        use crate::math::assert_r1cs;
+        use RiscvReg::*;
+
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (ni, p) = (class_data.n_i as usize, class_data.p);
+        let gates = vec![
+            Gate::new(None, Some(5), A0, A0, Zero, Addi),
+            Gate::new(None, None, A1, A0, A0, Mul), // a1 = a0^2
+            Gate::new(None, None, A0, A0, A0, Mul), // a0 = a0^2, in place
+            Gate::new(None, None, A2, A0, A1, Add),
+        ];
+        assert!(gates[1].is_square() && gates[2].is_square() && !gates[3].is_square());
+
+        let commitment = Commitment::new(class_data).gen_matrices(gates.clone(), ni, p).build();
+        let (a, b, c) = (&commitment.matrices.a, &commitment.matrices.b, &commitment.matrices.c);
+        let a0 = ni + 1; // Column of the first gate's output
+        for row in [ni + 2, ni + 3] {
+            assert_eq!((a[(row, a0)], b[(row, a0)], c[(row, row)]), (1, 1, 1));
+            let in_row = |m: &FMatrix| m.iter_nonzero().filter(|&(r, _, _)| r == row).count();
+            assert_eq!((in_row(a), in_row(b)), (1, 1));
+        }
+
+        let z_vec = witness(&gates, ni, p);
+        assert_eq!(z_vec[ni + 2], fmath::mul(z_vec[a0], z_vec[a0], p));
+        assert!(assert_r1cs(a, b, c, &z_vec, p).is_ok());
+
+        let fixture = fixture_with(gates);
+        assert!(fixture.verify(&fixture.prove(2)));
+    }
+
+    #[test]
+    fn dense_circuit_exceeds_set_k_capacity() {
+        use crate::ahp::test_utils::{sample_gates, CLASS_NUMBER};
+
+        let class_data = ClassDataJson::get_class_data("class.json", CLASS_NUMBER).unwrap();
+        let (n_i, p) = (class_data.n_i as usize, class_data.p);
+        // Every gate reads two registers, so B has two nonzeros per row
+        let dense = vec![
+            Gate::new(None, None, RiscvReg::A0, RiscvReg::A1, RiscvReg::A2, Instructions::Add),
+            Gate::new(None, None, RiscvReg::A1, RiscvReg::A0, RiscvReg::A2, Instructions::Add),
+            Gate::new(None, None, RiscvReg::A2, RiscvReg::A0, RiscvReg::A1, Instructions::Add),
+        ];
+
+        let fits = Commitment::new(class_data).gen_matrices(sample_gates(), n_i, p);
+        assert!(fits.check_set_k_capacity().is_ok());
+
+        // Half of class 2's m = 8
+        let small = ClassDataJson { m: 4, ..class_data };
+        let mut builder = Commitment::new(small).gen_matrices(dense, n_i, p);
+        let err = format!("{:#}", builder.check_set_k_capacity().unwrap_err());
+        assert_eq!(err, "matrix B doesn't fit set_k: matrix has more nonzeros than set_k supports (need 6, have 4)");
+        assert!(builder.gen_polynomials(p).is_err());
+    }
 }
@@ -13,7 +13,12 @@
 // limitations under the License.
 
 
+pub mod challenge_hasher;
 pub mod commitment_generation;
 pub mod proof_generation;
 pub mod proof_verification;
 pub mod setup;
+pub mod transcript;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
@@ -16,17 +16,29 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufWriter;
 use std::iter::repeat_with;
-
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
+use rand::rngs::StdRng;
 use rand::thread_rng;
 use rand::Rng;
+use rand::SeedableRng;
 use serde::Deserialize;
 use serde::Serialize;
 
 use crate::field::fmath;
 use crate::field::fmath::inverse_mul;
+use crate::add_many;
 use crate::fpoly;
 use crate::json_file::write_set;
+use crate::json_file::read_term;
+use crate::json_file::store_in_json_file;
 use crate::json_file::write_term;
 use crate::json_file::ClassDataJson;
 use crate::json_file::DeviceInfo;
@@ -37,11 +49,17 @@ use crate::matrices::matrix_fmath;
 use crate::poly_add_many;
 use crate::poly_mul_many;
 use crate::polynomial::poly_fmath;
+use crate::polynomial::format_poly;
 use crate::polynomial::FPoly;
 use crate::println_dbg;
 use crate::utils::*;
 
+use super::challenge_hasher::hasher_by_name;
+use super::challenge_hasher::ChallengeHasher;
+use super::challenge_hasher::Sha256Hasher;
 use super::commitment_generation::CommitmentJson;
+use super::transcript::ChallengeEncoding;
+use super::transcript::Transcript;
 
 /// Enum representing different polynomial types used in the computation
 #[derive(Debug, Clone, Copy)]
@@ -60,6 +78,45 @@ pub enum Polys {
     H3x,
 }
 
+/// All proof polynomials, in the order they are stored in the proof
+const POLYS: [Polys; 12] = [
+    Polys::WHat,
+    Polys::ZHatA,
+    Polys::ZHatB,
+    Polys::ZHatC,
+    Polys::H0,
+    Polys::Sx,
+    Polys::G1x,
+    Polys::H1x,
+    Polys::G2x,
+    Polys::H2x,
+    Polys::G3x,
+    Polys::H3x,
+];
+
+/// File `ProofGeneration::with_intermediates_dir` writes the intermediate polynomials to
+pub const INTERMEDIATES_FILE: &str = "intermediates.json";
+
+impl Polys {
+    /// Name of the polynomial in the prover, used as its key in the intermediates dump
+    pub fn label(&self) -> &'static str {
+        match self {
+            Polys::WHat => "poly_w_hat",
+            Polys::ZHatA => "poly_z_hat_a",
+            Polys::ZHatB => "poly_z_hat_b",
+            Polys::ZHatC => "poly_z_hat_c",
+            Polys::H0 => "poly_h_0",
+            Polys::Sx => "poly_sx",
+            Polys::G1x => "g_1x",
+            Polys::H1x => "h_1x",
+            Polys::G2x => "g_2x",
+            Polys::H2x => "h_2x",
+            Polys::G3x => "g_3x",
+            Polys::H3x => "h_3x",
+        }
+    }
+}
+
 // Assuming AHPData is defined as follows
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AHPData {
@@ -69,10 +126,167 @@ pub enum AHPData {
     Polynomial(Vec<u64>),
     Array(Vec<u64>),
 }
-pub struct ProofGeneration;
+
+impl std::fmt::Display for AHPData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AHPData::Commit(v) => write!(f, "commit {}", v),
+            AHPData::Value(v) => write!(f, "value {}", v),
+            AHPData::Sigma(v) => write!(f, "sigma {}", v),
+            AHPData::Polynomial(v) => write!(f, "polynomial {}", format_poly(&read_term(v), false)),
+            AHPData::Array(v) => write!(f, "array {:?}", v),
+        }
+    }
+}
+
+/// Returns a readable listing of every value in `proof`, labelled with the
+/// `Com*_AHP_x` / `P*AHP` names of the proof file format
+pub fn dump(proof: &ProofGenerationJson) -> String {
+    let mut lines = vec![
+        format!("class: {}", proof.class),
+        format!("commitment_id: {}", proof.commitment_id),
+    ];
+    if let Some(p) = proof.p {
+        lines.push(format!("P: {}", p));
+    }
+
+    lines.push(format!("Com1_AHP_x (x): {:?}", proof.com1ahp));
+    for (i, poly) in POLYS.iter().enumerate() {
+        lines.push(format!("Com{}_AHP_x ({:?}): {}", i + 2, poly, proof.get_commits(i)));
+    }
+
+    // P1AHP..P17AHP interleave the sigmas between the polynomials
+    let mut polys = POLYS.iter().enumerate();
+    let mut poly_line = |label: usize| {
+        let (i, poly) = polys.next().unwrap();
+        format!("P{}AHP ({:?}): {}", label, poly, format_poly(&proof.get_poly(i), false))
+    };
+    lines.push(format!("P1AHP (sigma_1): {}", proof.get_sigma(1)));
+    lines.extend((2..=9).map(&mut poly_line));
+    lines.push(format!("P10AHP (sigma_2): {}", proof.get_sigma(2)));
+    lines.extend((11..=12).map(&mut poly_line));
+    lines.push(format!("P13AHP (sigma_3): {}", proof.get_sigma(3)));
+    lines.extend((14..=15).map(&mut poly_line));
+    lines.push(format!("P16AHP (y = p(z)): {}", proof.get_value(1)));
+    lines.push(format!("P17AHP (Com(q)): {}", proof.get_value(2)));
+
+    lines.join("\n")
+}
+
+/// Intermediate polynomials recorded for `ProofGeneration::with_intermediates_dir`, in
+/// the order they are computed; `None` records nothing
+struct Intermediates(Option<Vec<(&'static str, FPoly)>>);
+
+impl Intermediates {
+    fn record(&mut self, label: &'static str, poly: &FPoly) {
+        if let Some(polys) = self.0.as_mut() {
+            polys.push((label, poly.clone()));
+        }
+    }
+}
+
+pub struct ProofGeneration {
+    /// Seeds the random polynomial s(x) when set, making proofs reproducible
+    seed: Option<u64>,
+    /// Hash function the challenges are derived with
+    hasher: Arc<dyn ChallengeHasher>,
+    /// Directory to dump the intermediate polynomials to, if any
+    intermediates_dir: Option<PathBuf>,
+}
+
+/// Major stages of `generate_proof`, reported in this order to a progress callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofStage {
+    /// Interpolating z^A, z^B, z^C, x^ and w^ over H
+    Interpolation,
+    /// First sumcheck: s(x), h_1(x) and g_1(x)
+    SumcheckRound1,
+    /// Second sumcheck: sigma_2, h_2(x) and g_2(x)
+    SumcheckRound2,
+    /// Third sumcheck over K: sigma_3, h_3(x) and g_3(x)
+    SumcheckRound3,
+    /// Committing to the proof polynomials
+    Commitment,
+    /// Batched KZG opening at z
+    Opening,
+}
+
+/// Error returned when proof generation is cancelled through its flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofAborted;
+
+impl std::fmt::Display for ProofAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "proof generation was aborted")
+    }
+}
+
+impl std::error::Error for ProofAborted {}
+
+/// Fails with `ProofAborted` once `cancel` is set; a relaxed load, cheap enough for hot loops
+fn check_cancel(cancel: Option<&AtomicBool>) -> Result<()> {
+    match cancel {
+        Some(flag) if flag.load(Ordering::Relaxed) => Err(ProofAborted.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Upper bound for the number of random blinding points `random_b`
+pub const MAX_RANDOM_B: u64 = 10;
+
+/// Version of the proof scheme, recorded in proof files and checked by the verifier
+///
+/// Bumped whenever a change makes proofs of the previous version fail to verify.
+pub const SCHEME_VERSION: u32 = 1;
+
 impl ProofGeneration {
     pub fn new() -> Self {
-        Self
+        Self { seed: None, hasher: Arc::new(Sha256Hasher), intermediates_dir: None }
+    }
+
+    /// Same as `new`, drawing s(x) from a generator seeded with `seed`
+    ///
+    /// Given the same inputs, `random_b` and seed, the proof is the same on every run,
+    /// e.g. when re-running a witness saved with `WitnessJson`. A fixed seed makes s(x)
+    /// predictable, so this is for reproducing proofs, not for publishing them.
+    pub fn with_seed(seed: u64) -> Self {
+        Self { seed: Some(seed), ..Self::new() }
+    }
+
+    /// Derives the challenges with `hasher` instead of `Sha256Hasher`
+    ///
+    /// The proof then only verifies with a verifier using the same hasher; `store`
+    /// records its name in the proof file.
+    pub fn with_hasher(mut self, hasher: impl ChallengeHasher + 'static) -> Self {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    /// Writes every intermediate polynomial of the prover to `dir/INTERMEDIATES_FILE`
+    ///
+    /// The file maps each polynomial's name in the prover (`Polys::label` for the proof
+    /// polynomials; `poly_f_3x_shifted` is f_3(x) less sigma_3 / |K|) to its coefficients as
+    /// `write_term` lays them out, the index being the exponent as in the proof file, so a
+    /// run can be compared against reference values without reading the debug output. A
+    /// failed run still writes the polynomials computed before the error.
+    pub fn with_intermediates_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.intermediates_dir = Some(dir.into());
+        self
+    }
+
+    /// Largest blinding factor `random_b` allowed for the given class: `min(n_g, MAX_RANDOM_B)`
+    pub fn max_random_b(class_data: &ClassDataJson) -> u64 {
+        std::cmp::min(class_data.n_g, MAX_RANDOM_B)
+    }
+
+    /// Draws a random blinding factor `random_b` in `1..=max_random_b(class_data)`
+    pub fn gen_random_b(class_data: &ClassDataJson) -> u64 {
+        thread_rng().gen_range(1..=Self::max_random_b(class_data))
+    }
+
+    /// Draws a random seed for `with_seed`, to be recorded along with the proof's witness
+    pub fn gen_seed() -> u64 {
+        thread_rng().gen()
     }
 
     // /// Generates a vector Z
@@ -92,35 +306,34 @@ impl ProofGeneration {
     // }
 
     /// Generates interpolated polynomials from the given matrix and random values
+    ///
+    /// Each vector holds the values over `domain_h`, so the polynomials go through the
+    /// FFT of `EvalDomain::interpolate_blinded`, with the `random_b` points blinding them.
     fn generate_oz_interpolations(
         matrix_oz: [Vec<u64>; 3],
         random_b: u64,
-        set_h: &Vec<u64>,
+        domain_h: &EvalDomain,
         p: u64
     ) -> (FPoly, FPoly, FPoly) {
-        let mut points_za = get_points_set(&matrix_oz[0], &set_h);
-        let mut points_zb = get_points_set(&matrix_oz[1], &set_h);
-        let mut points_zc = get_points_set(&matrix_oz[2], &set_h);
-
-        // TODO: Random values were taken from WIKI. After the test is completed, these inserts should be deleted or commented out.
-        // Wiki link: [https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/3-proof-generation-phase#id-3-5-2-ahp-proof]
-        // Uncomment and adjust the line below to push random points
-        push_random_points(&mut points_za, random_b, &vec_to_set(set_h), p);
-        push_random_points(&mut points_zb, random_b, &vec_to_set(set_h), p);
-        push_random_points(&mut points_zc, random_b, &vec_to_set(set_h), p);
-
-        println_dbg!("points_za: {:?}", points_za);
-        println_dbg!("points_zb: {:?}", points_zb);
-        println_dbg!("points_zc: {:?}", points_zc);
+        let set_h = vec_to_set(&domain_h.elements);
+        let [poly_z_hat_a, poly_z_hat_b, poly_z_hat_c] = matrix_oz.map(|evals| {
+            // TODO: Random values were taken from WIKI. After the test is completed, these inserts should be deleted or commented out.
+            // Wiki link: [https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/3-proof-generation-phase#id-3-5-2-ahp-proof]
+            let mut blinding = vec![];
+            push_random_points(&mut blinding, random_b, &set_h, p);
+            println_dbg!("points_z: {:?}, blinding: {:?}", evals, blinding);
 
-        let poly_z_hat_a = interpolate(&points_za, p);
-        let poly_z_hat_b = interpolate(&points_zb, p);
-        let poly_z_hat_c = interpolate(&points_zc, p);
+            domain_h.interpolate_blinded(&evals, &blinding)
+        });
 
         (poly_z_hat_a, poly_z_hat_b, poly_z_hat_c)
     }
 
     /// Helper function to compute interpolations for w(h)
+    ///
+    /// Unlike z^A, z^B and z^C these stay on the Newton interpolation: x^ and w(h) are
+    /// interpolated over the first `numebr_t_zero` elements of H and the rest of it, and
+    /// neither part is a subgroup the FFT could run over.
     fn compute_x_w_vanishing_interpolation(
         random_b: u64,
         set_h: &Vec<u64>,
@@ -176,8 +389,10 @@ impl ProofGeneration {
         alpha: u64,
         set_h: &Vec<u64>,
         g: u64,
-        p: u64
-    ) -> (FPoly, FPoly, FPoly) {
+        p: u64,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(FPoly, FPoly, FPoly)> {
+        check_cancel(cancel)?;
         // ∑ r(alpha_2, k) * A^(k,x)
         let r_a_kx = sigma_rk_mk(
             set_h,
@@ -191,7 +406,8 @@ impl ProofGeneration {
         );
 
         println_dbg!("Poly ∑ r(alpha_2, k) * A^(k,x): ");
-        println_dbg!("{}", r_a_kx);
+        println_dbg!("{}", format_poly(&r_a_kx, true));
+        check_cancel(cancel)?;
 
         // ∑ r(alpha_2, k) * B^(k,x)
         let r_b_kx = sigma_rk_mk(
@@ -205,7 +421,8 @@ impl ProofGeneration {
             p
         );
         println_dbg!("Poly ∑ r(alpha_2, k) * B^(k,x): ");
-        println_dbg!("{}", r_b_kx);
+        println_dbg!("{}", format_poly(&r_b_kx, true));
+        check_cancel(cancel)?;
 
         // ∑ r(alpha_2, k) * C^(k,x)
         let r_c_kx = sigma_rk_mk(
@@ -219,9 +436,9 @@ impl ProofGeneration {
             p
         );
         println_dbg!("Poly ∑ r(alpha_2, k) * C^(k,x): ");
-        println_dbg!("{}", r_c_kx);
+        println_dbg!("{}", format_poly(&r_c_kx, true));
 
-        (r_a_kx, r_b_kx, r_c_kx)
+        Ok((r_a_kx, r_b_kx, r_c_kx))
     }
 
     /// Calculates r polynomials using beta for given points
@@ -242,7 +459,7 @@ impl ProofGeneration {
             p
         );
         println_dbg!("Poly ∑ r(alpha_2, k) * A^(x,k): ");
-        println_dbg!("{}", r_a_xk);
+        println_dbg!("{}", format_poly(&r_a_xk, true));
 
         // ∑ r(alpha_2, k) * B^(x,k)
         let r_b_xk = m_k(
@@ -255,7 +472,7 @@ impl ProofGeneration {
             p
         );
         println_dbg!("Poly ∑ r(alpha_2, k) * B^(x,k): ");
-        println_dbg!("{}", r_b_xk);
+        println_dbg!("{}", format_poly(&r_b_xk, true));
 
         // ∑ r(alpha_2, k) * C^(x,k)
         let r_c_xk = m_k(
@@ -268,27 +485,172 @@ impl ProofGeneration {
             p
         );
         println_dbg!("Poly ∑ r(alpha_2, k) * C^(x,k): ");
-        println_dbg!("{}", r_c_xk);
+        println_dbg!("{}", format_poly(&r_c_xk, true));
 
         (r_a_xk, r_b_xk, r_c_xk)
     }
 
     /// Generates proof values to be used for creating a JSON file later
+    ///
+    /// `random_b` is the number of random points used to blind the interpolated
+    /// polynomials; it must lie in `1..=max_random_b(&class_data)`.
+    #[allow(clippy::too_many_arguments)]
     pub fn generate_proof(
         &self,
-        commitment_key: &Vec<u64>,
+        commitment_key: &[u64],
         class_data: ClassDataJson,
         program_params: ProgramParamsJson,
         commitment_json: CommitmentJson,
         z_vec: Vec<u64>,
+        random_b: u64,
         p: u64
-    ) -> Box<[AHPData]> {
+    ) -> Result<Box<[AHPData]>> {
+        self.generate_proof_with_progress(
+            commitment_key,
+            class_data,
+            program_params,
+            commitment_json,
+            z_vec,
+            random_b,
+            p,
+            None,
+        )
+    }
+
+    /// Same as `generate_proof`, calling `progress` as each `ProofStage` starts
+    ///
+    /// The callback only observes the run; the proof is the same with or without it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_proof_with_progress(
+        &self,
+        commitment_key: &[u64],
+        class_data: ClassDataJson,
+        program_params: ProgramParamsJson,
+        commitment_json: CommitmentJson,
+        z_vec: Vec<u64>,
+        random_b: u64,
+        p: u64,
+        progress: Option<&mut dyn FnMut(ProofStage)>,
+    ) -> Result<Box<[AHPData]>> {
+        self.generate(
+            commitment_key,
+            class_data,
+            program_params,
+            commitment_json,
+            z_vec,
+            random_b,
+            p,
+            progress,
+            None,
+        )
+    }
+
+    /// Same as `generate_proof`, returning a `ProofAborted` error once `cancel` is set
+    ///
+    /// The flag is checked around each sum over H, on every element of K in the third
+    /// sumcheck and around the commitment, so the call returns shortly after it is set.
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_proof_cancellable(
+        &self,
+        commitment_key: &[u64],
+        class_data: ClassDataJson,
+        program_params: ProgramParamsJson,
+        commitment_json: CommitmentJson,
+        z_vec: Vec<u64>,
+        random_b: u64,
+        p: u64,
+        cancel: &AtomicBool,
+    ) -> Result<Box<[AHPData]>> {
+        self.generate(
+            commitment_key,
+            class_data,
+            program_params,
+            commitment_json,
+            z_vec,
+            random_b,
+            p,
+            None,
+            Some(cancel),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate(
+        &self,
+        commitment_key: &[u64],
+        class_data: ClassDataJson,
+        program_params: ProgramParamsJson,
+        commitment_json: CommitmentJson,
+        z_vec: Vec<u64>,
+        random_b: u64,
+        p: u64,
+        progress: Option<&mut dyn FnMut(ProofStage)>,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Box<[AHPData]>> {
+        let mut dump = Intermediates(self.intermediates_dir.as_ref().map(|_| Vec::new()));
+        let res = self.generate_recording(
+            commitment_key,
+            class_data,
+            program_params,
+            commitment_json,
+            z_vec,
+            random_b,
+            p,
+            progress,
+            cancel,
+            &mut dump,
+        );
+
+        // Dumped on failure too, holding what was computed up to the error
+        if let (Some(dir), Some(polys)) = (&self.intermediates_dir, &dump.0) {
+            let stored = Self::store_intermediates(dir, polys.iter().map(|(label, poly)| (*label, poly)))
+                .with_context(|| format!("Error dumping intermediates to {}", dir.display()));
+            if res.is_ok() {
+                stored?;
+            }
+        }
+        res
+    }
+
+    /// Body of `generate`, recording each labelled intermediate polynomial in `dump`
+    #[allow(clippy::too_many_arguments)]
+    fn generate_recording(
+        &self,
+        commitment_key: &[u64],
+        class_data: ClassDataJson,
+        program_params: ProgramParamsJson,
+        commitment_json: CommitmentJson,
+        z_vec: Vec<u64>,
+        random_b: u64,
+        p: u64,
+        mut progress: Option<&mut dyn FnMut(ProofStage)>,
+        cancel: Option<&AtomicBool>,
+        dump: &mut Intermediates,
+    ) -> Result<Box<[AHPData]>> {
+        // Every stage starts with a look at the flag
+        let mut report = |stage: ProofStage| {
+            check_cancel(cancel)?;
+            if let Some(callback) = progress.as_mut() {
+                callback(stage);
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+
+        if !(1..=Self::max_random_b(&class_data)).contains(&random_b) {
+            return Err(anyhow!(
+                "random_b must be in 1..={}, got {}",
+                Self::max_random_b(&class_data),
+                random_b
+            ));
+        }
+
         // Generate sets
-        let set_h = generate_set(class_data.n, class_data, p);
-        let set_k = generate_set(class_data.m, class_data, p);
+        let domain_h = EvalDomain::new(class_data.set_h_len(), class_data, p);
+        let domain_k = EvalDomain::new(class_data.set_k_len(), class_data, p);
+        let (set_h, set_k) = (&domain_h.elements, &domain_k.elements);
 
         let numebr_t_zero = class_data.get_matrix_t_zeros();
-        let matrices = program_params.get_matrices(&class_data, p);
+        let matrices = program_params.get_matrices(&class_data, p)?;
         let (mat_a, mat_b, mat_c) = matrices.clone();
 
         println_dbg!("P Mat A:");
@@ -300,13 +662,14 @@ impl ProofGeneration {
 
         println_dbg!("{:?}", z_vec);
 
-        let points_px = program_params.get_points_px(&set_k, p);
+        // Reject a bad witness before doing any of the expensive interpolation
+        assert_r1cs(&mat_a, &mat_b, &mat_c, &z_vec, p)?;
 
-        // TODO: Set 'random_b' to a random value
-        // let b_max_rand = std::cmp::min(10, class_data.n_g);
-        // let random_b = thread_rng().gen_range(1..b_max_rand);
-        // println_dbg!("b = {}", random_b);
-        let random_b = 2;
+        let points_px = program_params.get_points_px(set_k, p);
+
+        println_dbg!("b = {}", random_b);
+
+        report(ProofStage::Interpolation)?;
 
         // Generate and interpolate points for matrices az, bz, cz
         let (poly_z_hat_a, poly_z_hat_b, poly_z_hat_c) = Self::generate_oz_interpolations(
@@ -316,33 +679,49 @@ impl ProofGeneration {
                 matrix_fmath::vector_mul(&mat_c, &z_vec, p),
             ],
             random_b,
-            &set_h,
+            &domain_h,
             p
         );
 
         let (poly_x_hat, poly_w_hat, van_poly_vh1) = Self::compute_x_w_vanishing_interpolation(
             random_b,
-            &set_h,
+            set_h,
             &z_vec,
             numebr_t_zero,
             p
         );
+        dump.record("poly_z_hat_a", &poly_z_hat_a);
+        dump.record("poly_z_hat_b", &poly_z_hat_b);
+        dump.record("poly_z_hat_c", &poly_z_hat_c);
+        dump.record("poly_x_hat", &poly_x_hat);
+        dump.record("poly_w_hat", &poly_w_hat);
+        dump.record("van_poly_vh1", &van_poly_vh1);
         println_dbg!("w_hat:"); // Output the interpolated polynomial for wˉ(h)
         println_dbg!("{}", poly_w_hat);
 
         // h_zero
-        let van_poly_vhx = vanishing_poly(&set_h, p);
+        let van_poly_vhx = domain_h.vanishing_poly();
+        dump.record("van_poly_vhx", &van_poly_vhx);
 
         println_dbg!("van_poly_vhx: ");
         println_dbg!("{}", van_poly_vhx);
 
         let tmp1 = poly_fmath::mul(&poly_z_hat_a, &poly_z_hat_b, p);
         let poly_ab_c = poly_fmath::sub(&tmp1, &poly_z_hat_c, p);
+        dump.record("poly_ab_c", &poly_ab_c);
         
         println_dbg!("poly_ab_c");
         println_dbg!("{}", poly_ab_c);
         
-        let poly_h_0 = poly_fmath::div(&poly_ab_c, &van_poly_vhx, p);
+        // A cheap evaluation on H tells whether the division below leaves a remainder
+        if !divides_vanishing(&poly_ab_c, set_h, p) {
+            return Err(anyhow!(
+                "z_vec does not satisfy the constraints: z^A(x) * z^B(x) - z^C(x) does not vanish on H at {:?}",
+                poly_ab_c.nonvanishing_points(set_h, p)
+            ));
+        }
+
+        let poly_h_0 = poly_fmath::try_div(&poly_ab_c, &van_poly_vhx, p)?;
 
         println_dbg!("rem poly_h_0:");
         println_dbg!("{}", poly_h_0.1);
@@ -350,15 +729,24 @@ impl ProofGeneration {
         // Ensure this division has no remainders
         assert!(
             poly_h_0.1.is_zero(),
-            "Proof panic: The remainder of the division for poly_h_0 should be zero"
+            "Proof panic: The remainder of the division for poly_h_0 should be zero; z^A(x) * z^B(x) - z^C(x) is nonzero on H at {:?}",
+            poly_ab_c.nonvanishing_points(set_h, p)
         );
 
         let poly_h_0 = poly_h_0.0;
+        dump.record("poly_h_0", &poly_h_0);
         println_dbg!("poly_h_0");
         println_dbg!("{}", poly_h_0);
 
+        report(ProofStage::SumcheckRound1)?;
+
         // Generate a random polynomial
-        let poly_sx = Self::generate_random_polynomial(2 * set_h.len() + 2 - 1, (0, class_data.p - 1), p);
+        let sx_degree = 2 * set_h.len() + 2 - 1;
+        let poly_sx = match self.seed {
+            Some(seed) => Self::generate_random_polynomial_with(sx_degree, (0, class_data.p - 1), p, &mut StdRng::seed_from_u64(seed)),
+            None => Self::generate_random_polynomial(sx_degree, (0, class_data.p - 1), p),
+        };
+        dump.record("poly_sx", &poly_sx);
         println_dbg!("poly_sx");
         println_dbg!("{}", poly_sx);
 
@@ -368,13 +756,11 @@ impl ProofGeneration {
             .fold(0, |acc, &v| fmath::add(acc, poly_sx.evaluate(v, p), p));
         println_dbg!("sigma_1 :	{}", sigma_1);
 
-        // TODO:
-        let alpha = sha2_hash_lower_32bit(&(poly_sx.evaluate(0, p)).to_string());
-        let eta_a = sha2_hash_lower_32bit(&(poly_sx.evaluate(1, p)).to_string());
-        let eta_b = sha2_hash_lower_32bit(&(poly_sx.evaluate(2, p)).to_string());
-        let eta_c = sha2_hash_lower_32bit(&(poly_sx.evaluate(3, p)).to_string());
-
-        let etas = &[eta_a, eta_b, eta_c];
+        // All challenges are derived from s(x), in the same way as the verifier does
+        let transcript = Transcript::new(&poly_sx, p).with_hasher(self.hasher.clone());
+        let alpha = transcript.alpha();
+        let etas = &transcript.etas();
+        let [eta_a, eta_b, eta_c] = *etas;
 
         // From wiki: [https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/3-proof-generation-phase#id-3-5-2-ahp-proof]
         //             Step 6
@@ -393,12 +779,14 @@ impl ProofGeneration {
             poly_fmath::mul_by_number(&poly_z_hat_b, eta_b, p),
             poly_fmath::mul_by_number(&poly_z_hat_c, eta_c, p)
         );
+        dump.record("sigma_eta_z_x", &sigma_eta_z_x);
 
         println_dbg!("sigma_eta_z_x");
         println_dbg!("{}", sigma_eta_z_x);
 
         // Compute polynomial for r(α,x) ∑ ηM(z^M(x))
         let poly_r = poly_func_u(Some(alpha), None, set_h.len(), p);
+        dump.record("poly_r", &poly_r);
         println_dbg!("poly_r:");
         println_dbg!("{}", poly_r);
 
@@ -407,20 +795,25 @@ impl ProofGeneration {
 
         // r(α,x) * ∑_m [η_M ​z^M​(x)]
         let sum_1 = poly_fmath::mul(&poly_r, &sigma_eta_z_x, p);
+        dump.record("sum_1", &sum_1);
         // let sum_1 = poly_multiply(&poly_r, &sigma_eta_z_x, class_data.g);
         // assert_eq!(sum_12, sum_1, "g: {}", class_data.g);
         println_dbg!("sum_1: ");
-        println_dbg!("{}", sum_1);
+        println_dbg!("{}", format_poly(&sum_1, true));
 
         // Compute polynomial for Z^(x)
         let tmp = poly_fmath::mul(&poly_w_hat, &van_poly_vh1, p);
         let poly_z_hat_x = poly_fmath::add(&tmp, &poly_x_hat, p);
+        dump.record("poly_z_hat_x", &poly_z_hat_x);
 
         println_dbg!("z_hat: ");
         println_dbg!("{}", poly_z_hat_x);
 
         let (r_a_kx, r_b_kx, r_c_kx) =
-            Self::calculate_r_polynomials_with_alpha(&points_px, alpha, &set_h, class_data.g, p);
+            Self::calculate_r_polynomials_with_alpha(&points_px, alpha, set_h, class_data.g, p, cancel)?;
+        dump.record("r_a_kx", &r_a_kx);
+        dump.record("r_b_kx", &r_b_kx);
+        dump.record("r_c_kx", &r_c_kx);
 
         // ∑_m [η_M r_M(α,x)] * z^(x)
         // FIXME: Check here
@@ -431,32 +824,37 @@ impl ProofGeneration {
         }
 
         let sum_2 = poly_fmath::mul(&sum_2, &poly_z_hat_x, p);
+        dump.record("sum_2", &sum_2);
 
         // Sum Check Protocol Formula:
         // s(x) + r(α,x) * ∑_m [η_M ​z^M​(x)] - ∑_m [η_M r_M(α,x)] * z^(x)
         let tmp = poly_fmath::add(&poly_sx, &sum_1, p);
         let poly_scp = poly_fmath::sub(&tmp, &sum_2, p);
+        dump.record("poly_scp", &poly_scp);
 
         println_dbg!("scp: ");
-        println_dbg!("{}", poly_scp);
+        println_dbg!("{}", format_poly(&poly_scp, true));
 
-        let div_res = poly_fmath::div(&poly_scp, &van_poly_vhx, p);
+        let div_res = poly_fmath::try_div(&poly_scp, &van_poly_vhx, p)?;
         let h_1x = div_res.0;
         println_dbg!("Poly h_1x: ");
-        println_dbg!("{}", h_1x);
+        println_dbg!("{}", format_poly(&h_1x, true));
 
         let g_1x = poly_fmath::div(&div_res.1, &FPoly::one_x(), p).0;
+        dump.record("h_1x", &h_1x);
+        dump.record("g_1x", &g_1x);
         println_dbg!("Poly g_1x:");
-        println_dbg!("{}", g_1x);
+        println_dbg!("{}", format_poly(&g_1x, true));
 
-        // TODO: Random F - H
-        let beta_1 = generate_beta_random(8, &poly_sx, &set_h, p);
-        let beta_2 = generate_beta_random(9, &poly_sx, &set_h, p);
+        // Random F - H
+        let [beta_1, beta_2] = transcript.betas(set_h);
 
         // let beta_1 = 22);
         // let beta_2 = 80);
 
 
+        report(ProofStage::SumcheckRound2)?;
+
         // sigma_2
         let mut sigma_2 = 0;
         for (num, eta) in [r_a_kx.evaluate(beta_1, p), r_b_kx.evaluate(beta_1, p), r_c_kx.evaluate(beta_1, p)].iter().zip(etas.iter()) {
@@ -467,7 +865,7 @@ impl ProofGeneration {
 
 
         let (r_a_xk, r_b_xk, r_c_xk) =
-            Self::calculate_r_polynomials_with_beta(&points_px, beta_1, &set_h, p);
+            Self::calculate_r_polynomials_with_beta(&points_px, beta_1, set_h, p);
 
         // r(alpha_2, x) ∑_m [​η_M ​M^(x,β1​)]
         let mut poly_sigma_2 = FPoly::zero();
@@ -477,18 +875,23 @@ impl ProofGeneration {
         }
 
         let poly_sigma_2 = poly_fmath::mul(&poly_r, &poly_sigma_2, p);
+        dump.record("poly_sigma_2", &poly_sigma_2);
 
         println_dbg!("r(alpha_2, x) * ∑_m [η_M M^(x, β1)]: ");
-        println_dbg!("{}", poly_sigma_2);
+        println_dbg!("{}", format_poly(&poly_sigma_2, true));
 
-        let div_res = poly_fmath::div(&poly_sigma_2, &van_poly_vhx, p);
+        let div_res = poly_fmath::try_div(&poly_sigma_2, &van_poly_vhx, p)?;
         let h_2x = div_res.0;
         println_dbg!("Poly h_2x: ");
-        println_dbg!("{}", h_2x);
+        println_dbg!("{}", format_poly(&h_2x, true));
 
         let g_2x = poly_fmath::div(&div_res.1, &FPoly::one_x(), p).0;
+        dump.record("h_2x", &h_2x);
+        dump.record("g_2x", &g_2x);
         println_dbg!("Poly g_2x:");
-        println_dbg!("{}", g_2x);
+        println_dbg!("{}", format_poly(&g_2x, true));
+
+        report(ProofStage::SumcheckRound3)?;
 
         // sigma_3
         let mut sigma_3 = 0;
@@ -502,14 +905,19 @@ impl ProofGeneration {
             &van_poly_vhx,
             &vec![eta_a, eta_b, eta_c],
             &vec![beta_1, beta_2],
-            &set_k,
-            p
-        );
+            &domain_k,
+            p,
+            cancel,
+        )?;
+        dump.record("poly_f_3x", &poly_f_3x);
         println_dbg!("poly_f_3x");
-        println_dbg!("{}", poly_f_3x);
+        println_dbg!("{}", format_poly(&poly_f_3x, true));
         println_dbg!("sigma_3: {}", sigma_3);
 
         let (pi_a, pi_b, pi_c) = Self::compute_polys_pi(beta_1, beta_2, &polys_px, p);
+        dump.record("poly_pi_a", &pi_a);
+        dump.record("poly_pi_b", &pi_b);
+        dump.record("poly_pi_c", &pi_c);
         let polys_pi = vec![&pi_a, &pi_b, &pi_c];
 
         println_dbg!("poly_pi_a");
@@ -528,15 +936,18 @@ impl ProofGeneration {
             &polys_pi,
             p
         );
+        dump.record("poly_a_x", &poly_a_x);
         println_dbg!("poly_a_x");
         println_dbg!("{}", poly_a_x);
 
         // b(x)
         let poly_b_x = poly_fmath::mul(&poly_fmath::mul(polys_pi[0], polys_pi[1], p), &polys_pi[2], p);
+        dump.record("poly_b_x", &poly_b_x);
         println_dbg!("poly_b_x");
         println_dbg!("{}", poly_b_x);
 
-        let van_poly_vkx = vanishing_poly(&set_k, p);
+        let van_poly_vkx = domain_k.vanishing_poly();
+        dump.record("van_poly_vkx", &van_poly_vkx);
         println_dbg!("van_poly_vkx");
         println_dbg!("{}", van_poly_vkx);
 
@@ -546,19 +957,22 @@ impl ProofGeneration {
         let poly_f_3x = poly_fmath::sub(&poly_f_3x, &fpoly!(sigma_3_set_k), p);
 
         println_dbg!("poly_f_3x");
-        println_dbg!("{}", poly_f_3x);
+        println_dbg!("{}", format_poly(&poly_f_3x, true));
 
         let g_3x = poly_fmath::div(&poly_f_3x, &FPoly::one_x(), p).0;
+        dump.record("poly_f_3x_shifted", &poly_f_3x);
+        dump.record("g_3x", &g_3x);
         println_dbg!("g_3x");
-        println_dbg!("{}", g_3x);
+        println_dbg!("{}", format_poly(&g_3x, true));
 
         let tmp_add = poly_fmath::add(&poly_f_3x, &fpoly!(sigma_3_set_k), p);
         let tmp_mul = poly_fmath::mul(&poly_b_x, &tmp_add, p);
         let tmp_sub = poly_fmath::sub(&poly_a_x, &tmp_mul, p);
-        let h_3x = poly_fmath::div(&tmp_sub, &van_poly_vkx, p).0;
+        let h_3x = poly_fmath::try_div(&tmp_sub, &van_poly_vkx, p)?.0;
+        dump.record("h_3x", &h_3x);
 
         println_dbg!("h_3x");
-        println_dbg!("{}", h_3x);
+        println_dbg!("{}", format_poly(&h_3x, true));
 
         let polys_proof = [
             poly_w_hat,
@@ -628,31 +1042,34 @@ impl ProofGeneration {
         //     63), // eta_h3
         // ];
 
-        let mut eta_values = vec![];
-        for i in 10..=21 {
-            eta_values.push(sha2_hash_lower_32bit(&poly_sx.evaluate(i, p).to_string()))
-        }
+        report(ProofStage::Commitment)?;
+        let commit_x = compute_all_commitment(&polys_proof, commitment_key, p);
+        check_cancel(cancel)?;
+
+        report(ProofStage::Opening)?;
+        let eta_values = transcript.proof_etas();
 
         let poly_px = eta_values
             .iter()
             .enumerate()
             .map(|(i, &eta)| poly_fmath::mul_by_number(&polys_proof[i], eta, p))
             .fold(FPoly::zero(), |acc, poly| poly_fmath::add(&acc, &poly, p));
+        dump.record("poly_px", &poly_px);
 
         println_dbg!("poly_px:");
         println_dbg!("{}", poly_px);
 
-        // TODO:
-        let z = sha2_hash_lower_32bit(&(poly_sx.evaluate(22, p).to_string()));
+        let z = transcript.z();
         // let z = 2);
         let val_y_p = poly_px.evaluate(z, p);
         println_dbg!("val_y_p {}", val_y_p);
 
-        let mut poly_px_add = poly_px;
+        let mut poly_px_add = poly_px.clone();
         poly_px_add.add_term(fmath::inverse_add(val_y_p, p), 0);
         let poly_x_z = FPoly::new(vec![1, fmath::inverse_add(z, p)]);
 
         let poly_qx = poly_fmath::div(&poly_px_add, &poly_x_z, p).0;
+        dump.record("poly_qx", &poly_qx);
         println_dbg!("poly_qx");
         println_dbg!("{}", poly_qx);
 
@@ -660,19 +1077,27 @@ impl ProofGeneration {
         println_dbg!("val_commit_qx: {}", val_commit_poly_qx);
 
         let sigma = [sigma_1, sigma_2, sigma_3];
-
-        let commit_x = compute_all_commitment(&polys_proof, commitment_key, p);
         println_dbg!("commit_x: {:?}", commit_x);
 
         let x_vec = &z_vec[1..numebr_t_zero];
-        Self::create_proof(
+        Ok(Self::create_proof(
             &polys_proof,
             &sigma,
             &commit_x,
             val_y_p,
             val_commit_poly_qx,
             &x_vec.to_vec(),
-        )
+        ))
+    }
+
+    /// Writes `polys` to `dir/INTERMEDIATES_FILE` as a map from label to `write_term` coefficients
+    fn store_intermediates<'a>(dir: &Path, polys: impl Iterator<Item = (&'static str, &'a FPoly)>) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let map: serde_json::Map<String, serde_json::Value> = polys
+            .map(|(label, poly)| (label.to_string(), serde_json::json!(write_term(poly))))
+            .collect();
+        let path = dir.join(INTERMEDIATES_FILE);
+        store_in_json_file(serde_json::Value::Object(map), &path.to_string_lossy())
     }
 
     /// Computes three polynomials used for ax
@@ -689,18 +1114,23 @@ impl ProofGeneration {
 
     /// Generates a random polynomial with specified degree and coefficient range
     fn generate_random_polynomial(degree: usize, coefficient_range: (u64, u64), p: u64) -> FPoly {
+        Self::generate_random_polynomial_with(degree, coefficient_range, p, &mut thread_rng())
+    }
+
+    /// Same as `generate_random_polynomial`, drawing the coefficients from `rng`
+    ///
+    /// Coefficients are uniform in `coefficient_range` (inclusive), which must lie in `[0, p)`.
+    /// The result is trimmed, so its degree is below `degree` when the leading draw is zero.
+    fn generate_random_polynomial_with(
+        degree: usize,
+        coefficient_range: (u64, u64),
+        p: u64,
+        rng: &mut impl Rng,
+    ) -> FPoly {
         assert!(coefficient_range.1 < p);
-        let mut rng = rand::thread_rng();
-        let mut tmp = 0;
-        let coefficients: Vec<u64> = repeat_with(|| {
-            // TODO: use random terms
-            // let random_value = rng.gen_range(coefficient_range.0..=coefficient_range.1);
-            let random_value = tmp;
-            tmp = tmp + 1;
-            random_value
-        })
-        .take(degree + 1) // +1 because degree is the highest power
-        .collect();
+        let coefficients: Vec<u64> = repeat_with(|| rng.gen_range(coefficient_range.0..=coefficient_range.1))
+            .take(degree + 1) // +1 because degree is the highest power
+            .collect();
 
         let mut rand_poly = FPoly::new(coefficients);
         rand_poly.trim();
@@ -746,17 +1176,20 @@ impl ProofGeneration {
     }
 
     /// Computes polynomial Fx
+    #[allow(clippy::too_many_arguments)]
     fn generate_poly_fx(
         sigma_3: &mut u64,
         polys_px: &[FPoly],
         van_poly_vhx: &FPoly,
         eta: &Vec<u64>,
         beta: &Vec<u64>,
-        set_k: &Vec<u64>,
-        p: u64
-    ) -> FPoly {
-        let mut points_f_3: Vec<Point> = vec![];
-        for k in set_k.iter() {
+        domain_k: &EvalDomain,
+        p: u64,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<FPoly> {
+        let mut points_f_3: Vec<u64> = vec![];
+        for k in domain_k.elements.iter() {
+            check_cancel(cancel)?;
             let sig_a = sigma_m(
                 &van_poly_vhx,
                 &eta[0],
@@ -785,11 +1218,11 @@ impl ProofGeneration {
                 p
             );
 
-            let sum = sig_a + sig_b + sig_c;
-            *sigma_3 += sum;
-            points_f_3.push((*k, sum));
+            let sum = add_many!(p, sig_a, sig_b, sig_c);
+            *sigma_3 = fmath::add(*sigma_3, sum, p);
+            points_f_3.push(sum);
         }
-        interpolate(&points_f_3, p)
+        Ok(domain_k.interpolate(&points_f_3))
     }
 
     /// Generates polynomial based on input parameters
@@ -826,13 +1259,15 @@ impl ProofGeneration {
     }
 
     /// Store in Json file
-    pub fn store(&self, path: &str, proof_data: Box<[AHPData]>, class_number: u8, commitment_id: String) -> Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
+    pub fn store(&self, path: &str, proof_data: Box<[AHPData]>, class_number: u8, commitment_id: String, p: u64) -> Result<()> {
+        self.to_json(proof_data, class_number, commitment_id, p).store(path)
+    }
 
-        let proof_json = ProofGenerationJson::new(proof_data, class_number, commitment_id);
-        serde_json::to_writer(writer, &proof_json)?;
-        Ok(())
+    /// Builds the proof Json, recording the hasher the challenges were derived with
+    pub fn to_json(&self, proof_data: Box<[AHPData]>, class_number: u8, commitment_id: String, p: u64) -> ProofGenerationJson {
+        let mut proof_json = ProofGenerationJson::new(proof_data, class_number, commitment_id, p);
+        proof_json.hasher = Some(self.hasher.name().to_string());
+        proof_json
     }
 
     /// Restore Commitment from Json file
@@ -841,13 +1276,77 @@ impl ProofGeneration {
     }
 }
 
+/// Witness a proof was generated from, saved so the proof can be reproduced
+///
+/// Besides z it holds the prover's randomness: `random_b` and the seed s(x) was drawn
+/// with. Proving z again with `prover` and `random_b` gives the same proof.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WitnessJson {
+    pub class: u8,
+
+    /// Field modulus the witness was reduced under
+    #[serde(rename = "P")]
+    pub p: u64,
+
+    /// z = [1, x1..xn, w1..wm], in the order it was fed into the R1CS
+    pub z_vec: Vec<u64>,
+
+    /// Number of random blinding points the proof was generated with
+    pub random_b: u64,
+
+    /// Seed s(x) was drawn with, see `ProofGeneration::with_seed`
+    pub seed: u64,
+}
+
+impl WitnessJson {
+    pub fn new(class: u8, p: u64, z_vec: &[u64], random_b: u64, seed: u64) -> Self {
+        Self { class, p, z_vec: z_vec.to_vec(), random_b, seed }
+    }
+
+    /// Prover drawing s(x) from the recorded seed
+    pub fn prover(&self) -> ProofGeneration {
+        ProofGeneration::with_seed(self.seed)
+    }
+
+    /// Store in Json file
+    pub fn store(&self, path: &str) -> Result<()> {
+        let file = File::create(path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Restore the witness from a Json file
+    pub fn restore(path: &str) -> Result<Self> {
+        read_json_file(path)
+    }
+}
+
 /// JSON struct according to Witi (not complete)
 /// More Info: [wiki](https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/3-proof-generation-phase#id-3-4-proof-json-file-format)
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct ProofGenerationJson {
     pub class: u8,
     pub commitment_id: String,
 
+    /// Field modulus the proof was generated under (absent in older proof files)
+    #[serde(rename = "P", default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u64>,
+
+    /// Encoding the challenges were hashed with (absent in older proof files, which use
+    /// `ChallengeEncoding::Decimal`)
+    #[serde(rename = "Challenges", default, skip_serializing_if = "Option::is_none")]
+    pub challenges: Option<ChallengeEncoding>,
+
+    /// Name of the `ChallengeHasher` the challenges were derived with (absent when not
+    /// recorded, in which case the verifier's hasher is used unchecked)
+    #[serde(rename = "Hasher", default, skip_serializing_if = "Option::is_none")]
+    pub hasher: Option<String>,
+
+    /// `SCHEME_VERSION` of the prover (absent in older proof files)
+    #[serde(rename = "SchemeVersion", default, skip_serializing_if = "Option::is_none")]
+    pub scheme_version: Option<u32>,
+
     // #[serde(rename = "DeviceEncodedID")]
     // device_encoded_id: String,
     #[serde(rename = "Com1_AHP_x")]
@@ -942,7 +1441,7 @@ pub struct ProofGenerationJson {
 }
 
 impl ProofGenerationJson {
-    pub fn new(proof_data: Box<[AHPData]>, class_number: u8, commitment_id: String) -> Self {
+    pub fn new(proof_data: Box<[AHPData]>, class_number: u8, commitment_id: String, p: u64) -> Self {
         let mut commits = vec![];
         let mut polys = vec![];
         let mut sigma = vec![];
@@ -962,6 +1461,10 @@ impl ProofGenerationJson {
         Self {
             class: class_number,
             commitment_id,
+            p: Some(p),
+            challenges: Some(ChallengeEncoding::Bytes),
+            hasher: None,
+            scheme_version: Some(SCHEME_VERSION),
             com1ahp: x_vec,
             com2ahp: commits[0],
             com3ahp: commits[1],
@@ -995,6 +1498,76 @@ impl ProofGenerationJson {
         }
     }
 
+    /// Store in Json file
+    pub fn store(&self, path: &str) -> Result<()> {
+        let file = File::create(path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Checks that the proof is well formed for `class_data` before it is verified
+    ///
+    /// Every polynomial must be present and have its coefficients in `[0, p)`, the
+    /// commitments, sigmas and values must lie in `[0, p)`, and the vector X must hold
+    /// one entry per register (`n_i`) of the class. The original prover didn't reduce
+    /// sigma_3, so proof files without a `Challenges` field may hold any sigma_3.
+    pub fn validate(&self, class_data: &ClassDataJson) -> Result<()> {
+        let p = class_data.p;
+
+        if self.com1ahp.len() != class_data.n_i as usize {
+            return Err(anyhow!(
+                "Com1_AHP_x holds {} values, but class {} has n_i = {}",
+                self.com1ahp.len(),
+                self.class,
+                class_data.n_i
+            ));
+        }
+
+        let labels = ["P2", "P3", "P4", "P5", "P6", "P7", "P8", "P9", "P11", "P12", "P14", "P15"];
+        let polys = [
+            &self.p2ahp, &self.p3ahp, &self.p4ahp, &self.p5ahp, &self.p6ahp, &self.p7ahp,
+            &self.p8ahp, &self.p9ahp, &self.p11ahp, &self.p12ahp, &self.p14ahp, &self.p15ahp,
+        ];
+        for (label, poly) in labels.iter().zip(polys) {
+            if poly.is_empty() {
+                return Err(anyhow!("{}AHP is empty", label));
+            }
+            if let Some(coeff) = poly.iter().find(|&&c| c >= p) {
+                return Err(anyhow!("{}AHP has coefficient {} outside the field (p = {})", label, coeff, p));
+            }
+        }
+
+        let sigmas = if self.challenges.is_some() { 1..=3 } else { 1..=2 };
+        let scalars = (0..POLYS.len())
+            .map(|i| (format!("Com{}_AHP_x", i + 2), self.get_commits(i)))
+            .chain(sigmas.map(|i| (format!("sigma_{}", i), self.get_sigma(i))))
+            .chain([("P16AHP".to_string(), self.p16ahp), ("P17AHP".to_string(), self.p17ahp)]);
+        for (label, value) in scalars {
+            if value >= p {
+                return Err(anyhow!("{} = {} is outside the field (p = {})", label, value, p));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encoding the prover hashed the challenges with
+    pub fn challenge_encoding(&self) -> ChallengeEncoding {
+        self.challenges.unwrap_or(ChallengeEncoding::Decimal)
+    }
+
+    /// Hasher the prover derived the challenges with, `Sha256Hasher` when none is recorded
+    ///
+    /// # Errors
+    /// Fails if the recorded name isn't one `hasher_by_name` knows.
+    pub fn challenge_hasher(&self) -> Result<Arc<dyn ChallengeHasher>> {
+        match &self.hasher {
+            Some(name) => hasher_by_name(name),
+            None => Ok(Arc::new(Sha256Hasher)),
+        }
+    }
+
     /// Get vector X (Vector X is the first part of vector Z, where Z = [X, W, Y])
     pub fn get_x_vec(&self) -> Vec<u64> {
         let mut x: Vec<u64> = self.com1ahp.iter().map(|v| *v).collect();
@@ -1023,15 +1596,7 @@ impl ProofGenerationJson {
             ),
         };
 
-        let poly_vec = this_poly
-            .iter()
-            .rev()
-            .map(|&v| v)
-            .collect::<Vec<u64>>();
-
-        let mut poly = FPoly::new(poly_vec);
-        poly.trim();
-        poly
+        read_term(this_poly)
     }
 
     /// Get commits
@@ -1075,4 +1640,492 @@ impl ProofGenerationJson {
             _ => panic!("Invalid value number"),
         }
     }
+
+    /// Set polynomial `num`, using the same numbering as `get_poly`
+    pub fn set_poly(&mut self, num: usize, poly: &FPoly) {
+        let this_poly = match num {
+            0 => &mut self.p2ahp,
+            1 => &mut self.p3ahp,
+            2 => &mut self.p4ahp,
+            3 => &mut self.p5ahp,
+            4 => &mut self.p6ahp,
+            5 => &mut self.p7ahp,
+            6 => &mut self.p8ahp,
+            7 => &mut self.p9ahp,
+            8 => &mut self.p11ahp,
+            9 => &mut self.p12ahp,
+            10 => &mut self.p14ahp,
+            11 => &mut self.p15ahp,
+            _ => panic!(
+                "Error: Invalid index {}. Expected a value between 0 and 11.",
+                num
+            ),
+        };
+        *this_poly = write_term(poly);
+    }
+
+    /// Set commit `num`, using the same numbering as `get_commits`
+    pub fn set_commit(&mut self, num: usize, commit: u64) {
+        *match num {
+            0 => &mut self.com2ahp,
+            1 => &mut self.com3ahp,
+            2 => &mut self.com4ahp,
+            3 => &mut self.com5ahp,
+            4 => &mut self.com6ahp,
+            5 => &mut self.com7ahp,
+            6 => &mut self.com8ahp,
+            7 => &mut self.com9ahp,
+            8 => &mut self.com10ahp,
+            9 => &mut self.com11ahp,
+            10 => &mut self.com12ahp,
+            11 => &mut self.com13ahp,
+            _ => panic!(
+                "Error: Invalid index {}. Expected a value between 0 and 11.",
+                num
+            ),
+        } = commit;
+    }
+
+    /// Set sigma value `num`, using the same numbering as `get_sigma`
+    pub fn set_sigma(&mut self, num: usize, sigma: u64) {
+        match num {
+            1 => self.p1ahp = sigma,
+            2 => self.p10ahp = sigma,
+            3 => self.p13ahp = sigma,
+            _ => panic!("Invalid sigma number"),
+        }
+    }
+
+    /// Set value `num`, using the same numbering as `get_value`
+    pub fn set_value(&mut self, num: usize, value: u64) {
+        match num {
+            1 => self.p16ahp = value,
+            2 => self.p17ahp = value,
+            _ => panic!("Invalid value number"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod proof_test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ahp::test_utils::fixture;
+    use crate::ahp::test_utils::CLASS_NUMBER;
+
+    #[test]
+    fn proofs_verify_for_each_random_b() {
+        let fixture = fixture();
+        let max_b = ProofGeneration::max_random_b(&fixture.class_data);
+        assert_eq!(max_b, 4);
+
+        let mut z_hat_polys = vec![];
+        for random_b in 1..=max_b {
+            let proof = fixture.prove(random_b);
+            assert!(fixture.verify(&proof), "random_b = {}", random_b);
+            z_hat_polys.push(proof.get_poly(Polys::ZHatA as usize));
+        }
+
+        // Every blinding factor gives a different z^_A(x)
+        for i in 0..z_hat_polys.len() {
+            for j in i + 1..z_hat_polys.len() {
+                assert_ne!(z_hat_polys[i], z_hat_polys[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn unsatisfied_witness_is_rejected() {
+        let mut fixture = fixture();
+        assert!(fixture.verify(&fixture.prove(2)));
+
+        // Corrupt the output of the last gate
+        let last = fixture.z_vec.len() - 1;
+        fixture.z_vec[last] = fmath::add(fixture.z_vec[last], 1, fixture.class_data.p);
+
+        let err = ProofGeneration::new()
+            .generate_proof(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                fixture.class_data.p,
+            )
+            .unwrap_err();
+        let last_constraint = fixture.class_data.get_matrix_size() - 1;
+        assert!(
+            err.to_string().starts_with(&format!("constraint {} is not satisfied", last_constraint)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn random_b_out_of_range() {
+        let fixture = fixture();
+        let err = ProofGeneration::new()
+            .generate_proof(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                5,
+                fixture.class_data.p,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("random_b must be in 1..=4"), "{}", err);
+    }
+
+    #[test]
+    fn debug_messages_go_through_log() {
+        use std::cell::Cell;
+        use std::sync::Mutex;
+
+        thread_local! {
+            // Only this test's thread is captured, so the other tests don't pay for formatting
+            static CAPTURE: Cell<bool> = const { Cell::new(false) };
+        }
+
+        struct CapturingLogger(Mutex<Vec<(log::Level, String)>>);
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                CAPTURE.with(|capture| capture.get())
+            }
+
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(vec![]));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let fixture = fixture();
+        CAPTURE.with(|capture| capture.set(true));
+        let proof = fixture.prove(3);
+        CAPTURE.with(|capture| capture.set(false));
+        assert!(fixture.verify(&proof));
+
+        let records = LOGGER.0.lock().unwrap();
+        assert!(records.contains(&(log::Level::Debug, "b = 3".to_string())));
+        assert!(records.iter().all(|(level, _)| *level == log::Level::Debug));
+    }
+
+    #[test]
+    fn cancelled_proof_is_aborted() {
+        let fixture = fixture();
+        let cancel = AtomicBool::new(true);
+
+        let err = ProofGeneration::new()
+            .generate_proof_cancellable(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                fixture.class_data.p,
+                &cancel,
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<ProofAborted>(), Some(&ProofAborted));
+
+        // Cancelled while the first sumcheck runs: no later stage starts
+        let cancel = AtomicBool::new(false);
+        let mut stages = vec![];
+        let mut record = |stage: ProofStage| {
+            stages.push(stage);
+            if stage == ProofStage::SumcheckRound1 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        };
+        let err = ProofGeneration::new()
+            .generate(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                fixture.class_data.p,
+                Some(&mut record),
+                Some(&cancel),
+            )
+            .unwrap_err();
+        assert!(err.is::<ProofAborted>());
+        assert_eq!(stages, [ProofStage::Interpolation, ProofStage::SumcheckRound1]);
+
+        // A flag that stays clear doesn't change the proof
+        let cancel = AtomicBool::new(false);
+        let proof_data = ProofGeneration::new()
+            .generate_proof_cancellable(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                fixture.class_data.p,
+                &cancel,
+            )
+            .unwrap();
+        let proof = ProofGenerationJson::new(
+            proof_data,
+            CLASS_NUMBER,
+            fixture.commitment_json.info.commitment_id.clone(),
+            fixture.class_data.p,
+        );
+        assert!(fixture.verify(&proof));
+    }
+
+    #[test]
+    fn progress_reports_every_stage() {
+        let fixture = fixture();
+        let mut stages = vec![];
+        let mut record = |stage: ProofStage| stages.push(stage);
+
+        let proof_data = ProofGeneration::new()
+            .generate_proof_with_progress(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                fixture.class_data.p,
+                Some(&mut record),
+            )
+            .unwrap();
+
+        assert_eq!(
+            stages,
+            [
+                ProofStage::Interpolation,
+                ProofStage::SumcheckRound1,
+                ProofStage::SumcheckRound2,
+                ProofStage::SumcheckRound3,
+                ProofStage::Commitment,
+                ProofStage::Opening,
+            ]
+        );
+
+        // The callback doesn't change the proof
+        let with_progress = ProofGenerationJson::new(
+            proof_data,
+            CLASS_NUMBER,
+            fixture.commitment_json.info.commitment_id.clone(),
+            fixture.class_data.p,
+        );
+        assert!(fixture.verify(&with_progress));
+    }
+
+    #[test]
+    fn random_polynomial_depends_on_seed() {
+        const P: u64 = 181;
+        let degree = 9;
+        let gen = |seed| {
+            ProofGeneration::generate_random_polynomial_with(degree, (0, P - 1), P, &mut StdRng::seed_from_u64(seed))
+        };
+
+        let poly_1 = gen(1);
+        let poly_2 = gen(2);
+        assert_ne!(poly_1, poly_2);
+        assert_eq!(poly_1, gen(1));
+        for poly in [poly_1, poly_2] {
+            assert!(poly.degree() <= degree);
+            assert!(poly.terms.iter().all(|&c| c < P));
+        }
+
+        // With a single allowed value the degree is exact
+        let poly = ProofGeneration::generate_random_polynomial_with(degree, (5, 5), P, &mut StdRng::seed_from_u64(3));
+        assert_eq!(poly, FPoly::new(vec![5; degree + 1]));
+    }
+
+    #[test]
+    fn commitment_iter_matches_slice() {
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let polys_proof: Vec<FPoly> = (0..POLYS.len()).map(|i| proof.get_poly(i)).collect();
+        let p = fixture.class_data.p;
+
+        let from_slice = compute_all_commitment(&polys_proof, &fixture.ck, p);
+        let from_iter = compute_all_commitment_iter(polys_proof.iter(), &fixture.ck, p);
+
+        assert_eq!(from_slice, from_iter);
+        assert_eq!(from_slice.len(), POLYS.len());
+    }
+
+    #[test]
+    fn dump_lists_every_label() {
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let text = dump(&proof);
+
+        assert_eq!(text.lines().count(), 3 + 13 + 17);
+        for i in 1..=13 {
+            assert!(text.contains(&format!("Com{}_AHP_x", i)), "missing Com{}_AHP_x", i);
+        }
+        for i in 1..=17 {
+            assert!(text.contains(&format!("\nP{}AHP (", i)), "missing P{}AHP", i);
+        }
+        assert!(text.contains(&format!("P10AHP (sigma_2): {}\n", proof.get_sigma(2))));
+        assert!(text.contains("P7AHP (Sx): "));
+
+        let poly = FPoly::new(vec![3, 0, 0, 7, 1]);
+        assert_eq!(AHPData::Polynomial(write_term(&poly)).to_string(), "polynomial 3·x^4 + 7·x + 1");
+    }
+
+    #[test]
+    fn saved_witness_reproduces_the_proof() {
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let prove = |prover: ProofGeneration, z_vec: Vec<u64>, random_b: u64| {
+            let proof_data = prover
+                .generate_proof(
+                    &fixture.ck,
+                    fixture.class_data,
+                    fixture.program_params.clone(),
+                    fixture.commitment_json.clone(),
+                    z_vec,
+                    random_b,
+                    p,
+                )
+                .unwrap();
+            let proof = ProofGenerationJson::new(proof_data, 2, fixture.commitment_json.info.commitment_id.clone(), p);
+            serde_json::to_vec(&proof).unwrap()
+        };
+
+        let path = std::env::temp_dir().join(format!("zkiot_witness_used_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        WitnessJson::new(2, p, &fixture.z_vec, 3, 42).store(path).unwrap();
+        let witness = WitnessJson::restore(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((witness.class, witness.p, witness.random_b, witness.seed), (2, p, 3, 42));
+        assert_eq!(
+            prove(witness.prover(), witness.z_vec.clone(), witness.random_b),
+            prove(ProofGeneration::with_seed(42), fixture.z_vec.clone(), 3)
+        );
+    }
+
+    #[test]
+    fn proof_matches_golden_file() {
+        use crate::utils::to_canonical_json;
+
+        const GOLDEN_PATH: &str = "tests/fixtures/golden_proof.json";
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof_data = ProofGeneration::with_seed(42)
+            .generate_proof(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                p,
+            )
+            .unwrap();
+        let proof = ProofGenerationJson::new(proof_data, CLASS_NUMBER, fixture.commitment_json.info.commitment_id.clone(), p);
+        let canonical = to_canonical_json(&proof).unwrap();
+
+        let golden = std::fs::read_to_string(GOLDEN_PATH).unwrap();
+        assert_eq!(canonical, golden, "proof format drifted from {}", GOLDEN_PATH);
+        assert_eq!(serde_json::from_str::<ProofGenerationJson>(&golden).unwrap(), proof);
+        assert!(fixture.verify(&proof));
+    }
+
+    #[test]
+    fn intermediates_dump_holds_proof_polynomials() {
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let dir = std::env::temp_dir().join(format!("zkiot_intermediates_{}", std::process::id()));
+
+        let proof_data = ProofGeneration::with_seed(42)
+            .with_intermediates_dir(&dir)
+            .generate_proof(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                p,
+            )
+            .unwrap();
+        let proof = ProofGenerationJson::new(proof_data, CLASS_NUMBER, fixture.commitment_json.info.commitment_id.clone(), p);
+
+        let dump: HashMap<String, Vec<u64>> = read_json_file(dir.join(INTERMEDIATES_FILE).to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let labels: Vec<&str> = POLYS.iter().map(Polys::label).collect();
+        assert_eq!(
+            labels,
+            ["poly_w_hat", "poly_z_hat_a", "poly_z_hat_b", "poly_z_hat_c", "poly_h_0", "poly_sx",
+             "g_1x", "h_1x", "g_2x", "h_2x", "g_3x", "h_3x"]
+        );
+        for (i, label) in labels.iter().enumerate() {
+            assert_eq!(read_term(&dump[*label]), proof.get_poly(i), "{}", label);
+        }
+        for label in [
+            "poly_x_hat", "van_poly_vh1", "van_poly_vhx", "poly_ab_c", "sigma_eta_z_x", "poly_r", "sum_1",
+            "poly_z_hat_x", "r_a_kx", "r_b_kx", "r_c_kx", "sum_2", "poly_scp", "poly_sigma_2", "poly_f_3x",
+            "poly_pi_a", "poly_pi_b", "poly_pi_c", "poly_a_x", "poly_b_x", "van_poly_vkx", "poly_f_3x_shifted",
+            "poly_px", "poly_qx",
+        ] {
+            assert!(dump.contains_key(label), "{} missing", label);
+        }
+
+        // z^A(x) * z^B(x) - z^C(x) = h_0(x) * v_H(x)
+        let ab_c = poly_fmath::mul(&read_term(&dump["poly_h_0"]), &read_term(&dump["van_poly_vhx"]), p);
+        assert_eq!(read_term(&dump["poly_ab_c"]), ab_c);
+    }
+
+    #[test]
+    fn intermediates_dump_survives_a_failed_proof() {
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let dir = std::env::temp_dir().join(format!("zkiot_intermediates_failed_{}", std::process::id()));
+
+        // Abort once the second sumcheck starts; f_3(x) is checked for the flag
+        let cancel = AtomicBool::new(false);
+        let mut progress = |stage: ProofStage| {
+            if stage == ProofStage::SumcheckRound2 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        };
+        let err = ProofGeneration::with_seed(42)
+            .with_intermediates_dir(&dir)
+            .generate(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                p,
+                Some(&mut progress),
+                Some(&cancel),
+            )
+            .unwrap_err();
+        assert!(err.downcast_ref::<ProofAborted>().is_some());
+
+        let dump: HashMap<String, Vec<u64>> = read_json_file(dir.join(INTERMEDIATES_FILE).to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(dump.contains_key("poly_scp") && dump.contains_key("poly_sigma_2"));
+        assert!(!dump.contains_key("poly_f_3x") && !dump.contains_key("h_3x"));
+    }
 }
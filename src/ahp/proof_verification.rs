@@ -12,12 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rand::thread_rng;
-use rand::Rng;
+use anyhow::bail;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
 use crate::field::fmath;
-use crate::fpoly;
 use crate::json_file::ClassDataJson;
-use crate::kzg;
 use crate::math::e_func;
 use crate::math::poly_func_u;
 use crate::math::generate_set;
@@ -27,36 +27,184 @@ use crate::mul_many;
 use crate::polynomial::poly_fmath;
 use crate::polynomial::FPoly;
 use crate::println_dbg;
-use crate::utils::generate_beta_random;
 use crate::utils::get_points_set;
-use crate::utils::sha2_hash_lower_32bit;
+use crate::utils::timed;
 
+use super::challenge_hasher::ChallengeHasher;
+use super::challenge_hasher::Sha256Hasher;
+use super::commitment_generation::CommitmentJson;
 use super::proof_generation::Polys;
 use super::proof_generation::ProofGeneration;
 use super::proof_generation::ProofGenerationJson;
+use super::proof_generation::SCHEME_VERSION;
+use super::transcript::Transcript;
+
+/// Commitment data shared by every proof verified against the same commitment
+///
+/// Parses the commitment polynomials and builds the sets and vanishing polynomials
+/// once, so verifying many proofs doesn't redo that work per proof.
+#[derive(Debug, Clone)]
+pub struct PreparedCommitment {
+    pub class_data: ClassDataJson,
+    pub polys_px: Vec<FPoly>,
+    pub p: u64,
+    set_h: Vec<u64>,
+    set_k: Vec<u64>,
+    van_poly_vhx: FPoly,
+    van_poly_vkx: FPoly,
+}
+
+impl PreparedCommitment {
+    /// Prepares the polynomials of `commitment_json` for verification over the field of modulus `p`
+    pub fn new(commitment_json: &CommitmentJson, class_data: ClassDataJson, p: u64) -> Self {
+        Self::from_polys(commitment_json.get_polys_px(), class_data, p)
+    }
+
+    /// Prepares already parsed commitment polynomials
+    pub fn from_polys(polys_px: Vec<FPoly>, class_data: ClassDataJson, p: u64) -> Self {
+        let set_h = generate_set(class_data.set_h_len(), class_data, p);
+        let set_k = generate_set(class_data.set_k_len(), class_data, p);
+        let van_poly_vhx = Verification::vanishing_poly(class_data.set_h_len() as usize, p);
+        let van_poly_vkx = Verification::vanishing_poly(class_data.set_k_len() as usize, p);
+
+        Self { class_data, polys_px, p, set_h, set_k, van_poly_vhx, van_poly_vkx }
+    }
+}
+
+/// Verifies a proof held in memory, without touching the filesystem
+///
+/// `proof_json` and `commitment_json` are the contents of a proof file and of the program
+/// commitment file; `class_data` is the proof's class and `vk` the verifying key of the
+/// setup. This is the entry point for targets without a filesystem, such as
+/// `wasm32-unknown-unknown`. The challenges are derived with the hasher the proof
+/// records, see `ProofGenerationJson::challenge_hasher`.
+///
+/// # Errors
+/// Fails if either input isn't valid JSON of its kind, if the proof was generated for
+/// another commitment or class or with an unknown hasher, or for the reasons
+/// `Verification::verify` fails
+pub fn verify_from_bytes(
+    proof_json: &[u8],
+    commitment_json: &[u8],
+    class_data: ClassDataJson,
+    vk: u64,
+) -> Result<bool> {
+    let proof: ProofGenerationJson = serde_json::from_slice(proof_json)?;
+    let commitment: CommitmentJson = serde_json::from_slice(commitment_json)?;
+
+    if proof.commitment_id != commitment.info.commitment_id || proof.class != commitment.info.class {
+        bail!(
+            "proof was generated for commitment {} (class {}), not {} (class {})",
+            proof.commitment_id,
+            proof.class,
+            commitment.info.commitment_id,
+            commitment.info.class
+        );
+    }
+
+    LightVerifier::new(&proof).with_hasher(proof.challenge_hasher()?).verify(
+        vk,
+        class_data,
+        commitment.get_polys_px(),
+        proof.get_x_vec(),
+        class_data.g,
+        class_data.p,
+    )
+}
+
+/// Time spent in each verification equation, returned by `Verification::verify_timed`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CheckTimings {
+    pub check1: Duration,
+    pub check2: Duration,
+    pub check3: Duration,
+    pub check4: Duration,
+    pub check5: Duration,
+    /// The whole verification, including preparing the commitment and the challenges
+    pub total: Duration,
+}
+
+impl CheckTimings {
+    /// Sum of the five check timings
+    pub fn checks(&self) -> Duration {
+        self.check1 + self.check2 + self.check3 + self.check4 + self.check5
+    }
+}
+
+/// Both sides of a verification equation, evaluated at the challenges
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EquationSides {
+    pub lhs: u64,
+    pub rhs: u64,
+}
+
+impl EquationSides {
+    /// Whether the equation holds
+    pub fn holds(&self) -> bool {
+        self.lhs == self.rhs
+    }
+}
+
+/// Values recomputed by `Verification::verify_verbose`, for comparing against an
+/// independent computation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyTrace {
+    pub alpha: u64,
+    pub eta_a: u64,
+    pub eta_b: u64,
+    pub eta_c: u64,
+    pub beta_1: u64,
+    pub beta_2: u64,
+    pub beta_3: u64,
+    pub z: u64,
+    /// Sides of the five verification equations, in order
+    pub equations: [EquationSides; 5],
+}
 
 /// Struct for verification data
+#[derive(Debug, Clone)]
 pub struct Verification {
     pub data: ProofGenerationJson, // Proof generation data
+    hasher: Arc<dyn ChallengeHasher>, // Hash function the challenges are derived with
 }
 
 impl Verification {
     /// Creates a new `Verification` instance from proof generation data
     pub fn new(data: &ProofGenerationJson) -> Self {
-        Self { data: data.clone() }
+        Self { data: data.clone(), hasher: Arc::new(Sha256Hasher) }
+    }
+
+    /// Derives the challenges with `hasher` instead of `Sha256Hasher`
+    ///
+    /// It must be the hasher the proof was generated with; a proof recording another
+    /// hasher's name is rejected with an error.
+    pub fn with_hasher(mut self, hasher: impl ChallengeHasher + 'static) -> Self {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    /// Starts building a `Verification` from individual proof components
+    pub fn builder() -> VerificationBuilder {
+        VerificationBuilder::default()
     }
 
     /// Verifies the proof using commitment and verifying keys
     ///
     /// # Parameters
-    /// - `ck`: Commitment keys
+    /// - `ck`: Commitment keys, only checked to cover the class (the quotient commitment
+    ///   is read from the proof; see `LightVerifier`)
     /// - `vk`: Verifying key
     /// - `class_data`: Class data for verification
     /// - `polys_px`: Polynomials for verification
-    /// - `x_vec`: Vector of u64 values
+    /// - `x_vec`: Public input, the vector X starting with 1 (`n_i + 1` values)
     ///
     /// # Returns
     /// Returns true if verification is successful, false otherwise
+    ///
+    /// # Errors
+    /// Fails if the proof was generated under a field modulus other than `p`, if `ck`
+    /// holds fewer than the `D_AHP` keys of the class, or if `x_vec` doesn't hold
+    /// `n_i + 1` values
     pub fn verify(
         &self,
         (ck, vk): (&[u64], u64),
@@ -65,88 +213,282 @@ impl Verification {
         x_vec: Vec<u64>,
         g: u64,
         p: u64
-    ) -> bool {
+    ) -> Result<bool> {
+        Self::check_setup_size(ck, &class_data)?;
+        self.verify_with_vk(vk, class_data, polys_px, x_vec, g, p)
+    }
+
+    /// Fails if the setup's `ck` can't commit to the polynomials of `class_data`
+    fn check_setup_size(ck: &[u64], class_data: &ClassDataJson) -> Result<()> {
+        let needed = class_data.d_ahp();
+        if (ck.len() as u64) < needed {
+            bail!(
+                "setup too small for this proof's class: ck holds {} keys, but the class needs D_AHP = {}",
+                ck.len(),
+                needed
+            );
+        }
+        Ok(())
+    }
+
+    /// Same as `verify`, but also reports how long each check took
+    ///
+    /// Meant for profiling verification; the result is the one `verify` returns. Timing
+    /// relies on `std::time::Instant`, which isn't available on wasm32-unknown-unknown.
+    pub fn verify_timed(
+        &self,
+        (ck, vk): (&[u64], u64),
+        class_data: ClassDataJson,
+        polys_px: Vec<FPoly>,
+        x_vec: Vec<u64>,
+        g: u64,
+        p: u64
+    ) -> Result<(bool, CheckTimings)> {
+        Self::check_setup_size(ck, &class_data)?;
+        let mut timings = CheckTimings::default();
+        let (res, total) = timed(true, || {
+            let prepared = PreparedCommitment::from_polys(polys_px, class_data, p);
+            self.run_checks(vk, &prepared, x_vec, g, Some(&mut timings), None)
+        });
+        timings.total = total;
+        Ok((res?, timings))
+    }
+
+    /// Same as `verify`, but also returns the recomputed challenges and both sides of
+    /// every equation
+    ///
+    /// The result is the one `verify` returns; the trace holds what the `println_dbg!`
+    /// output shows in debug builds.
+    pub fn verify_verbose(
+        &self,
+        (ck, vk): (&[u64], u64),
+        class_data: ClassDataJson,
+        polys_px: Vec<FPoly>,
+        x_vec: Vec<u64>,
+        g: u64,
+        p: u64
+    ) -> Result<(bool, VerifyTrace)> {
+        Self::check_setup_size(ck, &class_data)?;
+        let mut trace = VerifyTrace::default();
+        let prepared = PreparedCommitment::from_polys(polys_px, class_data, p);
+        let res = self.run_checks(vk, &prepared, x_vec, g, None, Some(&mut trace))?;
+        Ok((res, trace))
+    }
+
+    /// Verifies the proof against public inputs the verifier already trusts
+    ///
+    /// `expected_x` is the vector X in the layout of `ProofGenerationJson::get_x_vec`,
+    /// i.e. starting with 1. It must equal the copy embedded in the proof; the checks
+    /// then run on `expected_x` rather than on the embedded copy.
+    ///
+    /// # Errors
+    /// Fails if the embedded vector X differs from `expected_x`, or for the reasons `verify` fails
+    pub fn verify_with_public_inputs(
+        &self,
+        keys: (&[u64], u64),
+        class_data: ClassDataJson,
+        polys_px: Vec<FPoly>,
+        g: u64,
+        p: u64,
+        expected_x: &[u64],
+    ) -> Result<bool> {
+        let embedded_x = self.data.get_x_vec();
+        if embedded_x != expected_x {
+            let index = embedded_x
+                .iter()
+                .zip(expected_x)
+                .position(|(lhs, rhs)| lhs != rhs)
+                .unwrap_or(embedded_x.len().min(expected_x.len()));
+            bail!(
+                "public inputs don't match the proof: they first differ at index {} (proof has {} values, expected {})",
+                index,
+                embedded_x.len(),
+                expected_x.len()
+            );
+        }
+        self.verify(keys, class_data, polys_px, expected_x.to_vec(), g, p)
+    }
+
+    /// Verifies the proof using the verifying key only
+    fn verify_with_vk(
+        &self,
+        vk: u64,
+        class_data: ClassDataJson,
+        polys_px: Vec<FPoly>,
+        x_vec: Vec<u64>,
+        g: u64,
+        p: u64
+    ) -> Result<bool> {
+        let prepared = PreparedCommitment::from_polys(polys_px, class_data, p);
+        self.verify_with_prepared(vk, &prepared, x_vec, g)
+    }
+
+    /// Verifies the proof against a commitment prepared once with `PreparedCommitment`
+    ///
+    /// Gives the same result as `verify` with the prepared class data, polynomials and
+    /// field modulus, and only borrows the commitment.
+    pub fn verify_with_prepared(
+        &self,
+        vk: u64,
+        prepared: &PreparedCommitment,
+        x_vec: Vec<u64>,
+        g: u64,
+    ) -> Result<bool> {
+        self.run_checks(vk, prepared, x_vec, g, None, None)
+    }
+
+    /// Runs the five checks, recording their durations in `timings` and the challenges
+    /// and equation sides in `trace` when given
+    fn run_checks(
+        &self,
+        vk: u64,
+        prepared: &PreparedCommitment,
+        x_vec: Vec<u64>,
+        g: u64,
+        timings: Option<&mut CheckTimings>,
+        trace: Option<&mut VerifyTrace>,
+    ) -> Result<bool> {
+        let (class_data, p) = (prepared.class_data, prepared.p);
+        let public_len = (class_data.n_i + 1) as usize;
+        Self::check_public_input_len(&x_vec, public_len)?;
+        if let Some(proof_p) = self.data.p {
+            if proof_p != p {
+                bail!(
+                    "field modulus mismatch: proof was generated with p = {}, verifier uses p = {}",
+                    proof_p,
+                    p
+                );
+            }
+        }
+        if let Some(name) = &self.data.hasher {
+            if name != self.hasher.name() {
+                bail!(
+                    "proof challenges were hashed with {}, but the verifier uses {}",
+                    name,
+                    self.hasher.name()
+                );
+            }
+        }
+        if let Some(version) = self.data.scheme_version {
+            if version != SCHEME_VERSION {
+                bail!(
+                    "scheme version mismatch: proof was generated with version {}, verifier uses version {}",
+                    version,
+                    SCHEME_VERSION
+                );
+            }
+        }
+        self.data.validate(&class_data)?;
+
         let poly_sx = &self.data.get_poly(Polys::Sx as usize);
-        let set_h_len = class_data.n as usize;
-        let set_h = generate_set(set_h_len as u64, class_data, p);
-        let set_k_len = class_data.m as usize;
+        let set_h = &prepared.set_h;
 
         
+        // Recompute the prover's challenges from s(x)
+        let transcript = Transcript::with_encoding(poly_sx, p, self.data.challenge_encoding())
+            .with_hasher(self.hasher.clone());
+
         // Generate a random number that is not present in the set h
-        let beta_1 = generate_beta_random(8, &poly_sx, &set_h, p);
-        let beta_2 = generate_beta_random(9, &poly_sx, &set_h, p);
-        // let beta_3 = 5;
-        let beta_3 = thread_rng().gen_range(1..1000);
+        let [beta_1, beta_2] = transcript.betas(set_h);
+        // Derived like the other challenges, so every run checks the same point
+        let beta_3 = transcript.beta_3(&prepared.set_k);
         
 
         // TODO:
         // From wiki: [https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/3-proof-generation-phase#id-3-5-2-ahp-proof]
         //             Step 6
-        let alpha = u64::from(sha2_hash_lower_32bit(&(poly_sx.evaluate(0, p)).to_string()));
-        let eta_a = u64::from(sha2_hash_lower_32bit(&(poly_sx.evaluate(1, p)).to_string()));
-        let eta_b = u64::from(sha2_hash_lower_32bit(&(poly_sx.evaluate(2, p)).to_string()));
-        let eta_c = u64::from(sha2_hash_lower_32bit(&(poly_sx.evaluate(3, p)).to_string()));
+        let alpha = transcript.alpha();
+        let [eta_a, eta_b, eta_c] = transcript.etas();
 
         // let alpha = u64::from(10);
         // let eta_a = u64::from(2);
         // let eta_b = u64::from(30);
         // let eta_c = u64::from(100);
 
-        let z = u64::from(sha2_hash_lower_32bit(&poly_sx.evaluate(22, p).to_string()));
+        let z = transcript.z();
         // let z = u64::from(2);
 
         let beta = vec![beta_1, beta_2, beta_3];
         // let beta = vec![u64::from(22), u64::from(80), u64::from(5)];
 
         let eta = vec![eta_a, eta_b, eta_c];
-        let t = (class_data.n_i + 1) as usize;
 
         // https://fidesinnova-1.gitbook.io/fidesinnova-docs/zero-knowledge-proof-zkp-scheme/4-proof-verification-phase#id-4-2-ahp-verify
         // All functions need to be executed for debugging purposes, hence they are written this way
-        let mut res = true;
-        res &= self.check_1(&polys_px, &beta, &eta, set_h_len, set_k_len, p);
-        res &= self.check_2(&beta, alpha, set_h_len, p);
-        res &= self.check_3(x_vec, alpha, &beta, &eta, &set_h, t, p);
-        res &= self.check_4(&beta, set_h_len, p);
-        res &= self.check_5((ck, vk), z, u64::from(g), &poly_sx, p);
-        res
+        let enabled = timings.is_some();
+        let (res_1, check1) = timed(enabled, || self.check_1(prepared, &beta, &eta, p));
+        let (res_2, check2) = timed(enabled, || self.check_2(&beta, alpha, &prepared.van_poly_vhx, set_h.len(), p));
+        let (res_3, check3) = timed(enabled, || self.check_3(x_vec, public_len, alpha, &beta, &eta, prepared, p));
+        let (res_4, check4) = timed(enabled, || self.check_4(&beta, &prepared.van_poly_vhx, set_h, p));
+        let (res_5, check5) = timed(enabled, || self.check_5(vk, z, u64::from(g), &transcript.proof_etas(), p));
+
+        let res_4 = res_4?;
+
+        if let Some(timings) = timings {
+            *timings = CheckTimings { check1, check2, check3, check4, check5, ..*timings };
+        }
+        if let Some(trace) = trace {
+            *trace = VerifyTrace {
+                alpha,
+                eta_a,
+                eta_b,
+                eta_c,
+                beta_1,
+                beta_2,
+                beta_3,
+                z,
+                equations: [res_1, res_2, res_3, res_4, res_5],
+            };
+        }
+        Ok(res_1.holds() & res_2.holds() & res_3.holds() & res_4.holds() & res_5.holds())
+    }
+
+    /// Fails if the public input `x_vec` doesn't hold the `public_len` values
+    /// (`n_i + 1`, the leading 1 included) check 3 interpolates over H
+    fn check_public_input_len(x_vec: &[u64], public_len: usize) -> Result<()> {
+        if x_vec.len() != public_len {
+            bail!(
+                "public input holds {} values, but the class needs n_i + 1 = {}",
+                x_vec.len(),
+                public_len
+            );
+        }
+        Ok(())
     }
 
     /// Checks the first verification equation
     ///
     /// # Parameters
-    /// - `polys_px`: Vector of polynomials
+    /// - `prepared`: Commitment polynomials and vanishing polynomials
     /// - `beta`: Array of u64 values
     /// - `eta`: Array of u64 values
-    /// - `set_h_len`: Length of the set for h
-    /// - `set_k_len`: Length of the set for k
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_1(
         &self,
-        polys_px: &Vec<FPoly>,
+        prepared: &PreparedCommitment,
         beta: &[u64],
         eta: &[u64],
-        set_h_len: usize,
-        set_k_len: usize,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Preparing equation values
-        let van_poly_vkx = Self::vanishing_poly(set_k_len, p);
-        let van_poly_vhx = Self::vanishing_poly(set_h_len, p);
+        let polys_px = &prepared.polys_px;
+        let van_poly_vkx = &prepared.van_poly_vkx;
+        let van_poly_vhx = &prepared.van_poly_vhx;
+        let set_k_len = prepared.class_data.set_k_len() as usize;
 
         let (pi_a, pi_b, pi_c) = ProofGeneration::compute_polys_pi(beta[0], beta[1], polys_px, p);
         let polys_pi = vec![&pi_a, &pi_b, &pi_c];
 
-        let poly_a_x = Self::generate_poly_ax(polys_px, beta, &van_poly_vhx, eta, &polys_pi, p);
+        let poly_a_x = Self::generate_poly_ax(polys_px, beta, van_poly_vhx, eta, &polys_pi, p);
         
         let poly_b_x = poly_fmath::mul(&poly_fmath::mul(&polys_pi[0], &polys_pi[1], p), &polys_pi[2], p);
 
         Self::check_equation_1(
             &self.data.get_poly(Polys::H3x as usize),
             &self.data.get_poly(Polys::G3x as usize),
-            &van_poly_vkx,
+            van_poly_vkx,
             &poly_a_x,
             &poly_b_x,
             &beta[2],
@@ -161,13 +503,13 @@ impl Verification {
     /// # Parameters
     /// - `beta`: Array of u64 values
     /// - `alpha`: u64 value
+    /// - `van_poly_vhx`: Vanishing polynomial for h
     /// - `set_h_len`: Length of the set for h
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
-    fn check_2(&self, beta: &[u64], alpha: u64, set_h_len: usize, p: u64) -> bool {
+    /// Returns both sides of the equation, which holds when they are equal
+    fn check_2(&self, beta: &[u64], alpha: u64, van_poly_vhx: &FPoly, set_h_len: usize, p: u64) -> EquationSides {
         // Preparing equation values
-        let van_poly_vhx = Self::vanishing_poly(set_h_len, p); // Vanishing polynomial for h
         let poly_r = poly_func_u(Some(alpha), None, set_h_len, p); // Compute polynomial r
 
         // Check the second verification equation
@@ -175,7 +517,7 @@ impl Verification {
             &poly_r,
             &self.data.get_poly(Polys::H2x as usize),
             &self.data.get_poly(Polys::G2x as usize),
-            &van_poly_vhx,
+            van_poly_vhx,
             &beta[1],
             &self.data.get_sigma(2),
             &self.data.get_sigma(3),
@@ -187,31 +529,31 @@ impl Verification {
     /// Checks the third verification equation
     ///
     /// # Parameters
-    /// - `x`: Vector of u64 values
+    /// - `x`: Public input, `public_len` values starting with 1
+    /// - `public_len`: Length of the public input, the size of the subset of H it is interpolated over
     /// - `alpha`: u64 value
     /// - `beta`: Array of u64 values
     /// - `eta`: Array of u64 values
-    /// - `set_h`: Vector of u64 values
-    /// - `t_zero`: Index for the subset of H
+    /// - `prepared`: Set H and its vanishing polynomial
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_3(
         &self,
         x: Vec<u64>,
+        public_len: usize,
         alpha: u64,
         beta: &[u64],
         eta: &[u64],
-        set_h: &Vec<u64>,
-        t_zero: usize,
+        prepared: &PreparedCommitment,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Preparing equation values
-
-        let van_poly_vhx = Self::vanishing_poly(set_h.len(), p); // Vanishing polynomial for h
+        let set_h = &prepared.set_h;
+        let van_poly_vhx = &prepared.van_poly_vhx; // Vanishing polynomial for h
         let poly_r = poly_func_u(Some(alpha), None, set_h.len(), p); // Compute polynomial r
         let sum_1 = self.gen_poly_sigma(&eta, &poly_r, p); // Generate sigma polynomial
-        let set_h_1 = &set_h[0..t_zero].to_vec(); // Subset of H
+        let set_h_1 = &set_h[0..public_len].to_vec(); // Subset of H
 
         let points = get_points_set(&x, set_h_1); // Get points for interpolation
         let poly_x_hat = interpolate(&points, p); // Interpolate polynomial
@@ -230,7 +572,7 @@ impl Verification {
             &poly_z_hat_x,
             &self.data.get_poly(Polys::H1x as usize),
             &self.data.get_poly(Polys::G1x as usize),
-            &van_poly_vhx,
+            van_poly_vhx,
             &beta[0],
             &self.data.get_sigma(1),
             &self.data.get_sigma(2),
@@ -243,14 +585,14 @@ impl Verification {
     ///
     /// # Parameters
     /// - `beta`: Array of u64 values
-    /// - `set_h_len`: Length of the set for h
+    /// - `van_poly_vhx`: Vanishing polynomial for h
+    /// - `set_h`: Set H, used to name the failing points if the division leaves a remainder
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
-    fn check_4(&self, beta: &[u64], set_h_len: usize, p: u64) -> bool {
+    /// Returns both sides of the equation, or an error naming the points of `set_h` at which
+    /// z^A(x) * z^B(x) - z^C(x) doesn't vanish (or if the vanishing polynomial of `set_h` is zero)
+    fn check_4(&self, beta: &[u64], van_poly_vhx: &FPoly, set_h: &[u64], p: u64) -> Result<EquationSides> {
         println_dbg!("equation 4 ======");
-        // Preparing equation values
-        let van_poly_vhx = Self::vanishing_poly(set_h_len, p); // Vanishing polynomial for h
         println_dbg!("van_poly_vhx: {}", van_poly_vhx);
 
         let tmp_mul = poly_fmath::mul(&self.data.get_poly(Polys::ZHatA as usize), &self.data.get_poly(Polys::ZHatB as usize), p);
@@ -258,79 +600,62 @@ impl Verification {
 
         println_dbg!("poly_ab_c: {}", poly_ab_c);
         
-        let poly_h_0 = poly_fmath::div(&poly_ab_c, &van_poly_vhx, p); // Divide and get the result
+        let poly_h_0 = poly_fmath::try_div(&poly_ab_c, van_poly_vhx, p)?; // Divide and get the result
         
         println_dbg!("poly_h_0: {}", poly_h_0.0);
 
-        // Ensure this division has no remainders
-        assert!(poly_h_0.1.is_zero(), "Verify panic: The remainder of the division for poly_h_0 should be zero");
+        // A remainder means the constraints are violated at the points of H it names
+        if !poly_h_0.1.is_zero() {
+            bail!(
+                "the remainder of the division for poly_h_0 should be zero; z^A(x) * z^B(x) - z^C(x) is nonzero on H at {:?}",
+                poly_ab_c.nonvanishing_points(set_h, p)
+            );
+        }
 
         // Check the fourth verification equation
-        Self::check_equation_4(&poly_ab_c, &poly_h_0.0, &van_poly_vhx, &beta[0], p)
+        Ok(Self::check_equation_4(&poly_ab_c, &poly_h_0.0, van_poly_vhx, &beta[0], p))
     }
 
     /// Checks the fifth verification equation
     ///
     /// # Parameters
-    /// - `ck`: Array of commitment keys
     /// - `vk`: Verifying key
     /// - `z`: u64 value
     /// - `g`: u64 value
+    /// - `eta_values`: Etas batching the proof polynomials
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
-    fn check_5(&self, (ck, vk): (&[u64], u64), z: u64, g: u64, poly_sx: &FPoly, p: u64) -> bool {
-        // Preparing equation values
-        // TODO: Replace with random values in the range (1..P)
-        // let eta_values = [
-        //     u64::from(1),  // eta_w
-        //     u64::from(4),  // eta_z_a
-        //     u64::from(10), // eta_z_b
-        //     u64::from(8),  // eta_z_c
-        //     u64::from(32), // eta_h0
-        //     u64::from(45), // eta_s
-        //     u64::from(92), // eta_g1
-        //     u64::from(11), // eta_h1
-        //     u64::from(1),  // eta_g2
-        //     u64::from(5),  // eta_h2
-        //     u64::from(25), // eta_g3
-        //     u64::from(63), // eta_h3
-        // ];
-
-        let mut eta_values = vec![];
-        for i in 10..=21 {
-            eta_values.push(sha2_hash_lower_32bit(&poly_sx.evaluate(i, p).to_string()))
-        }
-
-        // Compute polynomial px using eta values
-        let poly_px = eta_values
-            .iter()
-            .enumerate()
-            .map(|(i, &eta)| poly_fmath::mul(&fpoly!(eta), &self.data.get_poly(i).clone(), p))
-            .fold(FPoly::zero(), |acc, poly| poly_fmath::add(&acc, &poly, p));
-
-
-        // Compute polynomial px using eta values
+    /// Returns both sides of the equation, which holds when they are equal
+    fn check_5(&self, vk: u64, z: u64, g: u64, eta_values: &[u64], p: u64) -> EquationSides {
+        // Compute the commitment of px using eta values
         let val_commit_poly_px = eta_values
             .iter()
             .enumerate()
-            .map(|(i, &eta)| fmath::mul(eta, self.data.get_commits(i).clone(), p))
+            .map(|(i, &eta)| fmath::mul(eta, self.data.get_commits(i), p))
             .fold(0, |acc, com| fmath::add(acc, com, p));
 
+        // Evaluate px at z from the individual polynomials
+        let val_y_p = self.eval_poly_px(eta_values, z, p);
 
-
-        let val_y_p = poly_px.evaluate(z, p); // Evaluate polynomial at z
-
-        let mut poly_px_add = poly_px;
-        poly_px_add.add_term(fmath::inverse_add(val_y_p, p), 0); // Adjust polynomial by subtracting evaluated value
-        let poly_x_z = fpoly!(1, u64::from(fmath::inverse_add(z, p))); // Polynomial for division
-        let poly_qx = poly_fmath::div(&poly_px_add, &poly_x_z, p).0; // Divide and get the result
-        let val_commit_poly_qx = kzg::commit(&poly_qx, &ck, p); // Commit to polynomial qx
+        // The prover commits to qx(x) = (px(x) - px(z)) / (x - z); the pairing check below
+        // fails unless that commitment is consistent with px(z)
+        let val_commit_poly_qx = self.data.get_value(2);
 
         // Check the fifth verification equation
         Self::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z, p)
     }
 
+    /// Evaluates `px(z) = ∑ eta_i * p_i(z)` over the proof polynomials without building `px`
+    ///
+    /// Each polynomial goes through `FPoly::evaluate`, so no power of `z` is recomputed.
+    fn eval_poly_px(&self, eta_values: &[u64], z: u64, p: u64) -> u64 {
+        eta_values
+            .iter()
+            .enumerate()
+            .map(|(i, &eta)| fmath::mul(eta, self.data.get_poly(i).evaluate(z, p), p))
+            .fold(0, |acc, val| fmath::add(acc, val, p))
+    }
+
     #[inline]
     /// Generates the sigma polynomial using eta values and polynomial r
     ///
@@ -361,10 +686,7 @@ impl Verification {
     /// # Returns
     /// Returns the generated vanishing polynomial
     fn vanishing_poly(len: usize, p: u64) -> FPoly {
-        // FIXME: Use normal case
-        let mut van = fpoly!(p - 1); // Start with -1
-        van.add_term(1, len); // Add term for x^len
-        van // Return the vanishing polynomial
+        poly_fmath::sub(&FPoly::monomial(1, len), &FPoly::one(), p)
     }
 
     /// Checks the first verification equation
@@ -380,7 +702,7 @@ impl Verification {
     /// - `set_k_len`: Length of the set for k
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_equation_1(
         h_3x: &FPoly,
         g_3x: &FPoly,
@@ -391,7 +713,7 @@ impl Verification {
         sigma_3: &u64,
         set_k_len: usize,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         println_dbg!("h_3x: ");
         println_dbg!("g_3x: ");
         println_dbg!("van_poly_vkx: {}", van_poly_vkx);
@@ -420,7 +742,7 @@ impl Verification {
         println_dbg!("------------------------------------");
 
         // Check if both sides of the equation are equal
-        eq11 == eq12
+        EquationSides { lhs: eq11, rhs: eq12 }
     }
 
     /// Checks the second verification equation
@@ -435,7 +757,7 @@ impl Verification {
     /// - `set_h_len`: Length of the set for h
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_equation_2(
         poly_r: &FPoly,
         h_2x: &FPoly,
@@ -446,7 +768,7 @@ impl Verification {
         sigma_3: &u64,
         set_h_len: usize,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Print names of the arguments
         println_dbg!("poly_r: {}", poly_r);
         println_dbg!("h_2x: {}", h_2x);
@@ -482,7 +804,7 @@ impl Verification {
         println_dbg!("------------------------------------");
 
         // Check if both sides of the equation are equal
-        eq21 == eq22
+        EquationSides { lhs: eq21, rhs: eq22 }
     }
 
     /// Checks the third verification equation
@@ -500,7 +822,7 @@ impl Verification {
     /// - `set_h_len`: Length of the set for h
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_equation_3(
         poly_sx: &FPoly,
         sum_1: &FPoly,
@@ -513,7 +835,7 @@ impl Verification {
         sigma_2: &u64,
         set_h_len: usize,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Evaluate the left-hand side of the equation
         // [ sx(beta_1) + sum_1(beta_1) ] mod p
         let tmp_x = fmath::add(poly_sx.evaluate(*beta_1, p), sum_1.evaluate(*beta_1, p), p);
@@ -537,7 +859,7 @@ impl Verification {
         println_dbg!("------------------------------------");
 
         // Check if both sides of the equation are equal
-        eq31 == eq32
+        EquationSides { lhs: eq31, rhs: eq32 }
     }
 
     /// Checks the fourth verification equation
@@ -549,14 +871,14 @@ impl Verification {
     /// - `beta_1`: u64 value for beta1
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     fn check_equation_4(
         poly_ab_c: &FPoly,
         poly_h_0: &FPoly,
         van_poly_vhx: &FPoly,
         beta_1: &u64,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Evaluate the left-hand side of the equation
         let eq41 = poly_ab_c.evaluate(*beta_1, p);
 
@@ -571,7 +893,7 @@ impl Verification {
         println_dbg!("------------------------------------");
 
         // Check if both sides of the equation are equal
-        eq41 == eq42
+        EquationSides { lhs: eq41, rhs: eq42 }
     }
 
     /// Checks the fifth verification equation
@@ -585,7 +907,7 @@ impl Verification {
     /// - `z`: u64 value for z
     ///
     /// # Returns
-    /// Returns true if the equation holds, false otherwise
+    /// Returns both sides of the equation, which holds when they are equal
     pub fn check_equation_5(
         val_commit_poly_px: u64,
         g: u64,
@@ -594,7 +916,7 @@ impl Verification {
         vk: u64,
         z: u64,
         p: u64
-    ) -> bool {
+    ) -> EquationSides {
         // Print input values for debugging
         println_dbg!("val_commit_poly_px: {val_commit_poly_px}, val_y_p: {val_y_p}, vk: {vk}, val_commit_poly_qx: {val_commit_poly_qx}");
 
@@ -624,7 +946,7 @@ impl Verification {
         println_dbg!("------------------------------------");
 
         // Check if both evaluated components are equal
-        e_1 == e_2
+        EquationSides { lhs: e_1, rhs: e_2 }
     }
 
     /// Generates the polynomial ax based on the provided parameters.
@@ -671,9 +993,85 @@ impl Verification {
 }
 
 
+/// Verifier that checks proofs with the verifying key alone
+///
+/// The quotient commitment is part of the proof, so unlike the commitment key `ck`
+/// (one key per coefficient) only the compact `vk` is needed on the verifying device.
+#[derive(Debug, Clone)]
+pub struct LightVerifier {
+    verification: Verification,
+}
+
+impl LightVerifier {
+    /// Creates a new `LightVerifier` from proof generation data
+    pub fn new(data: &ProofGenerationJson) -> Self {
+        Self { verification: Verification::new(data) }
+    }
+
+    /// Derives the challenges with `hasher` instead of `Sha256Hasher`, see `Verification::with_hasher`
+    pub fn with_hasher(mut self, hasher: impl ChallengeHasher + 'static) -> Self {
+        self.verification = self.verification.with_hasher(hasher);
+        self
+    }
+
+    /// Verifies the proof, see `Verification::verify`
+    pub fn verify(
+        &self,
+        vk: u64,
+        class_data: ClassDataJson,
+        polys_px: Vec<FPoly>,
+        x_vec: Vec<u64>,
+        g: u64,
+        p: u64
+    ) -> Result<bool> {
+        self.verification.verify_with_vk(vk, class_data, polys_px, x_vec, g, p)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// A struct for building a `Verification` in memory.
+///
+/// Components that are not set stay zero, which is enough to run the checks that
+/// do not read them.
+pub struct VerificationBuilder {
+    data: ProofGenerationJson,
+}
+
+impl VerificationBuilder {
+    /// Sets the proof polynomial `poly`
+    pub fn poly(&mut self, poly: Polys, value: &FPoly) -> Self {
+        self.data.set_poly(poly as usize, value);
+        self.clone()
+    }
+
+    /// Sets the commitment of the proof polynomial `poly`
+    pub fn commit(&mut self, poly: Polys, value: u64) -> Self {
+        self.data.set_commit(poly as usize, value);
+        self.clone()
+    }
+
+    /// Sets sigma `num` (1, 2 or 3)
+    pub fn sigma(&mut self, num: usize, value: u64) -> Self {
+        self.data.set_sigma(num, value);
+        self.clone()
+    }
+
+    /// Sets value `num` (1: y'(z), 2: the commitment of q(x))
+    pub fn value(&mut self, num: usize, value: u64) -> Self {
+        self.data.set_value(num, value);
+        self.clone()
+    }
+
+    /// Builds a Verification using the builder pattern from the current state
+    pub fn build(&self) -> Verification {
+        Verification::new(&self.data)
+    }
+}
+
 #[cfg(test)]
 mod verification_test {
     use super::*;
+    use crate::fpoly;
     const P: u64 = 1678321;
 
     #[test]
@@ -716,7 +1114,7 @@ mod verification_test {
             &sigma_3,
             set_k_len,
             P
-        ));
+        ).holds());
 
 
         let beta_3_random = 34;
@@ -730,7 +1128,7 @@ mod verification_test {
             &sigma_3,
             set_k_len,
             P
-        ));
+        ).holds());
 
 
         // False 
@@ -744,7 +1142,7 @@ mod verification_test {
             &sigma_3,
             set_k_len + 1,
             P
-        ));
+        ).holds());
 
         let h_3x_false = fpoly!(
             1166561, 211242, 719491, 1291747, 1004539, 1587800, 445828, 923361, 482361, 1414088,
@@ -762,7 +1160,7 @@ mod verification_test {
             &sigma_3,
             set_k_len,
             P
-        ));
+        ).holds());
 
         let g_3x_false = fpoly!(
             1152011, 933053, 1057743, 1515370, 1622431, 1294320, 1371749);        
@@ -776,7 +1174,7 @@ mod verification_test {
             &sigma_3,
             set_k_len,
             P
-        ));
+        ).holds());
 
 
         let bx_false = fpoly!(
@@ -795,7 +1193,7 @@ mod verification_test {
             &sigma_3,
             set_k_len,
             P
-        ));
+        ).holds());
 
 
         assert!(!Verification::check_equation_1(
@@ -808,7 +1206,7 @@ mod verification_test {
             &42134,
             set_k_len,
             P
-        ));
+        ).holds());
     }
 
     #[test]
@@ -848,7 +1246,7 @@ mod verification_test {
             sigma_3,
             set_h_len,
             P
-        ));
+        ).holds());
 
         // False
         let poly_r_false = fpoly!(
@@ -867,7 +1265,7 @@ mod verification_test {
             sigma_3,
             set_h_len,
             P
-        ));
+        ).holds());
         
         let h_2x_false = fpoly!(
             1527224, 202963, 1641460, 1532214, 8621, 202835, 1266475, 76428, 328846, 1604258,
@@ -885,7 +1283,7 @@ mod verification_test {
             sigma_3,
             set_h_len,
             P
-        ));
+        ).holds());
 
         assert!(!Verification::check_equation_2(
             &poly_r,
@@ -897,7 +1295,7 @@ mod verification_test {
             sigma_3,
             set_h_len,
             P
-        ));
+        ).holds());
 
     }
 
@@ -963,7 +1361,7 @@ mod verification_test {
             sigma_2,
             set_h_len,
             P
-        ));
+        ).holds());
 
         // False
         let sum_1_false = &fpoly!(
@@ -988,7 +1386,7 @@ mod verification_test {
             sigma_2,
             set_h_len,
             P
-        ));
+        ).holds());
     }
     
     #[test]
@@ -1022,7 +1420,7 @@ mod verification_test {
             van_poly_vhx,
             beta_1,
             P
-        ));
+        ).holds());
 
         let beta_1_another = &57149;
         assert!(Verification::check_equation_4(
@@ -1031,7 +1429,7 @@ mod verification_test {
             van_poly_vhx,
             beta_1_another,
             P
-        ));
+        ).holds());
 
 
         // False
@@ -1047,7 +1445,7 @@ mod verification_test {
             van_poly_vhx,
             beta_1,
             P
-        ));
+        ).holds());
 
 
         let poly_ab_c_false = &fpoly!(
@@ -1066,7 +1464,32 @@ mod verification_test {
             van_poly_vhx,
             beta_1,
             P
-        ));
+        ).holds());
+    }
+
+    #[test]
+    fn test_check_4_names_violated_points() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof = fixture.prove(2);
+        let set_h = generate_set(fixture.class_data.set_h_len(), fixture.class_data, p);
+
+        // v_H(x) / (x - h) vanishes on H except at h, so z^C(x) changes only there
+        let h = set_h[3];
+        let (bump, _) = poly_fmath::div(&vanishing_poly(&set_h, p), &fpoly!(1, p - h), p);
+        let mut tampered = proof.clone();
+        tampered.set_poly(Polys::ZHatC as usize, &poly_fmath::add(&proof.get_poly(Polys::ZHatC as usize), &bump, p));
+
+        let err = fixture.try_verify(&tampered, p).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the remainder of the division for poly_h_0 should be zero; z^A(x) * z^B(x) - z^C(x) is nonzero on H at [{}]",
+                h
+            )
+        );
     }
 
     #[test]
@@ -1079,14 +1502,577 @@ mod verification_test {
         let z = 1536867;
 
         // True
-        assert!(Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z, P));
+        assert!(Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z, P).holds());
         
         // False
-        assert!(!Verification::check_equation_5(val_commit_poly_px + 1, g, val_y_p, val_commit_poly_qx, vk, z, P));
-        assert!(!Verification::check_equation_5(val_commit_poly_px, g - 1, val_y_p, val_commit_poly_qx, vk, z, P));
-        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p + 2, val_commit_poly_qx, vk, z, P));
-        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx - 3, vk, z, P));
-        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk + 4, z, P));
-        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z + 7, P));
+        assert!(!Verification::check_equation_5(val_commit_poly_px + 1, g, val_y_p, val_commit_poly_qx, vk, z, P).holds());
+        assert!(!Verification::check_equation_5(val_commit_poly_px, g - 1, val_y_p, val_commit_poly_qx, vk, z, P).holds());
+        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p + 2, val_commit_poly_qx, vk, z, P).holds());
+        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx - 3, vk, z, P).holds());
+        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk + 4, z, P).holds());
+        assert!(!Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z + 7, P).holds());
+    }
+
+    #[test]
+    fn test_pairing_target_generator() {
+        use crate::math::e_func_with;
+        use crate::math::E_GG;
+
+        let (val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z) = (1226529, 11, 311048, 714628, 1309, 1536867);
+        // Both sides of the fifth equation, with `gt` standing for e(g, g)
+        let sides = |gt: u64, val_y_p: u64| {
+            let e_1 = e_func_with(fmath::sub(val_commit_poly_px, fmath::mul(g, val_y_p, P), P), g, g, gt, P);
+            let e_2 = e_func_with(val_commit_poly_qx, fmath::sub(vk, fmath::mul(g, z, P), P), g, gt, P);
+            (e_1, e_2)
+        };
+
+        let (e_1, e_2) = sides(E_GG, val_y_p);
+        let expected = Verification::check_equation_5(val_commit_poly_px, g, val_y_p, val_commit_poly_qx, vk, z, P);
+        assert_eq!(EquationSides { lhs: e_1, rhs: e_2 }, expected);
+
+        // Any nonzero e(g, g) scales both sides alike: the honest opening holds, a wrong one fails
+        for gt in [1, E_GG, 7, P - 1] {
+            let (e_1, e_2) = sides(gt, val_y_p);
+            assert_eq!(e_1, e_2);
+            let (e_1, e_2) = sides(gt, val_y_p + 2);
+            assert_ne!(e_1, e_2);
+        }
+
+        // A zero e(g, g) would accept the wrong opening too
+        let (e_1, e_2) = sides(0, val_y_p + 2);
+        assert_eq!(e_1, e_2);
+    }
+
+    #[test]
+    fn test_eval_poly_px() {
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::transcript::Transcript;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let p = fixture.class_data.p;
+        let verification = Verification::new(&proof);
+
+        let transcript = Transcript::new(&proof.get_poly(Polys::Sx as usize), p);
+        let eta_values = transcript.proof_etas();
+
+        let poly_px = eta_values
+            .iter()
+            .enumerate()
+            .map(|(i, &eta)| poly_fmath::mul_by_number(&proof.get_poly(i), eta, p))
+            .fold(FPoly::zero(), |acc, poly| poly_fmath::add(&acc, &poly, p));
+
+        for z in [0, 1, transcript.z(), p - 1] {
+            assert_eq!(verification.eval_poly_px(&eta_values, z, p), poly_px.evaluate(z, p));
+        }
+    }
+
+    #[test]
+    fn test_public_input_length_mismatch() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let verify = |x_vec: Vec<u64>| {
+            Verification::new(&proof).verify(
+                (&fixture.ck, fixture.vk),
+                fixture.class_data,
+                fixture.commitment_json.get_polys_px(),
+                x_vec,
+                fixture.class_data.g,
+                fixture.class_data.p,
+            )
+        };
+
+        let mut x_vec = proof.get_x_vec();
+        assert!(verify(x_vec.clone()).unwrap());
+
+        x_vec.pop();
+        let err = verify(x_vec.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "public input holds 32 values, but the class needs n_i + 1 = 33");
+
+        x_vec.extend([1, 2]);
+        let err = verify(x_vec).unwrap_err();
+        assert_eq!(err.to_string(), "public input holds 34 values, but the class needs n_i + 1 = 33");
+    }
+
+    #[test]
+    fn test_field_modulus_mismatch() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let p = fixture.class_data.p;
+        assert_eq!(proof.p, Some(p));
+
+        let err = fixture.try_verify(&proof, 2060801).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("field modulus mismatch: proof was generated with p = {}, verifier uses p = 2060801", p)
+        );
+
+        // Proof files written before the modulus was recorded are still accepted
+        let mut legacy = proof.clone();
+        legacy.p = None;
+        assert!(fixture.try_verify(&legacy, p).unwrap());
+    }
+
+    #[test]
+    fn test_challenge_encoding() {
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::transcript::ChallengeEncoding;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        assert_eq!(proof.challenge_encoding(), ChallengeEncoding::Bytes);
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.contains(r#""Challenges":"Bytes""#));
+
+        // Without the field the proof is read as an older one, and checked with the
+        // decimal challenges it wasn't generated with
+        let json = json.replace(r#""Challenges":"Bytes","#, "");
+        let older: ProofGenerationJson = serde_json::from_str(&json).unwrap();
+        assert_eq!(older.challenge_encoding(), ChallengeEncoding::Decimal);
+        assert!(!fixture.verify(&older));
+    }
+
+    #[test]
+    fn test_verify_baseline_proof() {
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::transcript::ChallengeEncoding;
+        use crate::utils::read_json_file;
+
+        // Written by the original prover for the fixture's program and keys, before proof
+        // files recorded the modulus, the challenge encoding or the hasher
+        let baseline: ProofGenerationJson = read_json_file("tests/fixtures/baseline_proof.json").unwrap();
+        assert_eq!((baseline.p, baseline.challenges), (None, None));
+        assert_eq!(baseline.challenge_encoding(), ChallengeEncoding::Decimal);
+
+        let fixture = fixture();
+        assert!(fixture.verify(&baseline));
+
+        // The original prover didn't reduce sigma_3
+        let p = fixture.class_data.p;
+        assert!(baseline.get_sigma(3) >= p);
+
+        // Derived with the current encoding, the same proof fails
+        let mut relabelled = baseline.clone();
+        relabelled.challenges = Some(ChallengeEncoding::Bytes);
+        relabelled.set_sigma(3, baseline.get_sigma(3) % p);
+        assert!(!fixture.verify(&relabelled));
+    }
+
+    #[test]
+    fn test_builder_check_2() {
+        let set_h_len = 4;
+        let (alpha, beta_2, sigma_3) = (3, 10, 11);
+        let h_2x = fpoly!(5, 0, 7);
+        let g_2x = fpoly!(2, 9);
+
+        // Pick sigma_2 so that r(beta_2) * sigma_3 = h_2(beta_2) * v_H(beta_2) + beta_2 * g_2(beta_2) + sigma_2 / |H|
+        let poly_r = poly_func_u(Some(alpha), None, set_h_len, P);
+        let van_poly_vhx = Verification::vanishing_poly(set_h_len, P);
+        let lhs = fmath::mul(poly_r.evaluate(beta_2, P), sigma_3, P);
+        let rest = fmath::add(
+            fmath::mul(h_2x.evaluate(beta_2, P), van_poly_vhx.evaluate(beta_2, P), P),
+            fmath::mul(beta_2, g_2x.evaluate(beta_2, P), P),
+            P,
+        );
+        let sigma_2 = fmath::mul(fmath::sub(lhs, rest, P), set_h_len as u64, P);
+
+        let mut builder = Verification::builder()
+            .poly(Polys::H2x, &h_2x)
+            .poly(Polys::G2x, &g_2x)
+            .sigma(2, sigma_2)
+            .sigma(3, sigma_3);
+        let verification = builder.build();
+        assert_eq!(verification.data.get_poly(Polys::H2x as usize), h_2x);
+        assert!(verification.check_2(&[0, beta_2, 0], alpha, &van_poly_vhx, set_h_len, P).holds());
+
+        let verification = builder.sigma(2, sigma_2 + 1).build();
+        assert!(!verification.check_2(&[0, beta_2, 0], alpha, &van_poly_vhx, set_h_len, P).holds());
+    }
+
+    #[test]
+    fn test_validate_malformed_proof() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof = fixture.prove(2);
+        proof.validate(&fixture.class_data).unwrap();
+
+        // Out-of-range sigma is reported by verify instead of going through the checks,
+        // also when it is congruent to the honest value
+        for (i, sigma) in [(2, p + 3), (3, p + 5), (3, proof.get_sigma(3) + p)] {
+            let mut tampered = proof.clone();
+            tampered.set_sigma(i, sigma);
+            let err = fixture.try_verify(&tampered, p).unwrap_err();
+            assert_eq!(err.to_string(), format!("sigma_{} = {} is outside the field (p = {})", i, sigma, p));
+        }
+
+        // A wrong sigma inside the field is a failed check, not an error
+        let mut tampered = proof.clone();
+        tampered.set_sigma(3, (proof.get_sigma(3) + 5) % p);
+        assert!(!fixture.try_verify(&tampered, p).unwrap());
+
+        let mut tampered = proof.clone();
+        tampered.set_poly(Polys::G1x as usize, &FPoly::zero());
+        let err = tampered.validate(&fixture.class_data).unwrap_err();
+        assert_eq!(err.to_string(), "P8AHP is empty");
+
+        let mut class_data = fixture.class_data;
+        class_data.n_i += 1;
+        let err = proof.validate(&class_data).unwrap_err();
+        assert!(err.to_string().starts_with("Com1_AHP_x holds 32 values"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_with_public_inputs() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let verify = |proof: &ProofGenerationJson, expected_x: &[u64]| {
+            Verification::new(proof).verify_with_public_inputs(
+                (&fixture.ck, fixture.vk),
+                fixture.class_data,
+                fixture.commitment_json.get_polys_px(),
+                fixture.class_data.g,
+                fixture.class_data.p,
+                expected_x,
+            )
+        };
+
+        let trusted_x = fixture.z_vec[..=fixture.class_data.n_i as usize].to_vec();
+        assert!(verify(&proof, &trusted_x).unwrap());
+
+        // The proof claims other public inputs than the ones the verifier holds
+        let mut expected_x = trusted_x.clone();
+        expected_x[3] += 1;
+        let err = verify(&proof, &expected_x).unwrap_err();
+        assert!(err.to_string().contains("first differ at index 3"), "{}", err);
+
+        let err = verify(&proof, &trusted_x[..10]).unwrap_err();
+        assert!(err.to_string().contains("first differ at index 10"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_with_prepared() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let prepared = PreparedCommitment::new(&fixture.commitment_json, fixture.class_data, p);
+
+        let mut proofs: Vec<_> = (1..=3).map(|random_b| fixture.prove(random_b)).collect();
+        let mut tampered = proofs[0].clone();
+        tampered.set_sigma(2, fmath::add(proofs[0].get_sigma(2), 1, p));
+        proofs.push(tampered);
+
+        let results: Vec<bool> = proofs
+            .iter()
+            .map(|proof| {
+                Verification::new(proof)
+                    .verify_with_prepared(fixture.vk, &prepared, proof.get_x_vec(), fixture.class_data.g)
+                    .unwrap()
+            })
+            .collect();
+        let expected: Vec<bool> = proofs.iter().map(|proof| fixture.verify(proof)).collect();
+
+        assert_eq!(results, expected);
+        assert_eq!(results, [true, true, true, false]);
+    }
+
+    #[test]
+    fn test_verify_timed() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof = fixture.prove(2);
+        let mut tampered = proof.clone();
+        tampered.set_sigma(2, fmath::add(proof.get_sigma(2), 1, p));
+
+        for proof in [proof, tampered] {
+            let (res, timings) = Verification::new(&proof)
+                .verify_timed(
+                    (&fixture.ck, fixture.vk),
+                    fixture.class_data,
+                    fixture.commitment_json.get_polys_px(),
+                    proof.get_x_vec(),
+                    fixture.class_data.g,
+                    p,
+                )
+                .unwrap();
+
+            assert_eq!(res, fixture.verify(&proof));
+            assert!(timings.total > Duration::ZERO, "{:?}", timings);
+            assert!(timings.total >= timings.checks(), "{:?}", timings);
+
+            // Each check writes its own timing, whatever the clock's resolution
+            let sentinel = Duration::MAX;
+            let mut timings = CheckTimings {
+                check1: sentinel,
+                check2: sentinel,
+                check3: sentinel,
+                check4: sentinel,
+                check5: sentinel,
+                total: sentinel,
+            };
+            let prepared = PreparedCommitment::from_polys(fixture.commitment_json.get_polys_px(), fixture.class_data, p);
+            Verification::new(&proof)
+                .run_checks(fixture.vk, &prepared, proof.get_x_vec(), fixture.class_data.g, Some(&mut timings), None)
+                .unwrap();
+            let checks = [timings.check1, timings.check2, timings.check3, timings.check4, timings.check5];
+            assert!(checks.iter().all(|&check| check != sentinel), "{:?}", timings);
+            assert_eq!(timings.total, sentinel);
+        }
+    }
+
+    #[test]
+    fn test_verify_verbose() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let verbose = |proof: &ProofGenerationJson| {
+            Verification::new(proof)
+                .verify_verbose(
+                    (&fixture.ck, fixture.vk),
+                    fixture.class_data,
+                    fixture.commitment_json.get_polys_px(),
+                    proof.get_x_vec(),
+                    fixture.class_data.g,
+                    p,
+                )
+                .unwrap()
+        };
+
+        let proof = fixture.prove(2);
+        let (res, trace) = verbose(&proof);
+        assert!(res);
+        assert!(trace.equations.iter().all(EquationSides::holds), "{:?}", trace);
+
+        // The challenges are the ones derived from the proof's s(x)
+        let transcript = Transcript::with_encoding(&proof.get_poly(Polys::Sx as usize), p, proof.challenge_encoding());
+        assert_eq!(trace.alpha, transcript.alpha());
+        assert_eq!([trace.eta_a, trace.eta_b, trace.eta_c], transcript.etas());
+        assert_eq!(trace.z, transcript.z());
+
+        // A wrong sigma_1 only unbalances the third equation
+        let mut tampered = proof.clone();
+        tampered.set_sigma(1, fmath::add(proof.get_sigma(1), 1, p));
+        let (res, tampered_trace) = verbose(&tampered);
+        assert!(!res);
+        assert_eq!(tampered_trace.alpha, trace.alpha);
+        let failed: Vec<usize> = (0..5).filter(|&i| !tampered_trace.equations[i].holds()).collect();
+        assert_eq!(failed, [2]);
+    }
+
+    #[test]
+    fn test_setup_too_small() {
+        use crate::ahp::test_utils::fixture;
+        use crate::kzg;
+
+        let fixture = fixture();
+        let small_class = ClassDataJson::get_class_data("class.json", 1).unwrap();
+        let small_ck = kzg::setup(small_class.d_ahp(), 119, small_class.g, small_class.p);
+        assert!(small_class.d_ahp() < fixture.class_data.d_ahp());
+
+        let proof = fixture.prove(2);
+        let err = Verification::new(&proof)
+            .verify(
+                (&small_ck, small_ck[1]),
+                fixture.class_data,
+                fixture.commitment_json.get_polys_px(),
+                proof.get_x_vec(),
+                fixture.class_data.g,
+                fixture.class_data.p,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "setup too small for this proof's class: ck holds {} keys, but the class needs D_AHP = {}",
+                small_class.d_ahp(),
+                fixture.class_data.d_ahp()
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify_with_hasher() {
+        use crate::ahp::challenge_hasher::PoseidonHasher;
+        use crate::ahp::test_utils::fixture;
+        use crate::ahp::test_utils::CLASS_NUMBER;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let verify_poseidon = |proof: &ProofGenerationJson| {
+            Verification::new(proof).with_hasher(PoseidonHasher).verify(
+                (&fixture.ck, fixture.vk),
+                fixture.class_data,
+                fixture.commitment_json.get_polys_px(),
+                proof.get_x_vec(),
+                fixture.class_data.g,
+                p,
+            )
+        };
+
+        let proof_data = ProofGeneration::new()
+            .with_hasher(PoseidonHasher)
+            .generate_proof(
+                &fixture.ck,
+                fixture.class_data,
+                fixture.program_params.clone(),
+                fixture.commitment_json.clone(),
+                fixture.z_vec.clone(),
+                2,
+                p,
+            )
+            .unwrap();
+        let mut proof = ProofGenerationJson::new(
+            proof_data,
+            CLASS_NUMBER,
+            fixture.commitment_json.info.commitment_id.clone(),
+            p,
+        );
+        assert!(verify_poseidon(&proof).unwrap());
+        assert!(!fixture.verify(&proof));
+
+        // A SHA-256 proof that doesn't record its hasher fails the checks at the Poseidon challenges
+        let mut sha256 = fixture.prove(2);
+        sha256.hasher = None;
+        assert!(!verify_poseidon(&sha256).unwrap());
+
+        proof.hasher = Some("sha256".to_string());
+        let err = verify_poseidon(&proof).unwrap_err();
+        assert!(err.to_string().contains("hashed with sha256"), "{}", err);
+    }
+
+    #[test]
+    fn test_proof_envelope_mismatch() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof = fixture.prove(2);
+        assert_eq!(proof.scheme_version, Some(SCHEME_VERSION));
+
+        let mut other_hash = proof.clone();
+        other_hash.hasher = Some("blake3".to_string());
+        let err = fixture.try_verify(&other_hash, p).unwrap_err();
+        assert_eq!(err.to_string(), "proof challenges were hashed with blake3, but the verifier uses sha256");
+
+        let mut other_version = proof.clone();
+        other_version.scheme_version = Some(SCHEME_VERSION + 1);
+        let err = fixture.try_verify(&other_version, p).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "scheme version mismatch: proof was generated with version {}, verifier uses version {}",
+                SCHEME_VERSION + 1,
+                SCHEME_VERSION
+            )
+        );
+
+        // Proof files written before the version was recorded are still accepted
+        let mut legacy = proof;
+        legacy.scheme_version = None;
+        assert!(fixture.try_verify(&legacy, p).unwrap());
+    }
+
+    #[test]
+    fn test_verify_from_bytes() {
+        use crate::ahp::challenge_hasher::PoseidonHasher;
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let proof_json = serde_json::to_vec(&proof).unwrap();
+        let commitment_json = serde_json::to_vec(&fixture.commitment_json).unwrap();
+
+        assert!(verify_from_bytes(&proof_json, &commitment_json, fixture.class_data, fixture.vk).unwrap());
+
+        let mut tampered = proof.clone();
+        tampered.set_value(2, proof.get_value(2) + 1);
+        let tampered_json = serde_json::to_vec(&tampered).unwrap();
+        assert!(!verify_from_bytes(&tampered_json, &commitment_json, fixture.class_data, fixture.vk).unwrap());
+
+        let mut other = proof.clone();
+        other.commitment_id = "0".repeat(64);
+        let other_json = serde_json::to_vec(&other).unwrap();
+        let err = verify_from_bytes(&other_json, &commitment_json, fixture.class_data, fixture.vk).unwrap_err();
+        assert!(err.to_string().starts_with("proof was generated for commitment 000"), "{}", err);
+
+        assert!(verify_from_bytes(b"{", &commitment_json, fixture.class_data, fixture.vk).is_err());
+
+        // The hasher recorded in the proof is used
+        let poseidon = fixture.prove_with(ProofGeneration::new().with_hasher(PoseidonHasher), 2);
+        let poseidon_json = serde_json::to_vec(&poseidon).unwrap();
+        assert!(verify_from_bytes(&poseidon_json, &commitment_json, fixture.class_data, fixture.vk).unwrap());
+
+        let mut unknown = poseidon.clone();
+        unknown.hasher = Some("blake3".to_string());
+        let unknown_json = serde_json::to_vec(&unknown).unwrap();
+        let err = verify_from_bytes(&unknown_json, &commitment_json, fixture.class_data, fixture.vk).unwrap_err();
+        assert!(err.to_string().starts_with("unknown challenge hasher blake3"), "{}", err);
+    }
+
+    #[test]
+    fn test_verification_is_deterministic() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let p = fixture.class_data.p;
+        let proof = fixture.prove(2);
+
+        // g_3(x) only enters the check at beta_3
+        let mut tampered = proof.clone();
+        let g_3x = poly_fmath::add(&proof.get_poly(Polys::G3x as usize), &FPoly::one(), p);
+        tampered.set_poly(Polys::G3x as usize, &g_3x);
+
+        for _ in 0..20 {
+            assert!(fixture.verify(&proof));
+            assert!(!fixture.verify(&tampered));
+        }
+    }
+
+    #[test]
+    fn test_light_verifier() {
+        use crate::ahp::test_utils::fixture;
+
+        let fixture = fixture();
+        let proof = fixture.prove(2);
+        let verify = |proof: &ProofGenerationJson| {
+            LightVerifier::new(proof)
+                .verify(
+                    fixture.vk,
+                    fixture.class_data,
+                    fixture.commitment_json.get_polys_px(),
+                    proof.get_x_vec(),
+                    fixture.class_data.g,
+                    fixture.class_data.p,
+                )
+                .unwrap()
+        };
+        assert!(verify(&proof));
+
+        // Quotient commitment
+        let mut tampered = proof.clone();
+        tampered.set_value(2, proof.get_value(2) + 1);
+        assert!(!verify(&tampered));
+
+        // Commitment of a proof polynomial
+        let mut tampered = proof.clone();
+        tampered.set_commit(Polys::H3x as usize, proof.get_commits(Polys::H3x as usize) + 1);
+        assert!(!verify(&tampered));
+
+        // Proof polynomial
+        let mut tampered = proof.clone();
+        let poly = poly_fmath::add(&proof.get_poly(Polys::G2x as usize), &FPoly::one(), fixture.class_data.p);
+        tampered.set_poly(Polys::G2x as usize, &poly);
+        assert!(!verify(&tampered));
     }
 }
@@ -14,20 +14,40 @@
 
 
 use std::fs::File;
+use std::io::Read;
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
 use rand::{thread_rng, Rng};
 use serde::Serialize;
 use std::io::BufWriter;
 use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
 
+use crate::field::fmath;
 use crate::kzg;
 use crate::json_file::write_set;
+use crate::json_file::ClassDataJson;
 use crate::utils::read_json_file;
 
 /// Struct for setup data with commitment and verifying keys
 pub struct Setup {
     ck: Vec<u64>, // Commitment keys
     vk: u64,      // Verifying key
+    /// Secret `tau` the keys were generated with, kept only to `extend` them
+    tau: Option<u64>,
+}
+
+/// Prints the keys, but only whether `tau` is kept, never its value
+impl std::fmt::Debug for Setup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Setup")
+            .field("ck", &self.ck)
+            .field("vk", &self.vk)
+            .field("tau", &self.tau.map(|_| "<redacted>"))
+            .finish()
+    }
 }
 
 impl Setup {
@@ -36,21 +56,116 @@ impl Setup {
         Self {
             ck: Vec::default(),
             vk: u64::default(),
+            tau: None,
         }
     }
     
     /// Generates commitment and verifying keys
     ///
+    /// `tau` is kept in memory, never in the setup file, so the keys can be `extend`ed
+    /// later; call `forget_tau` once no extension is needed.
+    ///
     /// # Parameters
     /// - `num`: Number of keys to generate.
     pub fn generate_keys(&mut self, num: u64, p: u64, g: u64) {
         let tau = thread_rng().gen_range(1..p);  // Placeholder for a random number
 
         // Generate commitment keys using KZG.
-        let ck = kzg::setup(num, tau, g, p);
+        let ck = kzg::setup_parallel(num, tau, g, p);
 
         self.ck = ck; // Store commitment keys
         self.vk = self.ck[1]; // Set verifying key
+        self.tau = Some(tau);
+    }
+
+    /// Grows the commitment keys to `new_max` values by continuing the powers of `tau`
+    ///
+    /// The result is the same as generating `new_max` keys with the same `tau` from the start.
+    /// Does nothing if there are already `new_max` keys or more.
+    ///
+    /// # Errors
+    /// Fails if `tau` isn't known, i.e. the keys were loaded from a ceremony or `forget_tau`
+    /// was called, or if there are no keys to extend.
+    pub fn extend(&mut self, new_max: u64, p: u64) -> Result<()> {
+        let tau = self
+            .tau
+            .ok_or_else(|| anyhow!("can't extend the setup: tau was not kept, generate new keys instead"))?;
+        let mut last = *self
+            .ck
+            .last()
+            .ok_or_else(|| anyhow!("can't extend the setup: it holds no keys yet"))?;
+
+        // Same reduction of tau as `kzg::setup`
+        let tau = tau % (p - 1);
+        while (self.ck.len() as u64) < new_max {
+            last = fmath::mul(last, tau, p);
+            self.ck.push(last);
+        }
+        Ok(())
+    }
+
+    /// Overwrites and drops `tau`, after which the keys can no longer be extended
+    pub fn forget_tau(&mut self) {
+        if let Some(tau) = self.tau.as_mut() {
+            // SAFETY: `tau` is a valid, aligned &mut u64; the write is volatile so the
+            // compiler can't drop it as a dead store before `tau` is cleared below
+            unsafe { std::ptr::write_volatile(tau, 0) };
+        }
+        self.tau = None;
+    }
+
+    /// Loads commitment keys produced by an external powers-of-tau ceremony
+    ///
+    /// The stream holds a little-endian `u64` count followed by that many little-endian
+    /// `u64` values `g * tau^i mod p`, for `i = 0, 1, ...` (the group elements
+    /// `g^{tau^i}` as this crate models them). The first `ClassDataJson::d_ahp` values
+    /// become the commitment keys.
+    ///
+    /// # Errors
+    /// Fails if the stream is truncated, holds fewer values than the class needs,
+    /// or holds values outside the field or a first value other than `g`.
+    pub fn from_powers_of_tau(mut reader: impl Read, class_data: &ClassDataJson) -> Result<Self> {
+        let mut read_u64 = |what: &str| -> Result<u64> {
+            let mut buf = [0u8; 8];
+            reader
+                .read_exact(&mut buf)
+                .with_context(|| format!("powers of tau stream ended while reading {}", what))?;
+            Ok(u64::from_le_bytes(buf))
+        };
+
+        let count = read_u64("the count")?;
+        let needed = class_data.d_ahp();
+        if count < needed {
+            return Err(anyhow!(
+                "powers of tau holds {} values, but the class needs at least {}",
+                count,
+                needed
+            ));
+        }
+
+        let mut ck = Vec::with_capacity(needed as usize);
+        for i in 0..needed {
+            let value = read_u64(&format!("value {}", i))?;
+            if value >= class_data.p {
+                return Err(anyhow!(
+                    "powers of tau value {} is {}, which is outside the field (P = {})",
+                    i,
+                    value,
+                    class_data.p
+                ));
+            }
+            ck.push(value);
+        }
+        if ck[0] != class_data.g % class_data.p {
+            return Err(anyhow!(
+                "powers of tau starts with {}, expected the generator g = {}",
+                ck[0],
+                class_data.g
+            ));
+        }
+
+        let vk = ck[1];
+        Ok(Self { ck, vk, tau: None })
     }
 
     /// Saves setup data to a JSON file
@@ -73,8 +188,24 @@ impl Setup {
     ///
     /// # Returns
     /// Returns a `Result` with the restored `SetupJson`
+    ///
+    /// # Errors
+    /// Fails with "setup file corrupted" if the keys don't match the checksum stored with
+    /// them. Files written before the checksum was added load without the check.
     pub fn restore(path: &str) -> Result<SetupJson> {
-        read_json_file(path) // Read and deserialize JSON
+        let setup_json: SetupJson = read_json_file(path)?; // Read and deserialize JSON
+        if let Some(stored) = &setup_json.checksum {
+            let computed = setup_json.compute_checksum();
+            if *stored != computed {
+                return Err(anyhow!(
+                    "setup file corrupted: {}: checksum is {}, but the keys hash to {}",
+                    path,
+                    stored,
+                    computed
+                ));
+            }
+        }
+        Ok(setup_json)
     }
 }
 
@@ -85,17 +216,37 @@ pub struct SetupJson {
     class: u8,         // Class identifier
     ck: Vec<u64>,      // Commitment keys
     vk: u64,           // Verifying key
+
+    /// SHA-256 of the class and keys (absent in older setup files)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
 }
 
 impl SetupJson {
     /// Creates a new `SetupJson` from commitment keys and a class identifier
     pub fn new(ck: &Vec<u64>, class: u8) -> Self {
         let ck = write_set(ck); // Convert u64 to u64
-        Self {
+        let mut setup_json = Self {
             class,
             ck: ck.clone(), // Store commitment keys
             vk: ck[1],     // Set verifying key
+            checksum: None,
+        };
+        setup_json.checksum = Some(setup_json.compute_checksum());
+        setup_json
+    }
+
+    /// Hex SHA-256 of the class, the number of keys, the keys and the verifying key,
+    /// each integer in big-endian bytes
+    fn compute_checksum(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([self.class]);
+        hasher.update((self.ck.len() as u64).to_be_bytes());
+        for key in &self.ck {
+            hasher.update(key.to_be_bytes());
         }
+        hasher.update(self.vk.to_be_bytes());
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
     }
 
     /// Gets commitment keys as `u64`.
@@ -107,4 +258,104 @@ impl SetupJson {
     pub fn get_vk(&self) -> u64 {
         self.vk
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test_setup {
+    use super::*;
+
+    #[test]
+    fn test_from_powers_of_tau() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let needed = class_data.d_ahp();
+        let expected = kzg::setup(needed + 5, 119, class_data.g, class_data.p);
+
+        let stream = |values: &[u64]| -> Vec<u8> {
+            let mut bytes = (values.len() as u64).to_le_bytes().to_vec();
+            bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            bytes
+        };
+
+        // Extra powers are ignored
+        let setup = Setup::from_powers_of_tau(&stream(&expected)[..], &class_data).unwrap();
+        let setup_json = SetupJson::new(&setup.ck, 2);
+        assert_eq!(setup_json.get_ck(), expected[..needed as usize]);
+        assert_eq!(setup_json.get_vk(), expected[1]);
+
+        let short = stream(&expected[..needed as usize - 1]);
+        let err = Setup::from_powers_of_tau(&short[..], &class_data).unwrap_err();
+        assert_eq!(err.to_string(), "powers of tau holds 77 values, but the class needs at least 78");
+
+        let truncated = stream(&expected);
+        let err = Setup::from_powers_of_tau(&truncated[..100], &class_data).unwrap_err();
+        assert!(err.to_string().starts_with("powers of tau stream ended"), "{}", err);
+
+        let mut out_of_field = expected.clone();
+        out_of_field[3] = class_data.p;
+        assert!(Setup::from_powers_of_tau(&stream(&out_of_field)[..], &class_data).is_err());
+    }
+
+    #[test]
+    fn test_extend() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let (p, g) = (class_data.p, class_data.g);
+
+        let mut setup = Setup::default();
+        setup.generate_keys(10, p, g);
+        let vk = setup.vk;
+        setup.extend(class_data.d_ahp(), p).unwrap();
+
+        let fresh = kzg::setup(class_data.d_ahp(), setup.tau.unwrap(), g, p);
+        assert_eq!(setup.ck, fresh);
+        assert_eq!(setup.vk, vk);
+
+        // Never shrinks
+        setup.extend(5, p).unwrap();
+        assert_eq!(setup.ck, fresh);
+
+        setup.forget_tau();
+        let err = setup.extend(200, p).unwrap_err();
+        assert!(err.to_string().contains("tau was not kept"), "{}", err);
+        assert!(Setup::default().extend(200, p).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_tau() {
+        let mut setup = Setup { ck: vec![11, 1309], vk: 1309, tau: Some(119) };
+        assert_eq!(format!("{:?}", setup), r#"Setup { ck: [11, 1309], vk: 1309, tau: Some("<redacted>") }"#);
+
+        setup.forget_tau();
+        assert_eq!(format!("{:?}", setup), "Setup { ck: [11, 1309], vk: 1309, tau: None }");
+    }
+
+    #[test]
+    fn test_store_restore_checksum() {
+        let path = std::env::temp_dir().join(format!("zkiot_setup_checksum_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut setup = Setup::default();
+        setup.generate_keys(10, 1678321, 11);
+        setup.store(path, 2).unwrap();
+        let restored = Setup::restore(path).unwrap();
+        assert_eq!(restored.get_ck(), setup.ck);
+        assert_eq!(restored.get_vk(), setup.vk);
+
+        // Change one digit of the last key, as a bad transfer would
+        let json = std::fs::read_to_string(path).unwrap();
+        let end = json.find("],").unwrap();
+        let mut bytes = json.into_bytes();
+        bytes[end - 1] = if bytes[end - 1] == b'0' { b'1' } else { b'0' };
+        std::fs::write(path, &bytes).unwrap();
+
+        let err = Setup::restore(path).unwrap_err();
+        assert!(err.to_string().starts_with("setup file corrupted"), "{}", err);
+
+        // Older files without a checksum still load
+        let mut setup_json: SetupJson = serde_json::from_slice(&bytes).unwrap();
+        setup_json.checksum = None;
+        std::fs::write(path, serde_json::to_string(&setup_json).unwrap()).unwrap();
+        assert!(Setup::restore(path).is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
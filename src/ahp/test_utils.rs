@@ -0,0 +1,187 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared fixtures for tests that run the whole commit / prove / verify pipeline.
+//!
+//! Built for this crate's tests, and for other crates' tests with the `test-utils` feature.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::field::fmath;
+use crate::json_file::ClassDataJson;
+use crate::json_file::DeviceConfigJson;
+use crate::json_file::LineValue;
+use crate::json_file::ProgramParamsJson;
+use crate::kzg;
+use crate::parser::Gate;
+use crate::parser::Instructions;
+use crate::parser::RiscvReg;
+
+use super::commitment_generation::Commitment;
+use super::commitment_generation::CommitmentJson;
+use super::proof_generation::ProofGeneration;
+use super::proof_generation::ProofGenerationJson;
+use super::proof_verification::Verification;
+
+/// Class used by the fixture (4 gates, 32 registers)
+pub const CLASS_NUMBER: u8 = 2;
+
+/// Class table of this crate, found whatever directory the tests run from
+const CLASS_TABLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/class.json");
+
+/// Everything the prover and the verifier need for one small program
+pub struct Fixture {
+    pub class_data: ClassDataJson,
+    pub commitment_json: CommitmentJson,
+    pub program_params: ProgramParamsJson,
+    pub ck: Vec<u64>,
+    pub vk: u64,
+    pub z_vec: Vec<u64>,
+}
+
+/// Four gates touching `a0`, `a1` and `a2`, exercising addi, add and mul
+pub fn sample_gates() -> Vec<Gate> {
+    vec![
+        Gate::new(None, Some(5), RiscvReg::A0, RiscvReg::A0, RiscvReg::Zero, Instructions::Addi),
+        Gate::new(None, None, RiscvReg::A1, RiscvReg::A0, RiscvReg::A2, Instructions::Mul),
+        Gate::new(None, Some(9), RiscvReg::A2, RiscvReg::A1, RiscvReg::Zero, Instructions::Addi),
+        Gate::new(None, None, RiscvReg::A0, RiscvReg::A1, RiscvReg::A2, Instructions::Add),
+    ]
+}
+
+/// Device configuration used to derive the commitment id
+pub fn sample_device_config() -> DeviceConfigJson {
+    DeviceConfigJson {
+        class: CLASS_NUMBER,
+        iot_developer_name: "Fidesinnova".to_string(),
+        iot_device_name: "zk-MultiSensor".to_string(),
+        device_hardware_version: "1.0".to_string(),
+        firmware_version: "1.0".to_string(),
+        code_block: LineValue::Range((1, 4)),
+    }
+}
+
+/// Executes `gates` on deterministic register values and returns the vector z
+///
+/// z = [1, x1..x32 (registers), w1..wn (gate outputs)], matching the layout
+/// `CommitmentBuilder::gen_matrices` uses for the matrices.
+pub fn witness(gates: &[Gate], n_i: usize, p: u64) -> Vec<u64> {
+    let mut z_vec = vec![1];
+    z_vec.extend((1..=n_i as u64).map(|i| (7 * i + 3) % p));
+
+    let mut regs_data: HashMap<RiscvReg, usize> = HashMap::new();
+    for (counter, gate) in gates.iter().enumerate() {
+        let value_of = |reg: RiscvReg| match regs_data.get(&reg) {
+            Some(&index) => z_vec[index],
+            None => z_vec[reg as usize + 1],
+        };
+        let left = gate.val_left.unwrap_or_else(|| value_of(gate.reg_left));
+        let right = gate.val_right.unwrap_or_else(|| value_of(gate.reg_right));
+
+        let out = match gate.instr {
+            Instructions::Add | Instructions::Addi => fmath::add(left, right, p),
+            Instructions::Mul => fmath::mul(left, right, p),
+        };
+        z_vec.push(out);
+        regs_data.insert(gate.des_reg, 1 + n_i + counter);
+    }
+    z_vec
+}
+
+/// Builds commitment, program parameters, keys and witness for `gates`
+pub fn fixture_with(gates: Vec<Gate>) -> Fixture {
+    let class_data = ClassDataJson::get_class_data(CLASS_TABLE, CLASS_NUMBER).unwrap();
+    let p = class_data.p;
+    let n_i = class_data.n_i as usize;
+
+    let z_vec = witness(&gates, n_i, p);
+
+    let commitment = Commitment::new(class_data)
+        .gen_matrices(gates, n_i, p)
+        .gen_polynomials(p)
+        .unwrap()
+        .build();
+    let program_params =
+        ProgramParamsJson::new(&commitment.matrices, &commitment.points_px, class_data, p);
+    let commitment_json = CommitmentJson::new(
+        &commitment.polys_px,
+        CLASS_NUMBER,
+        class_data,
+        sample_device_config(),
+    );
+
+    // Fixed tau keeps the keys reproducible
+    let ck = kzg::setup(class_data.d_ahp(), 119, class_data.g, p);
+    let vk = ck[1];
+
+    Fixture {
+        class_data,
+        commitment_json,
+        program_params,
+        ck,
+        vk,
+        z_vec,
+    }
+}
+
+/// Fixture built from `sample_gates`
+pub fn fixture() -> Fixture {
+    fixture_with(sample_gates())
+}
+
+impl Fixture {
+    /// Generates a proof for the fixture's witness
+    pub fn prove(&self, random_b: u64) -> ProofGenerationJson {
+        self.prove_with(ProofGeneration::new(), random_b)
+    }
+
+    /// Same as `prove`, with the prover configured by `prover`, e.g. its hasher
+    pub fn prove_with(&self, prover: ProofGeneration, random_b: u64) -> ProofGenerationJson {
+        let proof_data = prover.generate_proof(
+            &self.ck,
+            self.class_data,
+            self.program_params.clone(),
+            self.commitment_json.clone(),
+            self.z_vec.clone(),
+            random_b,
+            self.class_data.p,
+        )
+        .unwrap();
+        prover.to_json(
+            proof_data,
+            CLASS_NUMBER,
+            self.commitment_json.info.commitment_id.clone(),
+            self.class_data.p,
+        )
+    }
+
+    /// Verifies `proof` against the fixture's commitment and keys
+    pub fn verify(&self, proof: &ProofGenerationJson) -> bool {
+        self.try_verify(proof, self.class_data.p).unwrap()
+    }
+
+    /// Verifies `proof` with the verifier working over the field of modulus `p`
+    pub fn try_verify(&self, proof: &ProofGenerationJson, p: u64) -> Result<bool> {
+        Verification::new(proof).verify(
+            (&self.ck, self.vk),
+            self.class_data,
+            self.commitment_json.get_polys_px(),
+            proof.get_x_vec(),
+            self.class_data.g,
+            p,
+        )
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fiat-Shamir transcript shared by the prover and the verifier.
+//!
+//! Every challenge is derived by hashing an evaluation of the random polynomial
+//! `s(x)` at a fixed point, so both sides only need `s(x)` to agree on them.
+//! The evaluation is prefixed with a label naming the challenge, so two challenges
+//! never collide even when `s(x)` takes the same value at their points.
+//! The points and labels are fixed here, in the order the challenges are consumed:
+//!
+//! | challenge                 | point of `s(x)` | label                       |
+//! |---------------------------|-----------------|-----------------------------|
+//! | `alpha`                   | 0               | `alpha`                     |
+//! | `eta_a`, `eta_b`, `eta_c` | 1, 2, 3         | `eta_a`, `eta_b`, `eta_c`   |
+//! | `beta_1`, `beta_2`        | 8, 9            | `beta_1`, `beta_2`          |
+//! | proof polynomial etas     | 10 ..= 21       | `eta_poly_0` ..= `eta_poly_11` |
+//! | `z`                       | 22              | `z`                         |
+//! | `beta_3`                  | 23              | `beta_3`                    |
+//!
+//! The hashed input is `label`, `:` and the evaluation as 8 big-endian bytes. Proof files
+//! written before the labels hash only the evaluation in decimal; see `ChallengeEncoding`.
+//! The bytes are hashed with SHA-256 unless another `ChallengeHasher` is chosen.
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::sync::Arc;
+
+use crate::polynomial::FPoly;
+
+use super::challenge_hasher::ChallengeHasher;
+use super::challenge_hasher::Sha256Hasher;
+
+/// Point of `s(x)` used for `alpha`
+const ALPHA_POINT: u64 = 0;
+/// Points of `s(x)` used for `eta_a`, `eta_b` and `eta_c`
+const ETA_POINTS: [(&str, u64); 3] = [("eta_a", 1), ("eta_b", 2), ("eta_c", 3)];
+/// Points of `s(x)` used for `beta_1` and `beta_2`
+const BETA_POINTS: [(&str, u64); 2] = [("beta_1", 8), ("beta_2", 9)];
+/// First point of `s(x)` used for the etas of the twelve proof polynomials
+const PROOF_ETA_START: u64 = 10;
+/// Number of proof polynomials combined in the KZG opening
+pub const PROOF_ETA_COUNT: usize = 12;
+/// Point of `s(x)` used for the opening point `z`
+const Z_POINT: u64 = 22;
+/// Point of `s(x)` used for `beta_3`
+const BETA_3_POINT: u64 = 23;
+
+/// How the evaluations of `s(x)` are encoded before they are hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeEncoding {
+    /// The value in decimal, without the label, as hashed by the original prover
+    ///
+    /// Used by proof files without a `Challenges` field. Challenges at points where
+    /// `s(x)` takes the same value collide.
+    Decimal,
+    /// `label:` followed by the value as 8 big-endian bytes
+    Bytes,
+}
+
+/// Fiat-Shamir transcript over the random polynomial `s(x)`
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    poly_sx: FPoly,
+    p: u64,
+    encoding: ChallengeEncoding,
+    hasher: Arc<dyn ChallengeHasher>,
+}
+
+impl Transcript {
+    /// Creates a transcript that absorbs `s(x)` over the field of modulus `p`
+    pub fn new(poly_sx: &FPoly, p: u64) -> Self {
+        Self::with_encoding(poly_sx, p, ChallengeEncoding::Bytes)
+    }
+
+    /// Same as `new`, hashing the evaluations with `encoding`
+    pub fn with_encoding(poly_sx: &FPoly, p: u64, encoding: ChallengeEncoding) -> Self {
+        Self {
+            poly_sx: poly_sx.clone(),
+            p,
+            encoding,
+            hasher: Arc::new(Sha256Hasher),
+        }
+    }
+
+    /// Derives the challenges with `hasher` instead of `Sha256Hasher`
+    pub fn with_hasher(mut self, hasher: Arc<dyn ChallengeHasher>) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Hashes the evaluation of `s(x)` at `point`, tagged with `label`, into a challenge
+    fn squeeze(&self, label: &str, point: u64) -> u64 {
+        let value = self.poly_sx.evaluate(point, self.p);
+        let input = match self.encoding {
+            ChallengeEncoding::Decimal => value.to_string().into_bytes(),
+            ChallengeEncoding::Bytes => [label.as_bytes(), b":", &value.to_be_bytes()].concat(),
+        };
+        self.hasher.hash_to_field(&input, self.p)
+    }
+
+    /// Challenge `alpha` used by the first sumcheck
+    pub fn alpha(&self) -> u64 {
+        self.squeeze("alpha", ALPHA_POINT)
+    }
+
+    /// Challenges `[eta_a, eta_b, eta_c]` that combine the matrices A, B and C
+    pub fn etas(&self) -> [u64; 3] {
+        ETA_POINTS.map(|(label, point)| self.squeeze(label, point))
+    }
+
+    /// Challenges `[beta_1, beta_2]`, both guaranteed to lie outside `set_h`
+    pub fn betas(&self, set_h: &[u64]) -> [u64; 2] {
+        BETA_POINTS.map(|(label, point)| self.squeeze_outside(label, point, set_h))
+    }
+
+    /// Same as `squeeze`, moving on to the next field element while the challenge is in `set`
+    fn squeeze_outside(&self, label: &str, point: u64, set: &[u64]) -> u64 {
+        let mut challenge = self.squeeze(label, point);
+        while set.contains(&challenge) {
+            challenge = (challenge + 1) % self.p;
+        }
+        challenge
+    }
+
+    /// Etas used to batch the twelve proof polynomials, in `Polys` order
+    pub fn proof_etas(&self) -> Vec<u64> {
+        (0..PROOF_ETA_COUNT as u64)
+            .map(|i| self.squeeze(&format!("eta_poly_{}", i), PROOF_ETA_START + i))
+            .collect()
+    }
+
+    /// Opening point `z` of the batched KZG proof
+    pub fn z(&self) -> u64 {
+        self.squeeze("z", Z_POINT)
+    }
+
+    /// Challenge `beta_3` at which the verifier checks the third sumcheck over K,
+    /// guaranteed to lie outside `set_k` so that `v_K(beta_3) != 0`
+    pub fn beta_3(&self, set_k: &[u64]) -> u64 {
+        self.squeeze_outside("beta_3", BETA_3_POINT, set_k)
+    }
+}
+
+#[cfg(test)]
+mod test_transcript {
+    use super::*;
+    use crate::polynomial::poly_fmath;
+    use crate::utils::sha2_hash_lower_32bit;
+    use crate::utils::sha2_hash_lower_32bit_parts;
+
+    const P: u64 = 1678321;
+
+    fn challenges(transcript: &Transcript, set_h: &[u64]) -> Vec<u64> {
+        let mut res = vec![transcript.alpha()];
+        res.extend(transcript.etas());
+        res.extend(transcript.betas(set_h));
+        res.extend(transcript.proof_etas());
+        res.push(transcript.z());
+        res.push(transcript.beta_3(set_h));
+        res
+    }
+
+    #[test]
+    fn prover_and_verifier_agree() {
+        let poly_sx = FPoly::new((0..75).collect());
+        let set_h = vec![1, 5, 25, 125];
+
+        // The verifier reads s(x) back from the proof file, possibly with leading zeros
+        let mut stored = vec![0, 0];
+        stored.extend(poly_sx.terms.iter());
+        let restored = FPoly::new(stored);
+
+        let prover = challenges(&Transcript::new(&poly_sx, P), &set_h);
+        let verifier = challenges(&Transcript::new(&restored, P), &set_h);
+
+        assert_eq!(prover.len(), 1 + 3 + 2 + PROOF_ETA_COUNT + 2);
+        assert_eq!(prover, verifier);
+    }
+
+    #[test]
+    fn challenges_follow_the_documented_points() {
+        let poly_sx = FPoly::new(vec![3, 0, 7, 11]);
+        let transcript = Transcript::new(&poly_sx, P);
+        let hash_at = |label: &str, i: u64| {
+            let mut input = format!("{}:", label).into_bytes();
+            input.extend(poly_sx.evaluate(i, P).to_be_bytes());
+            sha2_hash_lower_32bit_parts(&[&input])
+        };
+
+        assert_eq!(transcript.alpha(), hash_at("alpha", 0));
+        assert_eq!(transcript.etas(), [hash_at("eta_a", 1), hash_at("eta_b", 2), hash_at("eta_c", 3)]);
+        assert_eq!(
+            transcript.proof_etas(),
+            (0..12).map(|i| hash_at(&format!("eta_poly_{}", i), 10 + i)).collect::<Vec<u64>>()
+        );
+        assert_eq!(transcript.z(), hash_at("z", 22));
+        assert_eq!(transcript.beta_3(&[]), hash_at("beta_3", 23));
+
+        // beta_3 skips over set_k
+        let beta_3 = hash_at("beta_3", 23);
+        let next = (beta_3 + 1) % P;
+        assert_eq!(transcript.beta_3(&[beta_3, next]), (next + 1) % P);
+
+        // A different s(x) yields a different transcript
+        let other = poly_fmath::add(&poly_sx, &FPoly::one(), P);
+        assert_ne!(Transcript::new(&other, P).alpha(), transcript.alpha());
+    }
+
+    #[test]
+    fn decimal_encoding_matches_older_proofs() {
+        let poly_sx = FPoly::new(vec![3, 0, 7, 11]);
+        let transcript = Transcript::with_encoding(&poly_sx, P, ChallengeEncoding::Decimal);
+        let hash_at = |i: u64| sha2_hash_lower_32bit(&poly_sx.evaluate(i, P).to_string());
+
+        assert_eq!(transcript.alpha(), hash_at(0));
+        assert_eq!(transcript.etas(), [hash_at(1), hash_at(2), hash_at(3)]);
+        assert_eq!(transcript.betas(&[]), [hash_at(8), hash_at(9)]);
+        assert_eq!(transcript.z(), hash_at(22));
+        assert_ne!(transcript.alpha(), Transcript::new(&poly_sx, P).alpha());
+    }
+
+    #[test]
+    fn equal_evaluations_give_distinct_challenges() {
+        // s(x) = x^2 - x + 7, so s(0) = s(1) = 7
+        let poly_sx = FPoly::new(vec![1, P - 1, 7]);
+        assert_eq!(poly_sx.evaluate(0, P), poly_sx.evaluate(1, P));
+
+        let transcript = Transcript::new(&poly_sx, P);
+        let [eta_a, _, _] = transcript.etas();
+        assert_ne!(transcript.alpha(), eta_a);
+    }
+}
@@ -66,6 +66,43 @@ pub mod fmath {
         result
     }
 
+    /// Add `b` to `a` element-wise modulo p, in place
+    ///
+    /// When every element is already reduced (below `p`), the sum is reduced with a single
+    /// conditional subtraction instead of a division, which the compiler can vectorize.
+    /// Otherwise it falls back to `add` for each element, so the result is always the same.
+    pub fn add_slice(a: &mut [u64], b: &[u64], p: u64) {
+        assert_eq!(a.len(), b.len(), "add_slice needs slices of the same length");
+        if !is_reduced(a, p) || !is_reduced(b, p) {
+            a.iter_mut().zip(b).for_each(|(x, &y)| *x = add(*x, y, p));
+            return;
+        }
+        for (x, &y) in a.iter_mut().zip(b) {
+            let sum = *x + y;
+            *x = if sum >= p { sum - p } else { sum };
+        }
+    }
+
+    /// Subtract `b` from `a` element-wise modulo p, in place
+    ///
+    /// Same fast path and fallback as `add_slice`, with a conditional addition of `p`.
+    pub fn sub_slice(a: &mut [u64], b: &[u64], p: u64) {
+        assert_eq!(a.len(), b.len(), "sub_slice needs slices of the same length");
+        if !is_reduced(a, p) || !is_reduced(b, p) {
+            a.iter_mut().zip(b).for_each(|(x, &y)| *x = sub(*x, y, p));
+            return;
+        }
+        for (x, &y) in a.iter_mut().zip(b) {
+            let diff = x.wrapping_sub(y);
+            *x = if *x < y { diff.wrapping_add(p) } else { diff };
+        }
+    }
+
+    /// True if every element of `values` is below `p`
+    fn is_reduced(values: &[u64], p: u64) -> bool {
+        values.iter().fold(true, |acc, &v| acc & (v < p))
+    }
+
     /// Calculate the multiplicative inverse
     pub fn inverse_mul(a: u64, p: u64) -> u64 {
         pow(a, p - 2, p)
@@ -181,4 +218,37 @@ mod tests {
         assert_eq!(fmath::inverse_add(3, 10), 7); // 10 - 3 = 7
         assert_eq!(fmath::inverse_add(5, 10), 5); // 10 - 5 = 5
     }
+
+    #[test]
+    fn test_add_sub_slice() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        const P: u64 = 1678321;
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut a: Vec<u64> = (0..257).map(|_| rng.gen_range(0..P)).collect();
+        let mut b: Vec<u64> = (0..257).map(|_| rng.gen_range(0..P)).collect();
+        // Force both reduction branches
+        a[..4].copy_from_slice(&[P - 1, P - 1, 0, 1]);
+        b[..4].copy_from_slice(&[P - 1, 1, P - 1, P - 1]);
+
+        let check = |a: &[u64], b: &[u64]| {
+            let mut sum = a.to_vec();
+            fmath::add_slice(&mut sum, b, P);
+            let mut diff = a.to_vec();
+            fmath::sub_slice(&mut diff, b, P);
+
+            for i in 0..a.len() {
+                assert_eq!(sum[i], fmath::add(a[i], b[i], P), "add at {}", i);
+                assert_eq!(diff[i], fmath::sub(a[i], b[i], P), "sub at {}", i);
+            }
+        };
+        check(&a, &b);
+
+        // Unreduced elements take the element-wise path
+        a[7] = 3 * P + 2;
+        check(&a, &b);
+        check(&b, &a);
+        check(&[], &[]);
+    }
 }
@@ -18,6 +18,8 @@ use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -26,6 +28,7 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::path::PathBuf;
 
+use crate::field::fmath;
 use crate::math::generate_set;
 use crate::matrices::FMatrix;
 use crate::matrices::Matrices;
@@ -41,10 +44,18 @@ use crate::utils::read_json_file;
 /// # Returns
 /// Returns a `Vec<u64>` containing the coefficients of the polynomial, where the index represents the exponent
 /// of each term. If a term does not exist for a particular exponent, the coefficient at that index will be `0`.
+///
+/// `read_term` is its inverse: `read_term(&write_term(&poly))` equals `poly` once trimmed.
 pub fn write_term(poly: &FPoly) -> Vec<u64> {
-    let mut poly = poly.clone();
-    poly.trim();
-    poly.terms.into_iter().rev().collect()
+    poly.coeffs_ascending()
+}
+
+/// Converts a coefficient vector written by `write_term` back into a polynomial.
+///
+/// The vector is indexed by exponent, so zero coefficients at its end are high-degree
+/// zeros and are trimmed; zeros anywhere else are kept in place.
+pub fn read_term(coeffs: &[u64]) -> FPoly {
+    FPoly::from_coeffs_ascending(coeffs)
 }
 
 /// Adds a new JSON value to an existing JSON file, replacing any existing data.
@@ -129,7 +140,15 @@ impl ClassDataJson {
         if let Some(class_data) = data.get(&class_to_access) {
             Ok(class_data.clone())
         } else {
-            Err(anyhow!("Class {} doesn't exist", class_to_access))
+            // List the classes that do exist so a typo is easy to spot
+            let mut available: Vec<u8> = data.keys().copied().collect();
+            available.sort_unstable();
+            Err(anyhow!(
+                "Class {} doesn't exist in {} (available classes: {:?})",
+                class_to_access,
+                path,
+                available
+            ))
         }
     }
 
@@ -144,6 +163,86 @@ impl ClassDataJson {
         (self.n_i + 1).try_into().unwrap()
     }
 
+    /// Number of elements of the set H, one per row of the matrices
+    pub fn set_h_len(&self) -> u64 {
+        self.n
+    }
+
+    /// Number of elements of the set K, the most nonzero entries a matrix may hold
+    pub fn set_k_len(&self) -> u64 {
+        self.m
+    }
+
+    /// Returns the number of commitment keys `D_AHP` the class needs.
+    ///
+    /// `D_AHP = max(3 * n_g + 2 * n_i + 2, 12 * n_g)`:
+    /// - `3 * n_g + 2 * n_i + 2 = 2 * |H| + n_g` (|H| = n_g + n_i + 1) bounds the polynomials
+    ///   over `H`. The largest is the random masking polynomial `s(x)`, whose `2 * |H| + 2`
+    ///   coefficients fit once `n_g >= 2`, exactly so for `n_g = 2`. The slack of `n_g` also
+    ///   covers the blinded `z^_M(x)` and `h_0(x)`, which grow with `random_b <= n_g`.
+    /// - `12 * n_g = 6 * |K|` (|K| = 2 * n_g) bounds the third sumcheck over `K`, whose
+    ///   `b(x)` is the product of three polynomials of degree `2 * (|K| - 1)`.
+    pub fn d_ahp(&self) -> u64 {
+        std::cmp::max(3 * self.n_g + 2 * self.n_i + 2, 12 * self.n_g)
+    }
+
+    /// Returns every inconsistency in this class, or an empty list if there is none
+    ///
+    /// Checks that `p` is prime, that `g` generates the whole multiplicative group mod `p`,
+    /// that `n = n_g + n_i + 1` and `m = 2 * n_g`, and that both `n` and `m` divide `p - 1`
+    /// so the subgroups H and K exist.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.n != self.n_g + self.n_i + 1 {
+            problems.push(format!("n = {} but n_g + n_i + 1 = {}", self.n, self.n_g + self.n_i + 1));
+        }
+        if self.m != 2 * self.n_g {
+            problems.push(format!("m = {} but 2 * n_g = {}", self.m, 2 * self.n_g));
+        }
+
+        let factors = prime_factors(self.p.saturating_sub(1));
+        if self.p < 3 || factors.is_empty() || !is_prime(self.p) {
+            problems.push(format!("p = {} is not an odd prime", self.p));
+            return problems;
+        }
+
+        for (name, len) in [("n", self.n), ("m", self.m)] {
+            if len == 0 || !(self.p - 1).is_multiple_of(len) {
+                problems.push(format!("{} = {} doesn't divide p - 1 = {}", name, len, self.p - 1));
+            }
+        }
+
+        let g = self.g % self.p;
+        if g == 0 || factors.iter().any(|&q| fmath::pow(g, (self.p - 1) / q, self.p) == 1) {
+            problems.push(format!("g = {} is not a generator mod p = {}", self.g, self.p));
+        }
+
+        problems
+    }
+
+    /// Checks every class of a class table, reporting all problems in one error
+    pub fn validate_all(classes: &HashMap<u8, ClassDataJson>) -> Result<()> {
+        let mut keys: Vec<&u8> = classes.keys().collect();
+        keys.sort_unstable();
+
+        let problems: Vec<String> = keys
+            .into_iter()
+            .flat_map(|key| {
+                classes[key]
+                    .problems()
+                    .into_iter()
+                    .map(move |problem| format!("class {}: {}", key, problem))
+            })
+            .collect();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("invalid class table:\n{}", problems.join("\n")))
+        }
+    }
+
     /// Retrieves all class data from a specified JSON file and returns it as a HashMap
     pub fn get_all_class_data(path: &str) -> Result<HashMap<u8, ClassDataJson>> {
         let reader = open_file(&PathBuf::from(path))?;
@@ -153,6 +252,71 @@ impl ClassDataJson {
     }
 }
 
+/// Returns `true` if `n` is prime, by trial division
+fn is_prime(n: u64) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d))
+}
+
+/// Returns the distinct prime factors of `n`, by trial division
+fn prime_factors(mut n: u64) -> Vec<u64> {
+    let mut factors = vec![];
+    let mut d = 2;
+    while d * d <= n {
+        if n.is_multiple_of(d) {
+            factors.push(d);
+            while n.is_multiple_of(d) {
+                n /= d;
+            }
+        }
+        d += 1;
+    }
+    if n > 1 {
+        factors.push(n);
+    }
+    factors
+}
+
+/// A matrix entry that differs between two `ProgramParamsJson`, reported by `ProgramParamsJson::diff`
+///
+/// Entries not stored in the parameters are zero. Rows of `A` are counted from its first
+/// gate row, as `A` is stored without the rows of x; rows of `B` are absolute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellChange {
+    /// `"A"` or `"B"`
+    pub matrix: &'static str,
+    pub row: usize,
+    pub col: usize,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// A point of a row, col or val vector that differs between two `ProgramParamsJson`
+///
+/// `old` is `None` for an added point and `new` is `None` for a removed one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointChange {
+    /// Name of the vector as stored in the file, e.g. `"rA"`
+    pub vector: &'static str,
+    /// Position of the point, i.e. the index of its element in K
+    pub index: usize,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+/// Differences between two `ProgramParamsJson`, in matrix and index order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParamsDiff {
+    pub cells: Vec<CellChange>,
+    pub points: Vec<PointChange>,
+}
+
+impl ParamsDiff {
+    /// True if both parameter sets hold the same matrices and points
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.points.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProgramParamsJson {
     /// [..t_zeros skipped.., col1, col2, col3, ...]
@@ -199,7 +363,7 @@ impl ProgramParamsJson {
         p: u64
     ) -> Self {
         // store points accordint to set_k
-        let set_k = generate_set(class_data.m, class_data, p);
+        let set_k = generate_set(class_data.set_k_len(), class_data, p);
 
         // Values of ranges: [[point_val_a, point_col_a, point_row_a, ...]]
         let points_px = Self::to_points_u64(points_px, &set_k, p);
@@ -323,12 +487,15 @@ impl ProgramParamsJson {
     /// - `class_data`: A reference to a `ClassData` object used to determine the size of the matrices.
     ///
     /// # Returns
-    /// A tuple containing three dense matrices: (A, B, C).
+    /// A tuple containing three dense matrices: (A, B, C), or an error if the stored
+    /// parameters were generated for a class with a different matrix size.
     pub fn get_matrices(
         &self,
         class_data: &ClassDataJson,
         p: u64
-    ) -> (FMatrix, FMatrix, FMatrix) {
+    ) -> Result<(FMatrix, FMatrix, FMatrix)> {
+        self.check_dimensions(class_data)?;
+
         let a = self.get_matrix_a(
             class_data.get_matrix_size(),
             class_data.get_matrix_t_zeros(),
@@ -339,7 +506,91 @@ impl ProgramParamsJson {
             class_data.get_matrix_t_zeros(),
         );
 
-        (a, b, c)
+        Ok((a, b, c))
+    }
+
+    /// Checks that the stored matrix data fits the matrix size of `class_data`
+    fn check_dimensions(&self, class_data: &ClassDataJson) -> Result<()> {
+        let size = class_data.get_matrix_size();
+        let gate_rows = size - class_data.get_matrix_t_zeros();
+
+        // A stores one column index per gate row
+        if self.a.len() != gate_rows {
+            return Err(anyhow!(
+                "Program params do not match the class: A has {} gate rows, expected {} (n_g = {})",
+                self.a.len(),
+                gate_rows,
+                class_data.n_g
+            ));
+        }
+
+        let max_a = self.a.iter().map(|&col| col as usize).max();
+        let max_b = self.b.iter().map(|&(row, col, _)| row.max(col)).max();
+        if let Some(index) = max_a.into_iter().chain(max_b).max().filter(|&i| i >= size) {
+            return Err(anyhow!(
+                "Program params do not match the class: index {} is out of range for {}x{} matrices",
+                index,
+                size,
+                size
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Compares the matrices and points with `other`, e.g. before and after a firmware update
+    ///
+    /// Changed matrix entries come with their coordinates and both values, and points
+    /// are compared position by position.
+    pub fn diff(&self, other: &Self) -> ParamsDiff {
+        let cells_a = |params: &Self| -> BTreeMap<(usize, usize), u64> {
+            params.a.iter().enumerate().map(|(row, &col)| ((row, col as usize), 1)).collect()
+        };
+        let cells_b = |params: &Self| -> BTreeMap<(usize, usize), u64> {
+            params.b.iter().map(|&(row, col, val)| ((row, col), val)).collect()
+        };
+
+        let mut cells = vec![];
+        for (matrix, old, new) in [
+            ("A", cells_a(self), cells_a(other)),
+            ("B", cells_b(self), cells_b(other)),
+        ] {
+            let coords: BTreeSet<_> = old.keys().chain(new.keys()).copied().collect();
+            for (row, col) in coords {
+                let old = old.get(&(row, col)).copied().unwrap_or(0);
+                let new = new.get(&(row, col)).copied().unwrap_or(0);
+                if old != new {
+                    cells.push(CellChange { matrix, row, col, old, new });
+                }
+            }
+        }
+
+        let mut points = vec![];
+        for ((vector, old), (_, new)) in self.named_points().into_iter().zip(other.named_points()) {
+            for index in 0..old.len().max(new.len()) {
+                let (old, new) = (old.get(index).copied(), new.get(index).copied());
+                if old != new {
+                    points.push(PointChange { vector, index, old, new });
+                }
+            }
+        }
+
+        ParamsDiff { cells, points }
+    }
+
+    /// The row, col and val vectors with the names they are stored under
+    fn named_points(&self) -> [(&'static str, &Vec<u64>); 9] {
+        [
+            ("rA", &self.r_a),
+            ("cA", &self.c_a),
+            ("vA", &self.v_a),
+            ("rB", &self.r_b),
+            ("cB", &self.c_b),
+            ("vB", &self.v_b),
+            ("rC", &self.r_c),
+            ("cC", &self.c_c),
+            ("vC", &self.v_c),
+        ]
     }
 
     /// Store in Json file
@@ -356,14 +607,17 @@ impl ProgramParamsJson {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub enum LineValue {
+    /// A single inclusive range, written `[start, end]`
     Range((usize, usize)),
+    /// Several inclusive ranges, written `[[start, end], ...]`
+    Ranges(Vec<(usize, usize)>),
 }
 
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct DeviceInfo {
     pub class: u8,
     pub commitment_id: String,
@@ -405,9 +659,34 @@ pub struct DeviceConfigJson {
 
 impl DeviceConfigJson {
     /// Converts a LineValue range into a vector of usize values
-    pub fn convert_lines(lines: LineValue) -> Vec<usize> {
-        let LineValue::Range(r) = lines;
-        (r.0..=r.1).collect()
+    ///
+    /// Ranges are inclusive and expanded in the order they are given. An inverted range
+    /// (`end < start`) is an error, and so are overlapping ranges: a line listed twice
+    /// would become two gates, so rather than guessing which block was meant the
+    /// configuration is rejected.
+    pub fn convert_lines(lines: &LineValue) -> Result<Vec<usize>> {
+        let ranges = match lines {
+            LineValue::Range(r) => vec![*r],
+            LineValue::Ranges(rs) => rs.clone(),
+        };
+
+        for (i, r) in ranges.iter().enumerate() {
+            if r.1 < r.0 {
+                return Err(anyhow!(
+                    "code_block range {:?} is inverted: it ends before it starts",
+                    r
+                ));
+            }
+            if let Some(other) = ranges[..i].iter().find(|o| r.0 <= o.1 && o.0 <= r.1) {
+                return Err(anyhow!(
+                    "code_block ranges {:?} and {:?} overlap",
+                    other,
+                    r
+                ));
+            }
+        }
+
+        Ok(ranges.iter().flat_map(|r| r.0..=r.1).collect())
     }
 }
 
@@ -416,6 +695,17 @@ impl DeviceConfigJson {
 mod test_json {
     use super::*;
 
+    #[test]
+    fn test_d_ahp() {
+        let classes = ClassDataJson::get_all_class_data("class.json").unwrap();
+
+        // n_i = 32 for all classes: 3 * n_g + 66 wins for small n_g, 12 * n_g afterwards
+        assert_eq!(classes[&1].d_ahp(), 72);
+        assert_eq!(classes[&2].d_ahp(), 78);
+        assert_eq!(classes[&3].d_ahp(), 96);
+        assert_eq!(classes[&4].d_ahp(), 192);
+    }
+
     #[test]
     fn test_write() {
         let poly1 = FPoly::new(vec![1, 2, 3, 4, 5]);
@@ -430,4 +720,138 @@ mod test_json {
         assert_eq!(vec![2, 3, 0, 0].into_iter().rev().collect::<Vec<u64>>(), write_term(&poly4));
         assert_eq!(vec![].into_iter().rev().collect::<Vec<u64>>(), write_term(&poly5));
     }
+
+    #[test]
+    fn test_validate_all() {
+        let classes = ClassDataJson::get_all_class_data("class.json").unwrap();
+        ClassDataJson::validate_all(&classes).unwrap();
+
+        // 4 is a square, so it can't generate the whole group
+        let good = classes[&2];
+        let bad = ClassDataJson { n_g: 4, n_i: 32, n: 36, m: 8, p: 181, g: 4 };
+        let table: HashMap<u8, ClassDataJson> = [(2, good), (7, bad)].into_iter().collect();
+
+        let err = ClassDataJson::validate_all(&table).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "invalid class table:\n\
+             class 7: n = 36 but n_g + n_i + 1 = 37\n\
+             class 7: m = 8 doesn't divide p - 1 = 180\n\
+             class 7: g = 4 is not a generator mod p = 181"
+        );
+
+        let not_prime = ClassDataJson { p: 187, ..good };
+        assert!(not_prime.problems().contains(&"p = 187 is not an odd prime".to_string()));
+    }
+
+    #[test]
+    fn test_write_read_round_trip() {
+        use rand::rngs::StdRng;
+        use rand::Rng;
+        use rand::SeedableRng;
+
+        const P: u64 = 1678321;
+        let mut rng = StdRng::seed_from_u64(827);
+
+        for _ in 0..200 {
+            let len = rng.gen_range(0..40);
+            // Roughly a third of the coefficients are zero, including leading and trailing ones
+            let terms: Vec<u64> = (0..len)
+                .map(|_| if rng.gen_bool(0.35) { 0 } else { rng.gen_range(1..P) })
+                .collect();
+            let poly = FPoly::new(terms);
+
+            let mut trimmed = poly.clone();
+            trimmed.trim();
+            let restored = read_term(&write_term(&poly));
+
+            assert_eq!(restored, trimmed, "{:?}", poly.terms);
+            assert_eq!(restored.degree(), poly.degree());
+            for x in [0, 1, 2, P - 1] {
+                assert_eq!(restored.evaluate(x, P), poly.evaluate(x, P));
+            }
+        }
+
+        // Zeros past the highest degree in the stored form are dropped
+        assert_eq!(read_term(&[3, 0, 5, 0, 0]), FPoly::new(vec![5, 0, 3]));
+    }
+
+    #[test]
+    fn test_get_matrices_class_mismatch() {
+        let class_1 = ClassDataJson::get_class_data("class.json", 1).unwrap();
+        let class_2 = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let size = class_2.get_matrix_size();
+
+        // Parameters generated for class 2 (4 gates)
+        let params = ProgramParamsJson {
+            a: vec![0, 2, 0, 33],
+            b: vec![(33, 0, 5), (34, 0, 2), (35, 34, 1), (36, 0, 7)],
+            r_a: vec![],
+            c_a: vec![],
+            v_a: vec![],
+            r_b: vec![],
+            c_b: vec![],
+            v_b: vec![],
+            r_c: vec![],
+            c_c: vec![],
+            v_c: vec![],
+        };
+
+        let (a, b, c) = params.get_matrices(&class_2, class_2.p).unwrap();
+        assert_eq!((a.size(), b.size(), c.size()), (size, size, size));
+
+        let err = params.get_matrices(&class_1, class_1.p).unwrap_err();
+        assert!(err.to_string().contains("A has 4 gate rows, expected 2"), "{}", err);
+
+        let mut params = params;
+        params.a = vec![0, 2];
+        let err = params.get_matrices(&class_1, class_1.p).unwrap_err();
+        assert!(err.to_string().contains("out of range for 35x35"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_class_lists_available() {
+        let err = ClassDataJson::get_class_data("class.json", 200).unwrap_err();
+        let msg = err.to_string();
+
+        assert!(msg.contains("Class 200 doesn't exist"), "{}", msg);
+        assert!(msg.contains("available classes: [1, 2, 3, 4, "), "{}", msg);
+    }
+
+    #[test]
+    fn test_convert_lines_ranges() {
+        let single: LineValue = serde_json::from_str("[1, 4]").unwrap();
+        assert_eq!(DeviceConfigJson::convert_lines(&single).unwrap(), vec![1, 2, 3, 4]);
+
+        let err = DeviceConfigJson::convert_lines(&LineValue::Range((5, 2))).unwrap_err();
+        assert!(err.to_string().contains("(5, 2) is inverted"), "{}", err);
+
+        let overlapping: LineValue = serde_json::from_str("[[1, 4], [4, 6]]").unwrap();
+        let err = DeviceConfigJson::convert_lines(&overlapping).unwrap_err();
+        assert!(err.to_string().contains("(1, 4) and (4, 6) overlap"), "{}", err);
+
+        // Disjoint ranges keep the order they were given in
+        let disjoint: LineValue = serde_json::from_str("[[7, 8], [2, 3]]").unwrap();
+        assert_eq!(DeviceConfigJson::convert_lines(&disjoint).unwrap(), vec![7, 8, 2, 3]);
+    }
+
+    #[test]
+    fn test_program_params_diff() {
+        let params = crate::ahp::test_utils::fixture().program_params;
+        assert!(params.diff(&params.clone()).is_empty());
+
+        let mut updated = params.clone();
+        let (row, col, old) = updated.b[1];
+        updated.b[1].2 = old + 1;
+
+        let diff = params.diff(&updated);
+        assert_eq!(diff.cells, [CellChange { matrix: "B", row, col, old, new: old + 1 }]);
+        assert!(diff.points.is_empty());
+
+        // A point appended to one vector is reported as added
+        updated.v_c.push(7);
+        let diff = params.diff(&updated);
+        let index = params.v_c.len();
+        assert_eq!(diff.points, [PointChange { vector: "vC", index, old: None, new: Some(7) }]);
+    }
 }
\ No newline at end of file
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use rayon::prelude::*;
+
 use crate::{field::fmath, polynomial::FPoly};
 
 /// Generates a vector of u64 values based on the setup parameters and a random number
@@ -29,6 +31,34 @@ pub fn setup(max: u64, tau: u64, g: u64, p: u64) -> Vec<u64> {
         .collect()
 }
 
+/// Number of keys each task of `setup_parallel` computes serially
+const SEGMENT_LEN: u64 = 1 << 14;
+
+/// Same keys as `setup`, computed in parallel segments
+///
+/// Each segment starts from `g * tau^start`, computed once with `fmath::pow`,
+/// and then runs the serial recurrence; the segments are concatenated in order.
+pub fn setup_parallel(max: u64, tau: u64, g: u64, p: u64) -> Vec<u64> {
+    let tau = tau % (p - 1);
+    let g = g % p;
+    let segments = max.div_ceil(SEGMENT_LEN);
+
+    (0..segments)
+        .into_par_iter()
+        .flat_map_iter(|segment| {
+            let start = segment * SEGMENT_LEN;
+            let end = max.min(start + SEGMENT_LEN);
+            let mut tmp = fmath::mul(g, fmath::pow(tau, start, p), p);
+
+            (start..end).map(move |_| {
+                let current = tmp;
+                tmp = fmath::mul(current, tau, p);
+                current
+            })
+        })
+        .collect()
+}
+
 /// Computes the commitment of a polynomial using the provided commitment keys
 pub fn commit(poly_in: &FPoly, ck: &[u64], p: u64) -> u64 {
     let mut res_poly = 0;
@@ -47,6 +77,17 @@ pub fn commit(poly_in: &FPoly, ck: &[u64], p: u64) -> u64 {
     res_poly
 }
 
+/// Same as `commit`, for a polynomial given by its nonzero `(degree, coeff)` terms
+///
+/// Only the listed terms are visited, which pays off for sparse polynomials such as
+/// quotients by a vanishing polynomial. Terms may come in any order.
+pub fn commit_sparse(terms: &[(usize, u64)], ck: &[u64], p: u64) -> u64 {
+    terms.iter().fold(0, |acc, &(degree, coeff)| {
+        assert!(degree < ck.len(), "Error: The number of commitment keys ({}), must be greater than the polynomial degree ({}).", ck.len(), degree);
+        fmath::add(acc, fmath::mul(coeff, ck[degree], p), p)
+    })
+}
+
 
 #[cfg(test)]
 mod test_kzg {
@@ -66,6 +107,19 @@ mod test_kzg {
         assert_eq!(result, vec![2, 61, 141, 47, 76]);
     }
 
+    #[test]
+    fn test_setup_parallel() {
+        let p = 1678321;
+        let (tau, g) = (1234567, 7);
+
+        // Several full segments plus a partial one
+        let max = 5 * SEGMENT_LEN + 123;
+        assert_eq!(setup_parallel(max, tau, g, p), setup(max, tau, g, p));
+
+        assert_eq!(setup_parallel(5, 121, 2, P), vec![2, 61, 141, 47, 76]);
+        assert!(setup_parallel(0, 121, 2, P).is_empty());
+    }
+
     #[test]
     fn test_commit() {
         let poly1 = FPoly::new(vec![
@@ -90,4 +144,23 @@ mod test_kzg {
         
         assert_eq!(result, 152);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_commit_sparse() {
+        let ck = setup(40, 121, 2, P);
+
+        let mut poly = FPoly::zero();
+        for &(degree, coeff) in &[(0, 5), (3, 180), (17, 1), (18, 42), (39, 7)] {
+            poly.add_term(coeff, degree);
+        }
+        let terms: Vec<(usize, u64)> = (0..=poly.degree())
+            .map(|degree| (degree, poly.get_term(degree)))
+            .filter(|&(_, coeff)| coeff != 0)
+            .rev()
+            .collect();
+
+        assert_eq!(terms.len(), 5);
+        assert_eq!(commit_sparse(&terms, &ck, P), commit(&poly, &ck, P));
+        assert_eq!(commit_sparse(&[], &ck, P), commit(&FPoly::zero(), &ck, P));
+    }
+}
@@ -21,4 +21,8 @@ pub mod matrices;
 pub mod ahp;
 pub mod kzg;
 pub mod polynomial;
-pub mod field;
\ No newline at end of file
+pub mod field;
+
+// Used by `println_dbg!` and `print_dbg!` from other crates
+#[doc(hidden)]
+pub use log;
\ No newline at end of file
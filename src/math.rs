@@ -14,7 +14,11 @@
 
 //! Module for mathematical functions and utilities for finite field operations using the `u64` type and polynomials.
 
+use anyhow::anyhow;
+use anyhow::Result;
+
 use crate::field::fmath;
+use crate::matrices::matrix_fmath;
 use crate::json_file::ClassDataJson;
 use crate::kzg;
 use crate::matrices::FMatrix;
@@ -22,12 +26,16 @@ use crate::polynomial::poly_fmath;
 use crate::polynomial::FPoly;
 use crate::println_dbg;
 use crate::utils::add_random_points;
+use rayon::prelude::*;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
 /// Type alias for a 2D point in the `u64` field.
 pub type Point = (u64, u64);
 
+/// Row, column and value points of a matrix, keyed by the values of `set_k`
+pub type MatrixPoints = (HashMap<u64, u64>, HashMap<u64, u64>, HashMap<u64, u64>);
+
 
 /// Interpolates a polynomial that passes through a given set of points using the Newton interpolation algorithm.
 ///
@@ -92,6 +100,216 @@ pub fn generate_set(len: u64, class_data: ClassDataJson, p: u64) -> Vec<u64> {
     (0..len).map(|i| fmath::pow(g, i, p)).collect()
 }
 
+/// Multiplicative subgroup of `F_p` used as an evaluation domain, such as H or K
+///
+/// `elements` are `gen^0, ..., gen^(size - 1)`, the same as `generate_set`. Build the
+/// domain once per proof and reuse it: evaluating or interpolating over it goes through
+/// an FFT instead of the generic Newton interpolation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalDomain {
+    pub gen: u64,
+    pub size: usize,
+    pub elements: Vec<u64>,
+    pub inv_size: u64,
+    p: u64,
+}
+
+impl EvalDomain {
+    /// Creates the subgroup of `size` elements, see `generate_set`
+    pub fn new(size: u64, class_data: ClassDataJson, p: u64) -> Self {
+        let elements = generate_set(size, class_data, p);
+        Self {
+            gen: elements.get(1).copied().unwrap_or(1),
+            size: size as usize,
+            elements,
+            inv_size: fmath::inverse_mul(size % p, p),
+            p,
+        }
+    }
+
+    /// Evaluates the polynomial with ascending `coeffs` at every element of the domain
+    ///
+    /// Coefficients past `size` wrap around, since `gen^size = 1`.
+    pub fn fft(&self, coeffs: &[u64]) -> Vec<u64> {
+        let mut folded = vec![0; self.size];
+        for (i, &coeff) in coeffs.iter().enumerate() {
+            folded[i % self.size] = fmath::add(folded[i % self.size], coeff % self.p, self.p);
+        }
+        Self::dft(&folded, self.gen, self.p)
+    }
+
+    /// Ascending coefficients of the polynomial of degree < `size` taking the values `evals`
+    /// on the domain, the inverse of `fft`
+    pub fn ifft(&self, evals: &[u64]) -> Vec<u64> {
+        assert_eq!(evals.len(), self.size, "expected one value per element of the domain");
+        Self::dft(evals, fmath::inverse_mul(self.gen, self.p), self.p)
+            .into_iter()
+            .map(|coeff| fmath::mul(coeff, self.inv_size, self.p))
+            .collect()
+    }
+
+    /// Interpolates the polynomial taking the values `evals` on the domain, in the order
+    /// of `elements`
+    pub fn interpolate(&self, evals: &[u64]) -> FPoly {
+        FPoly::from_coeffs_ascending(&self.ifft(evals))
+    }
+
+    /// Interpolates the polynomial of degree < `size + blinding.len()` taking the values
+    /// `evals` on the domain and passing through the `blinding` points outside it
+    ///
+    /// The part over the domain goes through `interpolate`; the blinding points only
+    /// add `v(x) * c(x)`, where `v` is the vanishing polynomial and `c` interpolates
+    /// `(y - f(x)) / v(x)` at them, so the Newton interpolation runs on those points alone.
+    pub fn interpolate_blinded(&self, evals: &[u64], blinding: &[Point]) -> FPoly {
+        let poly = self.interpolate(evals);
+        if blinding.is_empty() {
+            return poly;
+        }
+
+        let van_poly = self.vanishing_poly();
+        let corrections: Vec<Point> = blinding
+            .iter()
+            .map(|&(x, y)| {
+                let van = van_poly.evaluate(x, self.p);
+                assert!(van != 0, "blinding point {} lies in the domain", x);
+                (x, fmath::div(fmath::sub(y, poly.evaluate(x, self.p), self.p), van, self.p))
+            })
+            .collect();
+        let correction = poly_fmath::mul(&van_poly, &interpolate(&corrections, self.p), self.p);
+        poly_fmath::add(&poly, &correction, self.p)
+    }
+
+    /// The vanishing polynomial `x^size - 1` of the domain
+    pub fn vanishing_poly(&self) -> FPoly {
+        poly_fmath::sub(&FPoly::monomial(1, self.size), &FPoly::one(), self.p)
+    }
+
+    /// Mixed-radix Cooley-Tukey transform: `result[k] = ∑_j values[j] * root^(j * k)`,
+    /// where `root` has order `values.len()`
+    ///
+    /// Splits by the smallest prime factor `q` of the length, so a prime length falls
+    /// back to the quadratic sum.
+    fn dft(values: &[u64], root: u64, p: u64) -> Vec<u64> {
+        let n = values.len();
+        if n <= 1 {
+            return values.to_vec();
+        }
+        let q = (2..=n).find(|&q| n.is_multiple_of(q)).unwrap();
+        let m = n / q;
+
+        // Transforms of the q subsequences values[r], values[r + q], ...
+        let root_q = fmath::pow(root, q as u64, p);
+        let parts: Vec<Vec<u64>> = (0..q)
+            .map(|r| {
+                let sub: Vec<u64> = values[r..].iter().step_by(q).copied().collect();
+                Self::dft(&sub, root_q, p)
+            })
+            .collect();
+
+        let mut root_k = 1;
+        (0..n)
+            .map(|k| {
+                // ∑_r root^(r * k) * parts[r][k mod m]
+                let mut twiddle = 1;
+                let sum = parts.iter().fold(0, |acc, part| {
+                    let term = fmath::mul(twiddle, part[k % m], p);
+                    twiddle = fmath::mul(twiddle, root_k, p);
+                    fmath::add(acc, term, p)
+                });
+                root_k = fmath::mul(root_k, root, p);
+                sum
+            })
+            .collect()
+    }
+}
+
+/// Reed-Solomon encodes `message` over `domain`
+///
+/// The message holds the coefficients of a polynomial of degree < `message.len()`, from
+/// the constant term up; the codeword is its evaluation at every element of the domain,
+/// so any `message.len()` of its symbols determine the message.
+///
+/// # Errors
+/// Fails if the message is longer than the domain.
+pub fn rs_encode(message: &[u64], domain: &EvalDomain) -> Result<Vec<u64>> {
+    if message.len() > domain.size {
+        return Err(anyhow!(
+            "message of {} symbols doesn't fit in a domain of {} elements",
+            message.len(),
+            domain.size
+        ));
+    }
+    Ok(domain.fft(message))
+}
+
+/// Decodes a codeword of `rs_encode` for a message of `k` symbols, with erased symbols
+/// given as `None`
+///
+/// The message polynomial is interpolated from the first `k` symbols left, and every
+/// other symbol left is checked against it.
+///
+/// # Errors
+/// Fails if fewer than `k` symbols are left, or if the symbols left don't lie on a
+/// polynomial of degree < `k`, i.e. the codeword has errors besides the erasures.
+pub fn rs_decode(codeword: &[Option<u64>], k: usize, domain: &EvalDomain, p: u64) -> Result<Vec<u64>> {
+    let received: Vec<Point> = domain
+        .elements
+        .iter()
+        .zip(codeword)
+        .filter_map(|(&x, &y)| y.map(|y| (x, y)))
+        .collect();
+    if received.len() < k {
+        return Err(anyhow!(
+            "{} symbols left, at least {} are needed to decode the message",
+            received.len(),
+            k
+        ));
+    }
+
+    let poly = interpolate(&received[..k], p);
+    if let Some((x, _)) = received[k..].iter().find(|&&(x, y)| poly.evaluate(x, p) != y) {
+        return Err(anyhow!("symbol at {} doesn't match the decoded message", x));
+    }
+
+    let mut message = poly.coeffs_ascending();
+    message.resize(k, 0);
+    Ok(message)
+}
+
+/// Generates the elements of the subgroup generated by `ms_gen` for indices `t..n`,
+/// zero-padded to `len` elements.
+///
+/// # Parameters
+/// - `ms_gen`: The generator of the subgroup.
+/// - `n`: One past the last index to generate.
+/// - `t`: The first index to generate.
+/// - `len`: The length of the returned vector.
+///
+/// # Returns
+/// Returns `[ms_gen^t, ..., ms_gen^(n-1), 0, ..., 0]` with `len` elements in total.
+///
+/// # Errors
+/// Fails if `t > n`, or if the `n - t` elements don't fit in `len`.
+pub fn generate_set_eval(ms_gen: u64, n: u64, t: u64, len: usize, p: u64) -> Result<Vec<u64>> {
+    if t > n {
+        return Err(anyhow!("start index {} is past the end index {}", t, n));
+    }
+    let count = (n - t) as usize;
+    if count > len {
+        return Err(anyhow!(
+            "{} elements (indices {}..{}) don't fit in a set of length {}",
+            count,
+            t,
+            n,
+            len
+        ));
+    }
+
+    let mut set: Vec<u64> = (t..n).map(|i| fmath::pow(ms_gen, i, p)).collect();
+    set.resize(len, 0);
+    Ok(set)
+}
+
 /// Computes the vanishing polynomial for a given set of field elements.
 ///
 /// # Parameters
@@ -116,6 +334,61 @@ pub fn vanishing_poly(set: &Vec<u64>, p: u64) -> FPoly {
     vp
 }
 
+/// Checks whether `poly` is divisible by the vanishing polynomial of `set`.
+///
+/// Since the vanishing polynomial has every element of `set` as a simple root, this holds
+/// exactly when `poly` evaluates to zero on all of `set`, which is much cheaper to check
+/// than a polynomial long division.
+pub fn divides_vanishing(poly: &FPoly, set: &[u64], p: u64) -> bool {
+    set.iter().all(|&h| poly.evaluate(h, p) == 0)
+}
+
+/// Checks that `z_vec` satisfies the R1CS `(A·z) ∘ (B·z) = C·z` row by row.
+///
+/// This works directly on the matrices, so a bad witness is caught before any
+/// polynomial is interpolated. The error names the first unsatisfied constraint.
+pub fn assert_r1cs(mat_a: &FMatrix, mat_b: &FMatrix, mat_c: &FMatrix, z_vec: &Vec<u64>, p: u64) -> Result<()> {
+    match r1cs_violations(mat_a, mat_b, mat_c, z_vec, p)?.first() {
+        None => Ok(()),
+        Some(&(i, ab, c)) => Err(anyhow!(
+            "constraint {} is not satisfied: (A·z)[{}] * (B·z)[{}] = {}, but (C·z)[{}] = {}",
+            i, i, i, ab, i, c
+        )),
+    }
+}
+
+/// Returns every row where `(A·z)[i] * (B·z)[i] != (C·z)[i]`, as `(i, (A·z)[i] * (B·z)[i], (C·z)[i])`
+///
+/// Fails if `z_vec` doesn't have one entry per column of the matrices.
+pub fn r1cs_violations(
+    mat_a: &FMatrix,
+    mat_b: &FMatrix,
+    mat_c: &FMatrix,
+    z_vec: &Vec<u64>,
+    p: u64,
+) -> Result<Vec<(usize, u64, u64)>> {
+    if z_vec.len() != mat_a.ncols() {
+        return Err(anyhow!(
+            "z_vec has {} entries, expected {} to match the matrices",
+            z_vec.len(),
+            mat_a.ncols()
+        ));
+    }
+
+    let az = matrix_fmath::vector_mul(mat_a, z_vec, p);
+    let bz = matrix_fmath::vector_mul(mat_b, z_vec, p);
+    let cz = matrix_fmath::vector_mul(mat_c, z_vec, p);
+
+    Ok(az
+        .iter()
+        .zip(&bz)
+        .zip(&cz)
+        .enumerate()
+        .map(|(i, ((&a, &b), &c))| (i, fmath::mul(a, b, p), c))
+        .filter(|&(_, ab, c)| ab != c)
+        .collect())
+}
+
 /// Computes the value at specific points of a matrix `mat` based on the sets `set_h` and `set_k`,
 /// and the mappings `row_k` and `col_k`. It evaluates a polynomial `poly_u` at these points
 /// and divides the matrix value by the product of the evaluated values.
@@ -136,9 +409,8 @@ pub fn vanishing_poly(set: &Vec<u64>, p: u64) -> FPoly {
 /// based on the evaluation of the polynomial `poly_u` at the points defined by `row_k` and `col_k`.
 /// It then stores the result in the `res` map, associating it with the corresponding value from `set_k`.
 ///
-/// # Panic
-/// The function will panic if an index `c` in `set_k` is out of bounds or if `set_k` does not have
-/// a value corresponding to the index `c`. This is asserted with `assert!(set_k.get(c).is_some());`.
+/// # Errors
+/// Fails if `mat` has more non-zero elements than `set_k` has values.
 pub fn get_matrix_point_val(
     mat: &FMatrix,
     set_k: &[u64],
@@ -146,35 +418,37 @@ pub fn get_matrix_point_val(
     col_k: &HashMap<u64, u64>,
     n: usize, // set_h_len
     p: u64,
-) -> HashMap<u64, u64> {
+) -> Result<HashMap<u64, u64>> {
+    ensure_fits_set_k(mat, set_k)?;
     let mut res = HashMap::new();
-    let mut counter = 0;
-    let mat_len = mat.size();
 
     let mut poly_u = FPoly::new(vec![0]);
     // FIXME: Check here
     poly_u.add_term(n as u64, n - 1);
 
-    for i in 0..mat_len {
-        for j in 0..mat_len {
-            if mat[(i, j)] != 0 {
-                let val = mat[(i, j)];
-                assert!(set_k.get(counter).is_some());
-                let k = set_k[counter];
-                let mul_number = fmath::mul(
-                    poly_u.evaluate(row_k[&k], p),
-                    poly_u.evaluate(col_k[&k], p),
-                    p,
-                );
-                let div_res = fmath::div(val, mul_number, p);
-                let p2 = div_res;
-                res.insert(set_k[counter], p2);
-                counter += 1;
-            }
-        }
+    for (&k, (_, _, val)) in set_k.iter().zip(mat.iter_nonzero()) {
+        let mul_number = fmath::mul(
+            poly_u.evaluate(row_k[&k], p),
+            poly_u.evaluate(col_k[&k], p),
+            p,
+        );
+        res.insert(k, fmath::div(val, mul_number, p));
     }
 
-    res
+    Ok(res)
+}
+
+/// Fails if `mat` has more non-zero elements than `set_k` has values to map them to
+pub fn ensure_fits_set_k(mat: &FMatrix, set_k: &[u64]) -> Result<()> {
+    let nonzero = mat.iter_nonzero().count();
+    if nonzero > set_k.len() {
+        return Err(anyhow!(
+            "matrix has more nonzeros than set_k supports (need {}, have {})",
+            nonzero,
+            set_k.len()
+        ));
+    }
+    Ok(())
 }
 
 /// Maps non-zero elements of the matrix `mat` to the corresponding row values from `set_h`
@@ -192,23 +466,21 @@ pub fn get_matrix_point_val(
 /// # Description
 /// The function iterates over the matrix `mat` and, for each non-zero element,
 /// maps the corresponding value in `set_k` to the row value in `set_h`.
-pub fn get_matrix_point_row(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> HashMap<u64, u64> {
-    let mut res = HashMap::new();
-    let mut counter = 0;
-    let mat_len = mat.size();
-
-    for i in 0..mat_len {
-        for j in 0..mat_len {
-            if mat[(i, j)] != 0 {
-                res.insert(set_k[counter], set_h[i]);
-                counter += 1;
-            }
-        }
-    }
-
-    add_random_points(&mut res, counter, set_h, set_k).unwrap();
-
-    res
+///
+/// # Errors
+/// Fails if `mat` has more non-zero elements than `set_k` has values.
+pub fn get_matrix_point_row(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> Result<HashMap<u64, u64>> {
+    ensure_fits_set_k(mat, set_k)?;
+    let mut res: HashMap<u64, u64> = set_k
+        .iter()
+        .zip(mat.iter_nonzero())
+        .map(|(&k, (i, _, _))| (k, set_h[i]))
+        .collect();
+    let counter = res.len();
+
+    add_random_points(&mut res, counter, set_h, set_k)?;
+
+    Ok(res)
 }
 
 /// Maps non-zero elements of the matrix `mat` to the corresponding column values from `set_h`
@@ -226,23 +498,21 @@ pub fn get_matrix_point_row(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> Hash
 /// # Description
 /// The function iterates over the matrix `mat` and, for each non-zero element,
 /// maps the corresponding value in `set_k` to the column value in `set_h`.
-pub fn get_matrix_point_col(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> HashMap<u64, u64> {
-    let mut res = HashMap::new();
-    let mut c = 0;
-    let mat_len = mat.size();
-
-    for i in 0..mat_len {
-        for j in 0..mat_len {
-            if mat[(i, j)] != 0 {
-                res.insert(set_k[c], set_h[j]);
-                c += 1;
-            }
-        }
-    }
-
-    add_random_points(&mut res, c, set_h, set_k).unwrap();
-
-    res
+///
+/// # Errors
+/// Fails if `mat` has more non-zero elements than `set_k` has values.
+pub fn get_matrix_point_col(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> Result<HashMap<u64, u64>> {
+    ensure_fits_set_k(mat, set_k)?;
+    let mut res: HashMap<u64, u64> = set_k
+        .iter()
+        .zip(mat.iter_nonzero())
+        .map(|(&k, (_, j, _))| (k, set_h[j]))
+        .collect();
+    let counter = res.len();
+
+    add_random_points(&mut res, counter, set_h, set_k)?;
+
+    Ok(res)
 }
 
 /// Retrieves the row points, column points, and computed polynomial values for non-zero elements
@@ -265,6 +535,9 @@ pub fn get_matrix_point_col(mat: &FMatrix, set_h: &[u64], set_k: &[u64]) -> Hash
 /// values for each non-zero element in the matrix. It asserts that the total number of row and
 /// column points is twice the length of `set_k` to ensure consistency.
 ///
+/// # Errors
+/// Fails if `mat` has more non-zero elements than `set_k` has values.
+///
 /// # Panic
 /// The function will panic if the number of row and column points does not match the expected
 /// count based on `set_k`.
@@ -273,18 +546,18 @@ pub fn get_matrix_points(
     set_h: &[u64],
     set_k: &[u64],
     p: u64,
-) -> (HashMap<u64, u64>, HashMap<u64, u64>, HashMap<u64, u64>) {
-    let row_p = get_matrix_point_row(mat, &set_h, &set_k);
+) -> Result<MatrixPoints> {
+    let row_p = get_matrix_point_row(mat, &set_h, &set_k)?;
     // Ensure that the number of row points matches the length of set_k.
     assert_eq!(row_p.len(), set_k.len());
 
-    let col_p = get_matrix_point_col(mat, &set_h, &set_k);
+    let col_p = get_matrix_point_col(mat, &set_h, &set_k)?;
     // Ensure that the number of col points matches the length of set_k.
     assert_eq!(col_p.len(), set_k.len());
 
-    let val_p = get_matrix_point_val(mat, &set_k, &row_p, &col_p, set_h.len(), p);
+    let val_p = get_matrix_point_val(mat, &set_k, &row_p, &col_p, set_h.len(), p)?;
 
-    (row_p, col_p, val_p)
+    Ok((row_p, col_p, val_p))
 }
 
 /// Represents the order of evaluation for polynomial computations.
@@ -297,6 +570,17 @@ pub enum EvalOrder {
     KX,
 }
 
+/// Returns the entries of `points` sorted by key, i.e. by element of `set_k`
+///
+/// Iterating a `HashMap` visits the entries in an order that changes from run to run;
+/// the polynomial builders go through this instead so every run adds the terms in the
+/// same order.
+pub fn sorted_points(points: &HashMap<u64, u64>) -> Vec<(u64, u64)> {
+    let mut sorted: Vec<(u64, u64)> = points.iter().map(|(&k, &v)| (k, v)).collect();
+    sorted.sort_unstable();
+    sorted
+}
+
 /// Computes a polynomial `m_k` based on the provided `points_val`, `points_row`, and `points_col`.
 ///
 /// This function combines the functionality of the previous `m_xk` and `m_kx` functions into a single
@@ -315,7 +599,8 @@ pub enum EvalOrder {
 /// Returns a `Poly` representing the result of summing up the products of the evaluated polynomials.
 ///
 /// # Description
-/// This function iterates over each key-value pair `(k, val)` in `points_val`, and for each pair:
+/// This function iterates over each key-value pair `(k, val)` in `points_val`, in the order of
+/// `sorted_points`, and for each pair:
 /// 1. Constructs a polynomial `poly_val` from the value `val`.
 /// 2. Constructs two polynomials `poly_x` and `poly_y` using the `func_u` function, with `points_row[k]` and `points_col[k]` as inputs, respectively.
 /// 3. Depending on the specified `eval_order`, it evaluates either `poly_y` at `num` (for `XK`) or `poly_x` at `num` (for `KX`).
@@ -340,7 +625,7 @@ pub fn m_k(
 
     // eprintln!("val len: {}", points_val.len());
 
-    for (k, h) in points_val {
+    for (k, h) in &sorted_points(points_val) {
         // let timer = std::time::Instant::now();
         let poly_x = catch
             .entry(points_row[k])
@@ -383,7 +668,7 @@ pub fn m_k_2(
 
     let mut ftime = std::time::Duration::new(0, 0);
 
-    for (set_k_items, value) in points_val {
+    for (set_k_items, value) in &sorted_points(points_val) {
         // Retrieve corresponding row and column points
         let point_row = &points_row[set_k_items];
         let point_col = &points_col[set_k_items];
@@ -491,6 +776,9 @@ pub fn poly_func_u(x: Option<u64>, y: Option<u64>, degree: usize, p: u64) -> FPo
 /// 3. Trims the polynomials to remove leading zeros.
 /// 4. Multiplies `p_r_alphak` and `p_m_kx` and sums the result into `res`.
 ///
+/// The elements of `set_h` are processed in parallel. Field addition is exact, so the sum
+/// doesn't depend on the number of threads or on the order the products are added in.
+///
 /// This function is used to compute the final polynomial based on the interaction between `alpha` and `h`.
 pub fn sigma_rk_mk(
     set_h: &Vec<u64>,
@@ -501,8 +789,6 @@ pub fn sigma_rk_mk(
     eval_order: &EvalOrder,
     p: u64,
 ) -> FPoly {
-    let mut res = FPoly::new(vec![0]);
-
     let mut catch: HashMap<u64, FPoly> =
         HashMap::with_capacity(points_row.len() + points_col.len());
     let unique_keys: HashSet<_> = points_row.values().chain(points_col.values()).collect();
@@ -514,23 +800,34 @@ pub fn sigma_rk_mk(
             .or_insert_with(|| poly_func_u(None, Some(key), set_h.len(), p));
     }
 
-    for h in set_h {
-        let mut p_r_xk = poly_func_u(Some(alpha), Some(*h), set_h.len(), p);
+    set_h
+        .par_iter()
+        .map(|h| rk_mk_term(h, set_h.len(), alpha, points_val, points_row, points_col, &catch, eval_order, p))
+        .reduce(FPoly::zero, |lhs, rhs| poly_fmath::add(&lhs, &rhs, p))
+}
 
-        let timer = std::time::Instant::now();
-        // FIXME: this part is expensive
-        let mut p_m_kx = m_k_2(h, points_val, points_row, points_col, &catch, eval_order, p);
-        // eprintln!("time2 : {:?}", timer.elapsed());
+/// Term `r(alpha, h) * m_k(h)` of `sigma_rk_mk` for one element `h` of `set_h`
+#[allow(clippy::too_many_arguments)]
+fn rk_mk_term(
+    h: &u64,
+    set_h_len: usize,
+    alpha: u64,
+    points_val: &HashMap<u64, u64>,
+    points_row: &HashMap<u64, u64>,
+    points_col: &HashMap<u64, u64>,
+    catch: &HashMap<u64, FPoly>,
+    eval_order: &EvalOrder,
+    p: u64,
+) -> FPoly {
+    let mut p_r_xk = poly_func_u(Some(alpha), Some(*h), set_h_len, p);
 
-        p_r_xk.trim();
-        p_m_kx.trim();
+    // FIXME: this part is expensive
+    let mut p_m_kx = m_k_2(h, points_val, points_row, points_col, catch, eval_order, p);
 
-        // sigma
-        let mul_poly = poly_fmath::mul(&p_r_xk, &p_m_kx, p);
-        res = poly_fmath::add(&res, &mul_poly, p);
-    }
+    p_r_xk.trim();
+    p_m_kx.trim();
 
-    res
+    poly_fmath::mul(&p_r_xk, &p_m_kx, p)
 }
 
 /// Calculates the sigma_m value based on the provided polynomials and parameters.
@@ -599,25 +896,38 @@ pub fn sigma_yi_li(points: &HashMap<u64, u64>, set_k: &Vec<u64>, p: u64) -> FPol
     interpolate(&points_li, p)
 }
 
+/// `e(g, g)`, the generator of the target group of the pairing `e_func` models
+///
+/// Commitments are `g * x` in the additive group of F_p, and `e_func` maps
+/// `(g * a, g * b)` to `E_GG * a * b`. Both sides of a pairing check carry the factor
+/// once, so any nonzero value accepts exactly the same openings; zero would accept
+/// every opening.
+pub const E_GG: u64 = 3;
+
 /// Computes a pairing function based on the inputs `a`, `b`, and `g`.
 ///
-/// This function calculates a modified pairing value by reducing `a` and `b`
-/// modulo `g`, multiplying the results, and then scaling the product by a factor of 3.
+/// This function divides `a` and `b` by `g`, multiplies the results, and scales the
+/// product by `E_GG`, see `e_func_with`.
 ///
 /// # Parameters
 /// - `a`: An `u64` value representing the first input to the pairing function.
 /// - `b`: An `u64` value representing the second input to the pairing function.
-/// - `g`: An `u64` value used as the modulus for reducing `a` and `b`.
+/// - `g`: The generator `a` and `b` are multiples of.
 ///
 /// # Returns
 /// An `u64` value representing the result of the pairing computation.
 pub fn e_func(a: u64, b: u64, g: u64, p: u64) -> u64 {
+    e_func_with(a, b, g, E_GG, p)
+}
+
+/// Same as `e_func`, with `gt` standing for `e(g, g)` instead of `E_GG`
+pub fn e_func_with(a: u64, b: u64, g: u64, gt: u64, p: u64) -> u64 {
     println_dbg!("a: {a}, b: {b}");
     let a_r = fmath::div(a, g, p);
     let b_r = fmath::div(b, g, p);
     println_dbg!("a_r: {a_r}, b_r: {b_r}");
     let exp = fmath::mul(a_r, b_r, p);
-    fmath::mul(3, exp, p)
+    fmath::mul(gt, exp, p)
 }
 
 /// Computes commitments for a list of polynomials using a given commitment key.
@@ -627,19 +937,19 @@ pub fn e_func(a: u64, b: u64, g: u64, p: u64) -> u64 {
 ///
 /// # Parameters
 /// - `polys`: A slice of `Poly` representing the polynomials for which commitments are to be computed.
-/// - `ck`: A reference to a vector of `u64` values representing the commitment key used in the KZG scheme.
+/// - `ck`: A slice of `u64` values representing the commitment key used in the KZG scheme.
 ///
 /// # Returns
 /// A vector of `u64` values, where each value represents the commitment for the corresponding polynomial.
-pub fn compute_all_commitment(polys: &[FPoly], ck: &Vec<u64>, p: u64) -> Vec<u64> {
-    let mut res = vec![];
-
-    for poly in polys.iter() {
-        let commitment_num = kzg::commit(&poly, &ck, p);
-        res.push(commitment_num);
-    }
+pub fn compute_all_commitment(polys: &[FPoly], ck: &[u64], p: u64) -> Vec<u64> {
+    compute_all_commitment_iter(polys, ck, p)
+}
 
-    res
+/// Same as `compute_all_commitment`, but takes the polynomials from any iterator.
+///
+/// This lets callers commit polynomials as they are produced, without collecting them into a vector first.
+pub fn compute_all_commitment_iter<'a>(polys: impl IntoIterator<Item = &'a FPoly>, ck: &[u64], p: u64) -> Vec<u64> {
+    polys.into_iter().map(|poly| kzg::commit(poly, ck, p)).collect()
 }
 
 #[cfg(test)]
@@ -680,6 +990,124 @@ mod math_test {
         );
     }
 
+    #[test]
+    fn test_divides_vanishing() {
+        const P: u64 = 181;
+        let set = vec![1, 5, 25, 125];
+        let van = vanishing_poly(&set, P);
+
+        // (x^2 + 3x + 7) * v_H(x) is divisible, adding 1 breaks it
+        let mut poly = poly_fmath::mul(&FPoly::new(vec![1, 3, 7]), &van, P);
+        assert!(divides_vanishing(&poly, &set, P));
+        assert!(poly_fmath::div(&poly, &van, P).1.terms.iter().all(|&t| t == 0));
+
+        poly.add_term(1, 0);
+        assert!(!divides_vanishing(&poly, &set, P));
+        assert!(poly_fmath::div(&poly, &van, P).1.terms.iter().any(|&t| t != 0));
+    }
+
+    #[test]
+    fn test_generate_set_eval() {
+        const P: u64 = 181;
+        let gen = 5;
+        let pow = |i| fmath::pow(gen, i, P);
+
+        // Padded
+        assert_eq!(generate_set_eval(gen, 5, 2, 6, P).unwrap(), vec![pow(2), pow(3), pow(4), 0, 0, 0]);
+
+        // Exact fit
+        assert_eq!(generate_set_eval(gen, 5, 0, 5, P).unwrap(), (0..5).map(pow).collect::<Vec<u64>>());
+        assert_eq!(generate_set_eval(gen, 3, 3, 0, P).unwrap(), Vec::<u64>::new());
+
+        // Empty range, all padding
+        assert_eq!(generate_set_eval(gen, 3, 3, 2, P).unwrap(), vec![0, 0]);
+
+        // Too long
+        let err = generate_set_eval(gen, 5, 0, 4, P).unwrap_err();
+        assert_eq!(err.to_string(), "5 elements (indices 0..5) don't fit in a set of length 4");
+        assert!(generate_set_eval(gen, 2, 3, 4, P).is_err());
+    }
+
+    #[test]
+    fn test_eval_domain() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let p = class_data.p;
+
+        // n = 37 is prime and m = 8 a power of two
+        for size in [class_data.n, class_data.m] {
+            let domain = EvalDomain::new(size, class_data, p);
+            assert_eq!(domain.elements, generate_set(size, class_data, p));
+            assert_eq!(domain.vanishing_poly(), vanishing_poly(&domain.elements, p));
+
+            let coeffs: Vec<u64> = (0..size).map(|i| fmath::pow(7, i + 3, p)).collect();
+            let evals = domain.fft(&coeffs);
+            let poly = FPoly::from_coeffs_ascending(&coeffs);
+            let expected: Vec<u64> = domain.elements.iter().map(|&x| poly.evaluate(x, p)).collect();
+            assert_eq!(evals, expected);
+            assert_eq!(domain.ifft(&evals), coeffs);
+
+            let mut points: Vec<Point> = domain.elements.iter().copied().zip(evals.iter().copied()).collect();
+            assert_eq!(domain.interpolate(&evals), interpolate(&points, p));
+            assert_eq!(domain.interpolate_blinded(&evals, &[]), interpolate(&points, p));
+
+            // The blinding points of the prover lie outside the subgroup
+            let blinding = [(3, 3), (4, 4), (5, 1234)];
+            points.extend(blinding);
+            assert_eq!(domain.interpolate_blinded(&evals, &blinding), interpolate(&points, p));
+        }
+    }
+
+    #[test]
+    fn test_reed_solomon() {
+        let class_data = ClassDataJson::get_class_data("class.json", 2).unwrap();
+        let p = class_data.p;
+        let domain = EvalDomain::new(class_data.m, class_data, p);
+
+        // Rate 1/2: any 4 of the 8 symbols recover the message
+        let message = vec![12, 0, 345, 6789];
+        let codeword = rs_encode(&message, &domain).unwrap();
+        let poly = FPoly::from_coeffs_ascending(&message);
+        assert!(domain.elements.iter().zip(&codeword).all(|(&x, &y)| poly.evaluate(x, p) == y));
+
+        let mut received: Vec<Option<u64>> = codeword.iter().copied().map(Some).collect();
+        for i in [0, 3, 4, 6] {
+            received[i] = None;
+        }
+        assert_eq!(rs_decode(&received, message.len(), &domain, p).unwrap(), message);
+
+        // One more erasure is too many
+        received[7] = None;
+        let err = rs_decode(&received, message.len(), &domain, p).unwrap_err();
+        assert_eq!(err.to_string(), "3 symbols left, at least 4 are needed to decode the message");
+
+        // An error on top of the erasures is detected, not decoded
+        let mut corrupted: Vec<Option<u64>> = codeword.iter().copied().map(Some).collect();
+        corrupted[1] = None;
+        corrupted[5] = Some(fmath::add(codeword[5], 1, p));
+        assert!(rs_decode(&corrupted, message.len(), &domain, p).is_err());
+
+        assert!(rs_encode(&[1; 9], &domain).is_err());
+    }
+
+    #[test]
+    fn test_assert_r1cs() {
+        const P: u64 = 181;
+        // z = [1, x, w] with the single gate w = x * x
+        let mat_a = FMatrix::new(vec![vec![0, 0, 0], vec![0, 0, 0], vec![0, 1, 0]]);
+        let mat_b = mat_a.clone();
+        let mat_c = FMatrix::new(vec![vec![0, 0, 0], vec![0, 0, 0], vec![0, 0, 1]]);
+
+        assert!(assert_r1cs(&mat_a, &mat_b, &mat_c, &vec![1, 12, 144], P).is_ok());
+
+        let err = assert_r1cs(&mat_a, &mat_b, &mat_c, &vec![1, 12, 145], P).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "constraint 2 is not satisfied: (A·z)[2] * (B·z)[2] = 144, but (C·z)[2] = 145"
+        );
+
+        assert!(assert_r1cs(&mat_a, &mat_b, &mat_c, &vec![1, 12], P).is_err());
+    }
+
     #[test]
     fn test_interpolate() {
         // Test case 1
@@ -723,4 +1151,174 @@ mod math_test {
         let expected_poly3 = FPoly::new(vec![68, 70, 35, 146, 0]);
         assert_eq!(expected_poly3, interpolate(&points3, 181));
     }
+
+    // Reference for m_k(XK): ∑_k val(k) * u(x, row(k)) * u(num, col(k))
+    fn m_xk(
+        num: u64,
+        points_val: &HashMap<u64, u64>,
+        points_row: &HashMap<u64, u64>,
+        points_col: &HashMap<u64, u64>,
+        set_h_len: usize,
+        p: u64,
+    ) -> FPoly {
+        points_val.iter().fold(FPoly::zero(), |acc, (k, val)| {
+            let u_col = poly_func_u(Some(num), Some(points_col[k]), set_h_len, p).terms[0];
+            let poly_x = poly_func_u(None, Some(points_row[k]), set_h_len, p);
+            let tmp = poly_fmath::mul_by_number(&poly_x, fmath::mul(*val, u_col, p), p);
+            poly_fmath::add(&acc, &tmp, p)
+        })
+    }
+
+    // Reference for m_k(KX): ∑_k val(k) * u(num, row(k)) * u(x, col(k))
+    fn m_kx(
+        num: u64,
+        points_val: &HashMap<u64, u64>,
+        points_row: &HashMap<u64, u64>,
+        points_col: &HashMap<u64, u64>,
+        set_h_len: usize,
+        p: u64,
+    ) -> FPoly {
+        points_val.iter().fold(FPoly::zero(), |acc, (k, val)| {
+            let u_row = poly_func_u(Some(num), Some(points_row[k]), set_h_len, p).terms[0];
+            let poly_y = poly_func_u(None, Some(points_col[k]), set_h_len, p);
+            let tmp = poly_fmath::mul_by_number(&poly_y, fmath::mul(*val, u_row, p), p);
+            poly_fmath::add(&acc, &tmp, p)
+        })
+    }
+
+    #[test]
+    fn test_m_k_eval_orders() {
+        const P: u64 = 181;
+        let set_h_len = 5;
+        let points_val = HashMap::from([(1, 7), (2, 30), (3, 121), (4, 1)]);
+        let points_row = HashMap::from([(1, 42), (2, 59), (3, 42), (4, 125)]);
+        let points_col = HashMap::from([(1, 59), (2, 1), (3, 125), (4, 42)]);
+
+        // Cache of u(x, v) for every row/column value, as `sigma_rk_mk` builds it
+        let catch: HashMap<u64, FPoly> = points_row
+            .values()
+            .chain(points_col.values())
+            .map(|&v| (v, poly_func_u(None, Some(v), set_h_len, P)))
+            .collect();
+
+        for num in [0, 3, 42, 180] {
+            let xk = m_k(&num, &points_val, &points_row, &points_col, set_h_len, &EvalOrder::XK, P);
+            let kx = m_k(&num, &points_val, &points_row, &points_col, set_h_len, &EvalOrder::KX, P);
+
+            assert_eq!(xk, m_xk(num, &points_val, &points_row, &points_col, set_h_len, P));
+            assert_eq!(kx, m_kx(num, &points_val, &points_row, &points_col, set_h_len, P));
+
+            assert_eq!(xk, m_k_2(&num, &points_val, &points_row, &points_col, &catch, &EvalOrder::XK, P));
+            assert_eq!(kx, m_k_2(&num, &points_val, &points_row, &points_col, &catch, &EvalOrder::KX, P));
+
+            // Swapping the roles of row and column turns one order into the other
+            assert_eq!(
+                xk,
+                m_k(&num, &points_val, &points_col, &points_row, set_h_len, &EvalOrder::KX, P)
+            );
+        }
+    }
+
+    #[test]
+    fn test_polynomials_are_deterministic() {
+        const P: u64 = 181;
+        let set_h = vec![1, 5, 25, 125];
+        let entries = [(1, 7, 42, 59), (2, 30, 59, 1), (3, 121, 42, 125), (4, 1, 125, 42), (19, 88, 1, 5)];
+
+        // Every map gets its own hasher seed, and each build inserts in another order
+        let build = |run: usize| {
+            let mut rotated = entries.to_vec();
+            rotated.rotate_left(run % entries.len());
+            let (mut val, mut row, mut col) = (HashMap::new(), HashMap::new(), HashMap::new());
+            for &(k, v, r, c) in &rotated {
+                val.insert(k, v);
+                row.insert(k, r);
+                col.insert(k, c);
+            }
+            assert_eq!(sorted_points(&val), entries.iter().map(|e| (e.0, e.1)).collect::<Vec<_>>());
+            // Compare the raw terms, leading zeros included
+            (
+                m_k(&3, &val, &row, &col, set_h.len(), &EvalOrder::XK, P).terms,
+                sigma_rk_mk(&set_h, 10, &val, &row, &col, &EvalOrder::KX, P).terms,
+            )
+        };
+
+        let first = build(0);
+        for run in 1..10 {
+            assert_eq!(build(run), first);
+        }
+    }
+
+    #[test]
+    fn test_matrix_points_overflow_set_k() {
+        const P: u64 = 181;
+        let set_h = vec![1, 5, 25, 125];
+        let set_k = vec![1, 19];
+        // Three nonzeros, but set_k only has two points
+        let mat = FMatrix::new(vec![vec![1, 0, 0, 0], vec![0, 2, 0, 0], vec![0, 0, 0, 3], vec![0, 0, 0, 0]]);
+        let expected = "matrix has more nonzeros than set_k supports (need 3, have 2)";
+
+        assert_eq!(get_matrix_point_row(&mat, &set_h, &set_k).unwrap_err().to_string(), expected);
+        assert_eq!(get_matrix_point_col(&mat, &set_h, &set_k).unwrap_err().to_string(), expected);
+        let points = HashMap::new();
+        assert_eq!(
+            get_matrix_point_val(&mat, &set_k, &points, &points, set_h.len(), P).unwrap_err().to_string(),
+            expected
+        );
+        assert_eq!(get_matrix_points(&mat, &set_h, &set_k, P).unwrap_err().to_string(), expected);
+
+        // One more point in set_k is enough
+        let (row_p, col_p, val_p) = get_matrix_points(&mat, &set_h, &[1, 19, 30], P).unwrap();
+        assert_eq!((row_p.len(), col_p.len(), val_p.len()), (3, 3, 3));
+    }
+
+    #[test]
+    fn test_sigma_rk_mk_parallel() {
+        const P: u64 = 181;
+        // Subgroup of order 36 generated by 2^5
+        let set_h: Vec<u64> = (0..36).map(|i| fmath::pow(32, i, P)).collect();
+        let points_val: HashMap<u64, u64> = (1..=12).map(|k| (k, (17 * k + 5) % P)).collect();
+        let points_row: HashMap<u64, u64> = (1..=12).map(|k| (k, set_h[(5 * k as usize) % 36])).collect();
+        let points_col: HashMap<u64, u64> = (1..=12).map(|k| (k, set_h[(7 * k as usize + 3) % 36])).collect();
+        let alpha = 10;
+
+        let catch: HashMap<u64, FPoly> = points_row
+            .values()
+            .chain(points_col.values())
+            .map(|&v| (v, poly_func_u(None, Some(v), set_h.len(), P)))
+            .collect();
+
+        for eval_order in [EvalOrder::XK, EvalOrder::KX] {
+            let serial = set_h.iter().fold(FPoly::zero(), |acc, h| {
+                let term = rk_mk_term(h, set_h.len(), alpha, &points_val, &points_row, &points_col, &catch, &eval_order, P);
+                poly_fmath::add(&acc, &term, P)
+            });
+            assert!(!serial.coeffs_descending().is_empty());
+
+            for threads in [1, 4] {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+                let parallel = pool.install(|| {
+                    sigma_rk_mk(&set_h, alpha, &points_val, &points_row, &points_col, &eval_order, P)
+                });
+                assert_eq!(parallel, serial, "{} threads", threads);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compute_all_commitment_modulus() {
+        let polys = vec![FPoly::new(vec![500, 3, 9000]), FPoly::new(vec![7, 0, 0, 1])];
+        // ck = [1, t, t^2, ...] commits each polynomial to its value at t
+        let t: u64 = 50;
+        let ck: Vec<u64> = (0..4).map(|i| t.pow(i)).collect();
+
+        let under = |p: u64| compute_all_commitment(&polys, &ck, p);
+        let (small, large) = (181, 1678321);
+
+        for p in [small, large] {
+            let expected: Vec<u64> = polys.iter().map(|poly| poly.evaluate(t, p)).collect();
+            assert_eq!(under(p), expected, "p = {}", p);
+        }
+        assert_ne!(under(small), under(large));
+    }
 }
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
 
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FMatrix {
     pub data: Vec<Vec<u64>>,
 }
@@ -31,12 +33,35 @@ impl FMatrix {
         Self { data }
     }
 
+    /// Get the number of rows of the matrix
+    pub fn nrows(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Get the number of columns of the matrix
+    pub fn ncols(&self) -> usize {
+        self.data.first().map_or(0, |row| row.len())
+    }
+
     /// Get the size of the matrix
     pub fn size(&self) -> usize {
         let size = self.data.len();
         assert_eq!(self.data[0].len(), size);
         size
     }
+
+    /// Nonzero entries as `(row, col, val)`, in row-major order
+    ///
+    /// This is the order nonzeros are mapped to the elements of K in, so every
+    /// function that walks the nonzeros goes through here.
+    pub fn iter_nonzero(&self) -> impl Iterator<Item = (usize, usize, u64)> + '_ {
+        self.data.iter().enumerate().flat_map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .filter(|&(_, &val)| val != 0)
+                .map(move |(j, &val)| (i, j, val))
+        })
+    }
 }
 
 // Indexing for immutable access to matrix elements
@@ -145,18 +170,24 @@ pub mod matrix_fmath {
         let n = a.size();
         let mut result = vec![0; n];
 
-        for i in 0..n {
-            for j in 0..n {
-                let tmp_mul = fmath::mul(a[(i, j)], b[j], p);
-                result[i] = fmath::add(result[i], tmp_mul, p);
-            }
+        for (i, j, val) in a.iter_nonzero() {
+            let tmp_mul = fmath::mul(val, b[j], p);
+            result[i] = fmath::add(result[i], tmp_mul, p);
         }
         result
     }
 }
 
 
-#[derive(Debug, Clone)]
+/// Names one of the matrices A, B and C of a `Matrices`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixId {
+    A,
+    B,
+    C,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// A struct representing a collection of matrices used in computations.
 pub struct Matrices {
     pub a: FMatrix,
@@ -174,21 +205,33 @@ impl Matrices {
         Self { a, b, c, size }
     }
 
+    /// The matrix named by `which`
+    pub fn get(&self, which: MatrixId) -> &FMatrix {
+        match which {
+            MatrixId::A => &self.a,
+            MatrixId::B => &self.b,
+            MatrixId::C => &self.c,
+        }
+    }
+
+    /// Nonzero entries of the matrix named by `which`, see `FMatrix::iter_nonzero`
+    pub fn iter_nonzero(&self, which: MatrixId) -> impl Iterator<Item = (usize, usize, u64)> + '_ {
+        self.get(which).iter_nonzero()
+    }
+
+    /// Checks the Hadamard relation `(A·z) ∘ (B·z) = C·z` for the witness `z_vec`
+    ///
+    /// Commitment-side counterpart of the check the prover runs before interpolating;
+    /// the error names the first row where the products differ.
+    pub fn check_hadamard(&self, z_vec: &Vec<u64>, p: u64) -> Result<()> {
+        crate::math::assert_r1cs(&self.a, &self.b, &self.c, z_vec, p)
+    }
+
     /// Converts a dense matrix to a sparse coordinate form, represented as a vector of
     /// tuples (i, j, val), where `i` is the row index, `j` is the column index, and
     /// `val` is the value at that position in the matrix.
     pub fn to_sparse_coordinate_form(matrix: &FMatrix) -> Vec<(usize, usize, u64)> {
-        let mut sp_mat = vec![];
-
-        for i in 0..matrix.size() {
-            for j in 0..matrix.size() {
-                if matrix[(i, j)] != 0 {
-                    sp_mat.push((i, j, matrix[(i, j)]));
-                }
-            }
-        }
-
-        sp_mat
+        matrix.iter_nonzero().collect()
     }
 
     /// Converts a dense matrix to a sparse representation by storing the column indices
@@ -197,21 +240,13 @@ impl Matrices {
     pub fn to_sparse_column_indices(matrix: &FMatrix, number_t_zeros: usize) -> Vec<u64> {
         assert!(number_t_zeros <= matrix.size());
 
-        let mat_size = matrix.size();
-
-        let mut sp_mat = vec![];
-
-        for i in 0..mat_size {
-            for j in 0..mat_size {
-                assert!(matrix[(i, j)] == 0 || matrix[(i, j)] == 1);
-                if matrix[(i, j)] == 1 {
-                    sp_mat.push((j).try_into().unwrap());
-                }
-            }
-        }
-
-
-        sp_mat
+        matrix
+            .iter_nonzero()
+            .map(|(_, j, val)| {
+                assert!(val == 1);
+                j as u64
+            })
+            .collect()
     }
 
     /// Generates a square matrix of specified size with ones on the diagonal starting from the t_zero index
@@ -249,6 +284,25 @@ mod test_matrix_oprations {
     }
 
 
+    #[test]
+    fn test_check_hadamard() {
+        let p = 181;
+        // z = [1, x, x^2, x^3]: row 2 checks x * x = x^2 and row 3 checks x^2 * x = x^3
+        let mut matrices = Matrices::new(4);
+        matrices.a[(2, 1)] = 1;
+        matrices.b[(2, 1)] = 1;
+        matrices.a[(3, 2)] = 1;
+        matrices.b[(3, 1)] = 1;
+        matrices.c = Matrices::generate_matrix_c(4, 2);
+
+        assert!(matrices.check_hadamard(&vec![1, 5, 25, 125], p).is_ok());
+
+        let err = matrices.check_hadamard(&vec![1, 5, 25, 126], p).unwrap_err();
+        assert!(err.to_string().starts_with("constraint 3 is not satisfied"), "{}", err);
+        let err = matrices.check_hadamard(&vec![1, 5, 24, 120], p).unwrap_err();
+        assert!(err.to_string().starts_with("constraint 2 is not satisfied"), "{}", err);
+    }
+
     #[test]
     fn test_add() {
         let a = FMatrix::new(vec![vec![1, 2], vec![3, 4]]);
@@ -304,4 +358,26 @@ mod test_matrix_oprations {
 
         assert_eq!(result.data, expected.data);
     }
+
+    #[test]
+    fn test_iter_nonzero_order() {
+        let mut matrices = Matrices::new(5);
+        for &(i, j, val) in &[(4, 0, 3), (0, 4, 1), (2, 2, 7), (0, 1, 9), (3, 4, 2), (2, 0, 5)] {
+            matrices.b[(i, j)] = val;
+        }
+
+        let mut expected = vec![];
+        for i in 0..5 {
+            for j in 0..5 {
+                if matrices.b[(i, j)] != 0 {
+                    expected.push((i, j, matrices.b[(i, j)]));
+                }
+            }
+        }
+
+        let entries: Vec<_> = matrices.iter_nonzero(MatrixId::B).collect();
+        assert_eq!(entries, expected);
+        assert_eq!(entries, [(0, 1, 9), (0, 4, 1), (2, 0, 5), (2, 2, 7), (3, 4, 2), (4, 0, 3)]);
+        assert_eq!(matrices.iter_nonzero(MatrixId::A).count(), 0);
+    }
 }
@@ -16,9 +16,9 @@
 //! Module for parsing gate information from text files into `Gate` objects.
 
 use anyhow::{anyhow, Context, Result};
-use std::io::BufRead;
+use serde::Serialize;
 use std::path::PathBuf;
-use crate::{json_file::*, println_dbg};
+use crate::println_dbg;
 
 
 /// An enumeration representing the registers in the RISC-V architecture.
@@ -36,7 +36,7 @@ use crate::{json_file::*, println_dbg};
 /// - `T0` to `T6`: Temporary registers (x5 to x31), used for intermediate values.
 /// - `S0` to `S11`: Saved registers (x8 to x27), used to save values across function calls.
 /// - `A0` to `A7`: Argument registers (x10 to x17), used to pass arguments to functions.
-#[derive(Eq, Hash, PartialEq, Debug, Clone, Copy)]
+#[derive(Eq, Hash, PartialEq, Debug, Clone, Copy, Serialize)]
 pub enum RiscvReg {
     Zero = 0, // x0 - Hardwired zero
     Ra = 1,   // x1 - Return address
@@ -131,7 +131,7 @@ impl From<u8> for RiscvReg {
 ///
 /// This enum defines the possible types of gates,
 /// specifically addition and multiplication gates.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum Instructions {
     Add,
     Addi,
@@ -140,6 +140,25 @@ pub enum Instructions {
     // Div,
 }
 
+/// Parses an opcode mnemonic into its `Instructions` variant.
+///
+/// The mnemonic is matched case-insensitively. `sub`, `div` and `ld` are
+/// recognized RISC-V opcodes but have no gate representation yet, so they
+/// are rejected with a "not supported" error, as is any unknown mnemonic.
+impl TryFrom<&str> for Instructions {
+    type Error = anyhow::Error;
+
+    fn try_from(op: &str) -> Result<Self> {
+        match op.to_lowercase().as_str() {
+            "add" => Ok(Instructions::Add),
+            "addi" => Ok(Instructions::Addi),
+            "mul" => Ok(Instructions::Mul),
+            "sub" | "div" | "ld" => Err(anyhow!("operation is not supported yet: {}", op)),
+            _ => Err(anyhow!("unknown operation: {}", op)),
+        }
+    }
+}
+
 /// Represents a gate with its parameters.
 ///
 /// # Fields
@@ -148,11 +167,12 @@ pub enum Instructions {
 /// - `val_left`: Optional value for the left input, if provided.
 /// - `val_right`: Optional value for the right input, if provided.
 /// - `gate_type`: The type of the gate, which can be either an addition or multiplication gate.
+/// - `source_line`: The assembly line the gate was parsed from, or 0 for gates built in code.
 ///
 /// # Description
 /// This struct is used to define a gate. It includes the indices for the
 /// left and right inputs, optional values for these inputs, and the type of gate being used.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Gate {
     pub val_left: Option<u64>,
     pub val_right: Option<u64>,
@@ -160,6 +180,7 @@ pub struct Gate {
     pub reg_left: RiscvReg,
     pub reg_right: RiscvReg,
     pub instr: Instructions,
+    pub source_line: usize,
 }
 
 impl Gate {
@@ -193,8 +214,23 @@ impl Gate {
             reg_left,
             reg_right,
             instr: gate_type,
+            source_line: 0,
         }
     }
+
+    /// Returns the gate with `source_line` set to the assembly line it came from
+    pub fn at_line(mut self, source_line: usize) -> Self {
+        self.source_line = source_line;
+        self
+    }
+
+    /// Returns true for a `mul` of a register by itself (`mul rd, rs, rs`)
+    pub fn is_square(&self) -> bool {
+        self.instr == Instructions::Mul
+            && self.val_left.is_none()
+            && self.val_right.is_none()
+            && self.reg_left == self.reg_right
+    }
 }
 
 /// Parses a line of text into a tuple containing a specific element and a vector of elements.
@@ -291,24 +327,61 @@ fn register_parser(reg: Vec<&str>) -> (u8, u8, u8) {
 
 
 
+/// Mnemonics that neither compute nor write a general purpose register read by later gates:
+/// no-ops, branches, jumps and stores.
+const IGNORABLE_OPS: [&str; 27] = [
+    "nop", "fence", "ecall", "ebreak", "j", "jr", "ret", "call", "tail",
+    "beq", "bne", "blt", "bge", "bltu", "bgeu", "beqz", "bnez", "blez", "bgez", "bltz", "bgtz",
+    "ble", "bgt", "sb", "sh", "sw", "sd",
+];
+
+/// Returns true if `line` has nothing to prove and can be skipped inside a code block
+///
+/// Blank lines, comments (`#`), labels (`loop:`), assembler directives (`.align 2`) and the
+/// instructions in `IGNORABLE_OPS` are ignorable. Anything else, including loads and other
+/// instructions that write a register, is left to the gate parser.
+pub fn is_ignorable(line: &str) -> bool {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let Some(op) = line.split_whitespace().next() else {
+        return true;
+    };
+    op.ends_with(':') || op.starts_with('.') || IGNORABLE_OPS.contains(&op.to_lowercase().as_str())
+}
+
 /// Parses specified lines from an opcodes file and constructs a vector of Gate objects based on the parsed data
+///
+/// Reads the file and hands it to `parse_from_str`.
 pub fn parse_from_lines(line_file: Vec<usize>, opcodes_file: &PathBuf) -> Result<Vec<Gate>> {
+    let source = std::fs::read_to_string(opcodes_file).context("Failed to open opcodes file")?;
+    parse_from_str(&line_file, &source)
+}
+
+/// Parses the given 1-based `lines` of the assembly in `source` into gates, like
+/// `parse_from_lines` but without a file
+pub fn parse_from_str(lines: &[usize], source: &str) -> Result<Vec<Gate>> {
     let mut gates = Vec::new();
-    
-    // Iterate over each line number specified in line_file
-    for line_num in line_file {
-        let gates_file = open_file(opcodes_file).context("Failed to open opcodes file")?;
-        let line = gates_file.lines().nth(line_num - 1).ok_or_else(|| {
-            anyhow!("Line number {} is out of bounds in opcodes file", line_num)
-        })??;
-
-        let (operation, operands) = parse_line(&line, line_num)
+    let source_lines: Vec<&str> = source.lines().collect();
+
+    // Iterate over each line number specified in lines
+    for &line_num in lines {
+        let line = line_num
+            .checked_sub(1)
+            .and_then(|index| source_lines.get(index))
+            .ok_or_else(|| anyhow!("Line number {} is out of bounds in opcodes file", line_num))?;
+
+        // Let a code block cover a whole function, branches and stores included
+        if is_ignorable(line) {
+            println_dbg!("skipping line {}: {}", line_num, line);
+            continue;
+        }
+
+        let (operation, operands) = parse_line(line, line_num)
             .context(format!("Error parsing line {}: {}", line_num, line))?;
 
-        let gate_type = gate_type(operation);
+        let gate_type = Instructions::try_from(operation);
         if let Err(ref e) = gate_type {
             // Return Err
-            eprintln!("Error determining gate type for line {}: {}", line_num, e);
+            log::warn!("Error determining gate type for line {}: {}", line_num, e);
             continue;
         }
         let gate_type = gate_type.unwrap();
@@ -338,7 +411,8 @@ pub fn parse_from_lines(line_file: Vec<usize>, opcodes_file: &PathBuf) -> Result
             reg_data.1.into(),
             reg_data.2.into(),
             gate_type,
-        );
+        )
+        .at_line(line_num);
 
         println_dbg!("gate ==> {:?}", gate);
 
@@ -351,31 +425,6 @@ pub fn parse_from_lines(line_file: Vec<usize>, opcodes_file: &PathBuf) -> Result
     Ok(gates)
 }
 
-/// Determines the `GateType` based on the given operation string.
-///
-/// # Parameters
-/// - `op`: A reference to a string slice representing the operation to be parsed.
-///
-/// # Returns
-/// - `Ok(GateType)`: The corresponding `GateType` if the operation is recognized.
-/// - `Err(anyhow::Error)`: An error if the operation is not supported.
-///
-/// # Description
-/// The `gate_type` function matches the input operation string to predefined gate types.
-/// If the operation is recognized (e.g., `"mul"` or `"addi"`), the corresponding `GateType`
-/// is returned. If the operation is unrecognized, the function returns an error indicating
-/// that the operation is not supported.
-fn gate_type(op: &str) -> Result<Instructions> {
-    match op {
-        "addi" => Ok(Instructions::Addi),
-        "add" => Ok(Instructions::Add),
-        // "sub" => Ok(GateType::Sub),
-        "mul" => Ok(Instructions::Mul),
-        // "div" => Ok(GateType::Div),
-        _ => Err(anyhow!("operation is not support: {}", op)),
-    }
-}
-
 #[cfg(test)]
 mod parser_test {
     use super::*;
@@ -413,4 +462,61 @@ mod parser_test {
             assert_eq!(result, expected);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn instructions_try_from() {
+        assert_eq!(Instructions::try_from("add").unwrap(), Instructions::Add);
+        assert_eq!(Instructions::try_from("addi").unwrap(), Instructions::Addi);
+        assert_eq!(Instructions::try_from("mul").unwrap(), Instructions::Mul);
+        assert_eq!(Instructions::try_from("MUL").unwrap(), Instructions::Mul);
+
+        for op in ["sub", "div", "ld"] {
+            let err = Instructions::try_from(op).unwrap_err();
+            assert!(err.to_string().contains("not supported"), "{}", err);
+        }
+
+        let err = Instructions::try_from("xor").unwrap_err();
+        assert!(err.to_string().contains("unknown operation: xor"));
+    }
+
+    #[test]
+    fn parse_from_str_skips_ignorable() {
+        let program = "\
+loop:
+    addi    a0, a0, 5
+    nop
+    beq     a0, a1, done
+    mul     a1, a0, a2   # square
+    sd      a1, 8(sp)
+
+    .align 2
+    j       loop
+done:
+    add     a0, a1, a2
+    ret
+";
+        let lines: Vec<usize> = (1..=program.lines().count()).collect();
+        let gates = parse_from_str(&lines, program).unwrap();
+
+        let summary: Vec<_> = gates.iter().map(|g| (g.instr, g.des_reg, g.reg_left, g.reg_right)).collect();
+        assert_eq!(
+            summary,
+            [
+                (Instructions::Addi, RiscvReg::A0, RiscvReg::A0, RiscvReg::Zero),
+                (Instructions::Mul, RiscvReg::A1, RiscvReg::A0, RiscvReg::A2),
+                (Instructions::Add, RiscvReg::A0, RiscvReg::A1, RiscvReg::A2),
+            ]
+        );
+        assert_eq!(gates[0].val_right, Some(5));
+        let lines: Vec<_> = gates.iter().map(|g| g.source_line).collect();
+        assert_eq!(lines, [2, 5, 11]);
+
+        assert!(!is_ignorable("ld a1, 0(sp)"));
+        assert!(!is_ignorable("MUL a1, a0, a2"));
+        assert!(is_ignorable("  BNEZ a0, loop"));
+
+        let err = parse_from_str(&[13], program).unwrap_err();
+        assert_eq!(err.to_string(), "Line number 13 is out of bounds in opcodes file");
+        assert!(parse_from_str(&[0], program).is_err());
+    }
+}
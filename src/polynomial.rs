@@ -36,11 +36,50 @@ pub enum Term<N> {
     Term(N, usize),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A polynomial over a prime field, with coefficients stored from the highest degree down.
+///
+/// Equality, ordering and hashing ignore leading zero coefficients, so `[0, 0, 1, 2]`
+/// and `[1, 2]` are the same polynomial whether or not either side was trimmed.
+#[derive(Debug, Clone)]
 pub struct FPoly {
     pub terms: Vec<u64>,
 }
 
+impl FPoly {
+    /// Coefficients without the leading zeros (empty for the zero polynomial)
+    fn significant_terms(&self) -> &[u64] {
+        &self.terms[first_nonzero_index(&self.terms)..]
+    }
+}
+
+impl PartialEq for FPoly {
+    fn eq(&self, other: &Self) -> bool {
+        self.significant_terms() == other.significant_terms()
+    }
+}
+
+impl Eq for FPoly {}
+
+impl std::hash::Hash for FPoly {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.significant_terms().hash(state);
+    }
+}
+
+impl PartialOrd for FPoly {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FPoly {
+    // Orders by degree first, then by coefficients from the highest degree down
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let (lhs, rhs) = (self.significant_terms(), other.significant_terms());
+        lhs.len().cmp(&rhs.len()).then_with(|| lhs.cmp(rhs))
+    }
+}
+
 impl FPoly {
     /// Create a new polynomial with the given terms
     pub fn new(terms: Vec<u64>) -> Self {
@@ -66,6 +105,13 @@ impl FPoly {
         Self { terms: vec![1, 0] }
     }
 
+    /// Create the polynomial `coeff * x^degree`
+    pub fn monomial(coeff: u64, degree: usize) -> Self {
+        let mut terms = vec![0; degree + 1];
+        terms[0] = coeff;
+        Self { terms }
+    }
+
     /// Get the degree of the polynomial
     pub fn degree(&self) -> usize {
         let index = first_nonzero_index(&self.terms);
@@ -87,17 +133,61 @@ impl FPoly {
         self.terms[index] += coeff;
     }
 
-    /// Evaluate the polynomial at a given value of x
+    /// Raise the polynomial to the power `exp` by repeated squaring, with coefficients modulo `p`
+    pub fn pow(&self, exp: usize, p: u64) -> FPoly {
+        if exp > 0 && self.is_zero() {
+            return FPoly::zero();
+        }
+
+        let mut result = FPoly::one();
+        let mut base = self.clone();
+        let mut exp = exp;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = poly_fmath::mul(&result, &base, p);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = poly_fmath::mul(&base, &base, p);
+            }
+        }
+        result
+    }
+
+    /// Evaluate the polynomial at a given value of x (Horner's method)
     pub fn evaluate(&self, x: u64, p: u64) -> u64 {
+        self.terms
+            .iter()
+            .fold(0, |acc, &coeff| fmath::add(fmath::mul(acc, x, p), coeff % p, p))
+    }
+
+    /// Evaluate the polynomial at x, given the table `[1, x, x^2, ...]` from `poly_fmath::power_table`
+    ///
+    /// Building the table once lets many polynomials be evaluated at the same point
+    /// with a single multiplication per term.
+    pub fn evaluate_with_powers(&self, powers: &[u64], p: u64) -> u64 {
+        assert!(
+            powers.len() >= self.terms.len(),
+            "power table has {} entries, but the polynomial has {} terms",
+            powers.len(),
+            self.terms.len()
+        );
         self.terms
             .iter()
             .rev()
-            .enumerate()
-            .map(|(i, &coeff)| {
-                let term_x = fmath::pow(x, i.try_into().unwrap(), p);
-                fmath::mul(coeff, term_x, p)
-            })
-            .fold(0, |acc, x| fmath::add(acc, x, p))
+            .zip(powers)
+            .fold(0, |acc, (&coeff, &power)| fmath::add(acc, fmath::mul(coeff, power, p), p))
+    }
+
+    /// Returns the elements of `set` at which the polynomial is nonzero, in the order of `set`
+    ///
+    /// For a polynomial that should vanish on H, such as z^A(x) * z^B(x) - z^C(x),
+    /// each returned point names an R1CS constraint the witness violates.
+    pub fn nonvanishing_points(&self, set: &[u64], p: u64) -> Vec<u64> {
+        set.iter()
+            .copied()
+            .filter(|&x| self.evaluate(x, p) != 0)
+            .collect()
     }
 
     /// Trim leading zeros from the polynomial
@@ -108,6 +198,28 @@ impl FPoly {
         }
     }
 
+    /// Coefficients from the highest degree down, `[c_d, ..., c_1, c_0]`, without leading zeros
+    ///
+    /// This is the order `terms` is stored in; the zero polynomial gives an empty slice.
+    pub fn coeffs_descending(&self) -> &[u64] {
+        self.significant_terms()
+    }
+
+    /// Coefficients from the constant term up, `[c_0, c_1, ..., c_d]`, so index `i` holds the
+    /// coefficient of `x^i`; the zero polynomial gives an empty vector.
+    ///
+    /// This is the order polynomials are serialized in.
+    pub fn coeffs_ascending(&self) -> Vec<u64> {
+        self.significant_terms().iter().rev().copied().collect()
+    }
+
+    /// Builds a polynomial from coefficients in the order of `coeffs_ascending`, trimmed
+    pub fn from_coeffs_ascending(coeffs: &[u64]) -> Self {
+        let mut poly = Self::new(coeffs.iter().rev().copied().collect());
+        poly.trim();
+        poly
+    }
+
     /// Check if the polynomial is zero
     pub fn is_zero(&self) -> bool {
         self.degree() == 0
@@ -123,6 +235,33 @@ impl FPoly {
     }
 }
 
+/// Formats `poly` as `c0 + c1·x + c2·x^2 + ...`, skipping zero coefficients
+///
+/// The terms are listed lowest degree first when `ascending` is set, highest degree
+/// first otherwise; the zero polynomial is formatted as `0`.
+pub fn format_poly(poly: &FPoly, ascending: bool) -> String {
+    let deg = poly.terms.len().saturating_sub(1);
+    let mut terms: Vec<String> = poly
+        .terms
+        .iter()
+        .enumerate()
+        .filter(|(_, &coeff)| coeff != 0)
+        .map(|(i, &coeff)| match deg - i {
+            0 => coeff.to_string(),
+            1 => format!("{}·x", coeff),
+            exp => format!("{}·x^{}", coeff, exp),
+        })
+        .collect();
+
+    if terms.is_empty() {
+        return "0".to_string();
+    }
+    if ascending {
+        terms.reverse();
+    }
+    terms.join(" + ")
+}
+
 impl std::fmt::Display for FPoly {
     // Format the polynomial for display
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -154,9 +293,22 @@ impl std::fmt::Display for FPoly {
 
 #[macro_use]
 pub mod poly_fmath {
+    use anyhow::{bail, Result};
+
     use super::{FPoly, Term};
     use crate::field::fmath;
 
+    /// Returns `[1, x, x^2, ..., x^max_deg]` for `FPoly::evaluate_with_powers`
+    pub fn power_table(x: u64, max_deg: usize, p: u64) -> Vec<u64> {
+        let mut powers = Vec::with_capacity(max_deg + 1);
+        let mut current = 1 % p;
+        for _ in 0..=max_deg {
+            powers.push(current);
+            current = fmath::mul(current, x, p);
+        }
+        powers
+    }
+
     pub fn add(a: &FPoly, b: &FPoly, p: u64) -> FPoly {
         let (mut terms, small) = if b.terms.len() > a.terms.len() {
             (b.terms.clone(), &a.terms)
@@ -165,10 +317,7 @@ pub mod poly_fmath {
         };
 
         let offset = terms.len() - small.len();
-
-        for (index, &val) in terms[offset..].iter_mut().zip(small) {
-            *index = fmath::add(*index, val, p);
-        }
+        fmath::add_slice(&mut terms[offset..], small, p);
 
         FPoly::new(terms)
     }
@@ -176,28 +325,16 @@ pub mod poly_fmath {
     pub fn sub(a: &FPoly, b: &FPoly, p: u64) -> FPoly {
         let max_degree = std::cmp::max(a.terms.len(), b.terms.len());
 
-        // Prepare the result vector
-        let mut result_terms = vec![0; max_degree];
-
         // Align the terms from the right (highest degree)
         let a_offset = max_degree - a.terms.len();
         let b_offset = max_degree - b.terms.len();
 
-        for i in 0..max_degree {
-            let ai = if i >= a_offset {
-                a.terms[i - a_offset]
-            } else {
-                0
-            };
-            let bi = if i >= b_offset {
-                b.terms[i - b_offset]
-            } else {
-                0
-            };
-
-            // Perform subtraction modulo p
-            result_terms[i] = fmath::sub(ai, bi, p);
-        }
+        let mut result_terms = vec![0; max_degree];
+        result_terms[a_offset..].copy_from_slice(&a.terms);
+
+        // Terms above the degree of b are only reduced
+        result_terms[..b_offset].iter_mut().for_each(|t| *t %= p);
+        fmath::sub_slice(&mut result_terms[b_offset..], &b.terms, p);
 
         FPoly {
             terms: result_terms,
@@ -254,11 +391,12 @@ pub mod poly_fmath {
             for i in (0..n).step_by(length) {
                 let mut w = 1;
                 for j in 0..length / 2 {
+                    // fmath::mul widens to u128, so the product can't overflow for primes up to 63 bits
                     let u = poly[i + j];
-                    let v = (poly[i + j + length / 2] * w) % p;
-                    poly[i + j] = (u + v) % p;
-                    poly[i + j + length / 2] = (u + p - v) % p;
-                    w = (w * w_len) % p;
+                    let v = fmath::mul(poly[i + j + length / 2], w, p);
+                    poly[i + j] = fmath::add(u, v, p);
+                    poly[i + j + length / 2] = fmath::sub(u, v, p);
+                    w = fmath::mul(w, w_len, p);
                 }
             }
             length *= 2;
@@ -281,8 +419,11 @@ pub mod poly_fmath {
     /// - Performs NTT on both polynomials.
     /// - Multiplies the transformed coefficients element-wise.
     /// - Applies the inverse NTT and rescales the coefficients by `1/n`.
-    fn mul_ntt(a: FPoly, b: FPoly, p: u64, root: u64) -> FPoly {
-        let len = a.degree() + b.degree() - 1;
+    ///
+    /// `root` must be a primitive root modulo `p`, and `p - 1` must be divisible by the
+    /// transform size, the smallest power of two holding the product.
+    pub fn mul_ntt(a: FPoly, b: FPoly, p: u64, root: u64) -> FPoly {
+        let len = a.terms.len() + b.terms.len() - 1;
         let n = len.next_power_of_two();
 
         let mut a = a.terms;
@@ -295,16 +436,26 @@ pub mod poly_fmath {
 
         let mut result = vec![0; n];
         for i in 0..n {
-            result[i] = (a[i] * b[i]) % p;
+            result[i] = fmath::mul(a[i], b[i], p);
         }
 
         let inv_n = fmath::pow(n as u64, p - 2, p);
         ntt(&mut result, n, fmath::pow(root, p - 2, p), p);
-        result.iter_mut().for_each(|x| *x = (*x * inv_n) % p);
+        result.iter_mut().for_each(|x| *x = fmath::mul(*x, inv_n, p));
 
         FPoly::new(result.into_iter().take(len).collect())
     }
 
+    /// Divides `a` by `b`, returning `(quotient, remainder)`, or an error if `b` is zero
+    ///
+    /// Use this instead of `div` when the divisor comes from class data or a proof.
+    pub fn try_div(a: &FPoly, b: &FPoly, p: u64) -> Result<(FPoly, FPoly)> {
+        if let Term::ZeroTerm = first_term(&b.terms) {
+            bail!("can't divide a polynomial by the zero polynomial");
+        }
+        Ok(div(a, b, p))
+    }
+
     pub fn div(a: &FPoly, b: &FPoly, p: u64) -> (FPoly, FPoly) {
         let zero = 0;
 
@@ -457,6 +608,24 @@ mod tests {
         assert_eq!(result.terms, vec![9, 9, 6, 6, 7, 0]);
     }
 
+    #[test]
+    fn test_eq_ignores_leading_zeros() {
+        use std::collections::HashSet;
+
+        let poly1 = FPoly::new(vec![1, 2, 3]);
+        let poly2 = FPoly::new(vec![0, 0, 1, 2, 3]);
+        let poly3 = FPoly::new(vec![1, 2, 4]);
+
+        assert_eq!(poly1, poly2);
+        assert_ne!(poly1, poly3);
+        assert_eq!(FPoly::zero(), FPoly::new(vec![0, 0, 0]));
+        assert!(poly1 < poly3);
+        assert!(FPoly::new(vec![0, 9]) < poly2);
+
+        let set: HashSet<FPoly> = [poly1, poly2, poly3].into_iter().collect();
+        assert_eq!(set.len(), 2);
+    }
+
     #[test]
     fn test_eval() {
         let poly1 = FPoly::new(vec![10, 70, 12, 220, 133, 112, 512, 150]);
@@ -467,6 +636,29 @@ mod tests {
         assert_eq!(poly1.evaluate(0, 11), 7);
     }
 
+    #[test]
+    fn test_evaluate_with_powers() {
+        const P: u64 = 1678321;
+        let polys = [
+            FPoly::new(vec![10, 70, 12, 220, 133, 112, 512, 150]),
+            FPoly::new(vec![0, 0, 5, 0, 1]),
+            FPoly::new((1..60).map(|i| i * 7919).collect()),
+            FPoly::zero(),
+        ];
+
+        for x in [0, 1, 2, 361480, P - 1] {
+            let powers = power_table(x, 58, P);
+            assert_eq!(powers.len(), 59);
+            for poly in &polys {
+                assert_eq!(poly.evaluate_with_powers(&powers, P), poly.evaluate(x, P));
+            }
+        }
+
+        // A table longer than needed works for small polynomials, with unreduced coefficients too
+        let powers = power_table(191, 10, 181);
+        assert_eq!(polys[0].evaluate_with_powers(&powers, 181), 154);
+    }
+
     #[test]
     fn test_degree() {
         let poly1 = FPoly::new(vec![1, 2, 4]);
@@ -530,4 +722,99 @@ mod tests {
 
         assert_eq!(vec![3, 5, 7], div(&poly2, &poly1, 11).1.terms);
     }
+
+    #[test]
+    fn test_try_div() {
+        let poly1 = FPoly::new(vec![1, 5, 6, 9]);
+        let poly2 = FPoly::new(vec![2, 7, 11, 5, 24]);
+
+        assert_eq!(div(&poly2, &poly1, 11), try_div(&poly2, &poly1, 11).unwrap());
+
+        let err = try_div(&poly1, &FPoly::zero(), 11).unwrap_err();
+        assert_eq!(err.to_string(), "can't divide a polynomial by the zero polynomial");
+        assert!(try_div(&poly1, &FPoly::new(vec![0, 0, 0]), 11).is_err());
+    }
+
+    #[test]
+    fn test_coeffs_order() {
+        // 3x^3 + 2x + 7, with a leading zero
+        let poly = FPoly::new(vec![0, 3, 0, 2, 7]);
+
+        assert_eq!(poly.coeffs_descending(), [3, 0, 2, 7]);
+        assert_eq!(poly.coeffs_ascending(), [7, 2, 0, 3]);
+        assert_eq!(poly.coeffs_ascending()[3], poly.get_term(3));
+        assert_eq!(FPoly::from_coeffs_ascending(&[7, 2, 0, 3, 0, 0]), poly);
+
+        assert!(FPoly::zero().coeffs_descending().is_empty());
+        assert!(FPoly::zero().coeffs_ascending().is_empty());
+    }
+
+    #[test]
+    fn test_nonvanishing_points() {
+        let p = 11;
+        let set = [1, 2, 3, 4, 5];
+
+        // (x - 1)(x - 3) = x^2 - 4x + 3 vanishes on {1, 3} only
+        let poly = mul(&FPoly::new(vec![1, p - 1]), &FPoly::new(vec![1, p - 3]), p);
+        assert_eq!(poly.terms, vec![1, 7, 3]);
+        assert_eq!(poly.nonvanishing_points(&set, p), vec![2, 4, 5]);
+
+        assert!(crate::math::vanishing_poly(&set.to_vec(), p).nonvanishing_points(&set, p).is_empty());
+        assert_eq!(FPoly::one().nonvanishing_points(&set, p), set.to_vec());
+    }
+
+    #[test]
+    fn test_mul_ntt_large_prime() {
+        // 2^20 * 1048581 + 1, just above 2^40, with primitive root 10
+        let p: u64 = 1_099_516_870_657;
+        let root = 10;
+
+        let a: Vec<u64> = (0..37u64).map(|i| p - 1 - i * 1_234_567).collect();
+        let b: Vec<u64> = (0..20u64).map(|i| (i + 1) * 987_654_321_013 % p).collect();
+
+        // Schoolbook reference in u128
+        let mut expected = vec![0u128; a.len() + b.len() - 1];
+        for (i, &x) in a.iter().enumerate() {
+            for (j, &y) in b.iter().enumerate() {
+                expected[i + j] = (expected[i + j] + x as u128 * y as u128) % p as u128;
+            }
+        }
+        let expected: Vec<u64> = expected.into_iter().map(|x| x as u64).collect();
+
+        let result = mul_ntt(FPoly::new(a.clone()), FPoly::new(b.clone()), p, root);
+        assert_eq!(result.terms, expected);
+        assert_eq!(result, mul(&FPoly::new(a), &FPoly::new(b), p));
+    }
+
+    #[test]
+    fn test_monomial_and_pow() {
+        let p = 11;
+
+        // x^n - 1, as the vanishing polynomials used to be built
+        let mut vanishing = fpoly!(p - 1);
+        vanishing.add_term(1, 8);
+        assert_eq!(sub(&FPoly::monomial(1, 8), &FPoly::one(), p), vanishing);
+        assert_eq!(FPoly::monomial(3, 2), fpoly!(3, 0, 0));
+
+        // (x + 1)^2 = x^2 + 2x + 1
+        let x_plus_one = fpoly!(1, 1);
+        assert_eq!(x_plus_one.pow(2, p), fpoly!(1, 2, 1));
+        assert_eq!(x_plus_one.pow(5, p), poly_mul_many!(p, &x_plus_one.pow(2, p), &x_plus_one.pow(3, p)));
+        assert_eq!(fpoly!(3, 5).pow(1, p), fpoly!(3, 5));
+
+        assert_eq!(x_plus_one.pow(0, p), FPoly::one());
+        assert_eq!(FPoly::zero().pow(0, p), FPoly::one());
+        assert_eq!(FPoly::zero().pow(3, p), FPoly::zero());
+    }
+
+    #[test]
+    fn test_format_poly() {
+        // 5x^4 + 3x^2 + 2, with zeros at x^3, x^1 and a leading zero term
+        let poly = FPoly::new(vec![0, 5, 0, 3, 0, 2]);
+
+        assert_eq!(format_poly(&poly, true), "2 + 3·x^2 + 5·x^4");
+        assert_eq!(format_poly(&poly, false), "5·x^4 + 3·x^2 + 2");
+        assert_eq!(format_poly(&FPoly::new(vec![7, 0]), true), "7·x");
+        assert_eq!(format_poly(&FPoly::zero(), false), "0");
+    }
 }
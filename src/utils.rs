@@ -14,12 +14,15 @@
 
 //! Utility functions and structures for gate definitions, matrix operations, and polynomial encoding.
 
+use anyhow::anyhow;
 use anyhow::Result;
 use rand::thread_rng;
 use rand::Rng;
 use sha2::Digest;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
 
 use crate::define_get_points_fn;
 use crate::get_val;
@@ -89,32 +92,33 @@ pub fn get_points_set(seq: &[u64], n: &[u64]) -> Vec<Point> {
     points
 }
 
-// /// Converts a column vector matrix to a vector of field elements.
-// ///
-// /// # Parameters
-// /// - `mat`: A matrix of field elements with a single column and multiple rows.
-// ///
-// /// # Returns
-// /// Returns a vector of `u64` elements, where each element is extracted from the column of the matrix.
-// ///
-// /// # Description
-// /// This function takes a matrix with a single column and converts it into a vector of field elements.
-// /// It iterates over the rows of the matrix, extracting each element from the single column and adding
-// /// it to the resulting vector.
-// ///
-// /// # Panics
-// /// Panics if the number of columns in the matrix is not equal to 1. The function assumes that the matrix
-// /// is a column vector with exactly one column.
-// pub fn mat_to_vec(mat: &DVector<u64>) -> Vec<u64> {
-//     assert!(mat.ncols() == 1, "cannot convet to vec mat.ncols() == 1");
-
-//     let mut v = vec![];
-
-//     for i in 0..mat.nrows() {
-//         v.push(mat[(i, 0)]);
-//     }
-//     v
-// }
+/// Extracts one column of a matrix as a vector of field elements.
+///
+/// # Parameters
+/// - `mat`: The matrix to read from; it does not have to be square.
+/// - `col`: Index of the column to extract.
+///
+/// # Returns
+/// Returns the elements of column `col`, from the first row down, or an error if
+/// `col` is not smaller than the number of columns of `mat`.
+pub fn column_to_vec(mat: &FMatrix, col: usize) -> Result<Vec<u64>> {
+    if col >= mat.ncols() {
+        return Err(anyhow!(
+            "column {} is out of range for a matrix with {} columns",
+            col,
+            mat.ncols()
+        ));
+    }
+
+    Ok(mat.data.iter().map(|row| row[col]).collect())
+}
+
+/// Converts a column vector matrix to a vector of field elements.
+///
+/// This is `column_to_vec(mat, 0)`, panicking if the matrix has no columns.
+pub fn mat_to_vec(mat: &FMatrix) -> Vec<u64> {
+    column_to_vec(mat, 0).expect("cannot convert an empty matrix to vec")
+}
 
 /// Converts a vector of `u64` elements into a `HashSet` of `u64`.
 ///
@@ -180,6 +184,91 @@ pub fn push_random_points(points: &mut Vec<Point>, b: u64, set_h: &HashSet<u64>,
     }
 }
 
+/// Generates a random field element not present in `set`, giving up after a bounded number of draws.
+///
+/// # Parameters
+/// - `set`: Field elements that must be excluded from the selection.
+/// - `p`: The field modulus; values are drawn from `0..p`.
+/// - `rng`: The random number generator to draw from.
+/// - `max_retries`: The maximum number of draws before giving up.
+///
+/// # Returns
+/// A field element not in `set`, or an error if every element of the field is excluded
+/// or no free element was drawn within `max_retries` attempts.
+pub fn gen_rand_not_in_set_with(
+    set: &HashSet<u64>,
+    p: u64,
+    rng: &mut impl Rng,
+    max_retries: usize,
+) -> Result<u64> {
+    // Count the exclusions that actually lie inside the field
+    let excluded = set.iter().filter(|&&v| v < p).count() as u64;
+    if excluded >= p {
+        return Err(anyhow!("no field element outside the set is available (P = {})", p));
+    }
+
+    for _ in 0..max_retries {
+        let num = rng.gen_range(0..p);
+        if !set.contains(&num) {
+            return Ok(num);
+        }
+    }
+
+    Err(anyhow!(
+        "failed to find a field element outside the set after {} retries (P = {}, {} excluded)",
+        max_retries,
+        p,
+        excluded
+    ))
+}
+
+/// Adds `b` random points whose x-coordinates are distinct and outside `set_h`.
+///
+/// # Parameters
+/// - `points`: The vector the generated `(x, y)` points are appended to.
+/// - `b`: The number of random points to generate.
+/// - `set_h`: Field elements the x-coordinates must avoid.
+/// - `p`: The field modulus.
+/// - `rng`: The random number generator to draw from.
+/// - `max_retries`: The maximum number of draws allowed for each x-coordinate.
+///
+/// # Description
+/// Unlike `push_random_points`, this checks up front that the field has at least `b` free
+/// elements left and bounds the search for each one, so a saturated `set_h` results in an
+/// error instead of an endless loop. Nothing is pushed if an error is returned.
+pub fn push_random_points_with(
+    points: &mut Vec<Point>,
+    b: u64,
+    set_h: &HashSet<u64>,
+    p: u64,
+    rng: &mut impl Rng,
+    max_retries: usize,
+) -> Result<()> {
+    let excluded = set_h.iter().filter(|&&v| v < p).count() as u64;
+    let free = p - excluded.min(p);
+    if free < b {
+        return Err(anyhow!(
+            "cannot pick {} random points: only {} field elements are outside set_h (P = {})",
+            b,
+            free,
+            p
+        ));
+    }
+
+    // Keep the new x-coordinates distinct from each other as well
+    let mut used = set_h.clone();
+    let mut new_points = Vec::with_capacity(b as usize);
+    for _ in 0..b {
+        let domain = gen_rand_not_in_set_with(&used, p, rng, max_retries)?;
+        let range = rng.gen_range(0..p);
+        used.insert(domain);
+        new_points.push((domain, range));
+    }
+
+    points.extend(new_points);
+    Ok(())
+}
+
 /// Generates a random number based on a given polynomial and a set of existing values.
 ///
 /// # Parameters
@@ -225,33 +314,37 @@ pub fn poly_gen_randomly(deg: usize, p: u64) -> FPoly {
     FPoly::new(poly)
 }
 
-/// Adds random points to the given `points` HashMap by pairing each element in `set_k`
-/// (starting from index `c`) with a randomly chosen element from `set_h`.
-/// Returns an error if a random element cannot be chosen from `set_h`.
+/// Pads `points` by pairing each element in `set_k` (starting from index `c`) with an
+/// element of `set_h`.
+///
+/// The padding is deterministic: `set_k[i]` is paired with `set_h[i % set_h.len()]`, so
+/// the same circuit always commits to the same row and column polynomials and a device
+/// can be compared against a reference commitment. This costs no zero knowledge, since
+/// the matrices are public and `val` is zero at every padded point.
 ///
 /// # Arguments
 ///
 /// * `points` - A mutable HashMap where new points will be added, with keys from `set_k`
-///   and values chosen randomly from `set_h`.
+///   and values taken from `set_h`.
 /// * `c` - The starting index in `set_k` from which to begin adding points.
 /// * `set_h` - A slice of values used to pair with elements from `set_k`.
 /// * `set_k` - A slice of values used to generate the new points.
 ///
 /// # Returns
 ///
-/// A `Result<()>` indicating success or failure. If successful, it returns `Ok(())`.
-/// If an error occurs while choosing a random element from `set_h`, it returns an error.
+/// A `Result<()>` indicating success or failure. Fails if points are needed but `set_h`
+/// is empty.
 pub fn add_random_points(
     points: &mut HashMap<u64, u64>,
     c: usize,
     set_h: &[u64],
     set_k: &[u64],
 ) -> Result<()> {
-    let mut rng = rand::thread_rng();
+    if set_h.is_empty() && c < set_k.len() {
+        return Err(anyhow!("Failed to pad the points: set_h is empty"));
+    }
 
     for i in c..set_k.len() {
-        // TODO:
-        // let rand_h = set_h.choose(&mut rng).ok_or(anyhow!("Failed to choose a random element from set_h"))?;
         let rand_h = &set_h[i % set_h.len()];
         println_dbg!("r: ({}, {})", set_k[i], *rand_h);
         points.insert(set_k[i], *rand_h);
@@ -354,19 +447,10 @@ macro_rules! define_get_points_fn {
     ($name:ident, $mode:ident) => {
         #[allow(unused_variables)]
         pub fn $name(mat: &FMatrix, h: &[u64], k: &[u64]) -> Vec<(u64, u64)> {
-            let mut points: Vec<(u64, u64)> = vec![];
-            let mut c = 0;
-
-            for i in 0..mat.size() {
-                for j in 0..mat.size() {
-                    if mat[(i, j)] != 0 {
-                        let value = get_val!($mode, h, mat, i, j);
-                        points.push((k[c], value));
-                        c += 1;
-                    }
-                }
-            }
-            points
+            mat.iter_nonzero()
+                .enumerate()
+                .map(|(c, (i, j, _))| (k[c], get_val!($mode, h, mat, i, j)))
+                .collect()
         }
     };
 }
@@ -398,8 +482,16 @@ pub fn sha2_hash(input: &str) -> String {
 /// # Returns
 /// A `u32` value representing the lower 32 bits of the SHA-256 hash.
 pub fn sha2_hash_lower_32bit(input: &str) -> u64 {
+    sha2_hash_lower_32bit_parts(&[input.as_bytes()])
+}
+
+/// Computes the SHA-256 hash of `parts` fed one after the other, i.e. of their
+/// concatenation, and returns its lower 32 bits.
+pub fn sha2_hash_lower_32bit_parts(parts: &[&[u8]]) -> u64 {
     let mut hasher = sha2::Sha256::new();
-    hasher.update(input);
+    for part in parts {
+        hasher.update(part);
+    }
     let result = hasher.finalize();
     let res = u32::from_le_bytes([
         result[31], result[30], result[29], result[28],
@@ -408,6 +500,16 @@ pub fn sha2_hash_lower_32bit(input: &str) -> u64 {
 }
 
 
+/// Runs `f`, measuring its duration only when `enabled`
+pub(crate) fn timed<T>(enabled: bool, f: impl FnOnce() -> T) -> (T, Duration) {
+    if !enabled {
+        return (f(), Duration::ZERO);
+    }
+    let start = Instant::now();
+    let res = f();
+    (res, start.elapsed())
+}
+
 /// Reads a JSON file and deserializes its contents into a specified type.
 ///
 /// # Parameters
@@ -430,11 +532,40 @@ pub fn read_json_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
     Ok(setup_json)
 }
 
+/// Serializes `value` to pretty-printed JSON with the keys of every object sorted
+///
+/// The output only depends on the data, not on the field order of the structs, so it can
+/// be compared byte for byte against a checked-in golden file.
+pub fn to_canonical_json<T: serde::Serialize>(value: &T) -> Result<String> {
+    // `serde_json::Map` is a `BTreeMap`, so going through a `Value` sorts the keys
+    let value = serde_json::to_value(value)?;
+    Ok(serde_json::to_string_pretty(&value)? + "\n")
+}
+
+/// Same as `read_json_file`, but points at the offending field when the file doesn't match `T`.
+///
+/// Meant for files users edit by hand, such as the device configuration: the error names the file,
+/// the path of the field that failed (e.g. `code_block[1]`) and what was expected there.
+pub fn read_json_file_validated<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let reader = crate::json_file::open_file(&std::path::PathBuf::from(path))?;
+    let deserializer = &mut serde_json::Deserializer::from_reader(reader);
 
-/// A macro for printing formatted output to the standard output stream.
+    serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let field = err.path().to_string();
+        let inner = err.into_inner();
+        if field == "." {
+            anyhow!("{}: {}; check that the file holds a complete JSON object", path, inner)
+        } else {
+            anyhow!("{}: field `{}`: {}; fix the value of `{}` in the file", path, field, inner, field)
+        }
+    })
+}
+
+
+/// A macro for emitting formatted debugging output.
 ///
-/// This macro behaves like `print!`, but is intended for debugging purposes. 
-/// It will only execute when the `debug_assertions` configuration is enabled.
+/// This macro takes the same arguments as `print!`, and forwards the message to
+/// `log::debug!`, so it is shown when the logger enables the debug level (e.g. `RUST_LOG=debug`).
 ///
 /// # Parameters
 /// - `$fmt`: A format string that specifies how to format the output.
@@ -449,16 +580,15 @@ pub fn read_json_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
 #[macro_export]
 macro_rules! print_dbg {
     ($fmt:expr $(, $arg:expr)*) => {
-        #[cfg(debug_assertions)]
-        print!("{}", format_args!($fmt $(, $arg)*));
+        $crate::log::debug!("{}", format_args!($fmt $(, $arg)*))
     }
 }
 
 
-/// A macro for printing formatted output followed by a newline to the standard output stream.
+/// A macro for emitting a formatted debugging message.
 ///
-/// This macro behaves like `println!`, but is intended for debugging purposes. 
-/// It will only execute when the `debug_assertions` configuration is enabled.
+/// This macro takes the same arguments as `println!`, and forwards the message to
+/// `log::debug!`, so it is shown when the logger enables the debug level (e.g. `RUST_LOG=debug`).
 ///
 /// # Parameters
 /// - `$fmt`: A format string that specifies how to format the output.
@@ -473,11 +603,115 @@ macro_rules! print_dbg {
 #[macro_export]
 macro_rules! println_dbg {
     () => {
-        #[cfg(debug_assertions)]
-        println!()
+        $crate::log::debug!("")
     };
     ($fmt:expr $(, $arg:expr)*) => {
-        #[cfg(debug_assertions)]
-        println!("{}", format_args!($fmt $(, $arg)*));
+        $crate::log::debug!("{}", format_args!($fmt $(, $arg)*))
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn column_to_vec_non_square() {
+        let mat = FMatrix::new(vec![
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+        ]);
+
+        assert_eq!(column_to_vec(&mat, 1).unwrap(), vec![2, 5]);
+        assert_eq!(mat_to_vec(&mat), vec![1, 4]);
+
+        let err = column_to_vec(&mat, 3).unwrap_err();
+        assert!(err.to_string().contains("column 3 is out of range"), "{}", err);
+    }
+
+    #[test]
+    fn push_random_points_with_fresh_domain() {
+        const P: u64 = 181;
+        let set_h: HashSet<u64> = (0..170).collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut points = vec![];
+
+        push_random_points_with(&mut points, 5, &set_h, P, &mut rng, 10_000).unwrap();
+
+        assert_eq!(points.len(), 5);
+        let xs: HashSet<u64> = points.iter().map(|(x, _)| *x).collect();
+        assert_eq!(xs.len(), 5);
+        assert!(points.iter().all(|(x, y)| *x < P && *y < P && !set_h.contains(x)));
+    }
+
+    #[test]
+    fn push_random_points_with_saturated_field() {
+        const P: u64 = 181;
+        // Only 180 is left outside set_h
+        let set_h: HashSet<u64> = (0..180).collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut points = vec![];
+
+        let err = push_random_points_with(&mut points, 2, &set_h, P, &mut rng, 10_000).unwrap_err();
+        assert!(err.to_string().contains("only 1 field elements"), "{}", err);
+        assert!(points.is_empty());
+
+        let err = gen_rand_not_in_set_with(&set_h, P, &mut rng, 0).unwrap_err();
+        assert!(err.to_string().contains("after 0 retries"), "{}", err);
+
+        let full: HashSet<u64> = (0..P).collect();
+        assert!(gen_rand_not_in_set_with(&full, P, &mut rng, 10_000).is_err());
+    }
+
+    #[test]
+    fn read_json_file_validated_names_the_field() {
+        use crate::json_file::DeviceConfigJson;
+
+        let path = std::env::temp_dir().join(format!("zkiot_device_config_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let config = r#"{
+            "class": 2,
+            "iot_developer_name": "Fidesinnova",
+            "iot_device_name": "zk-MultiSensor",
+            "device_hardware_version": "1.0",
+            "firmware_version": "1.0",
+            "code_block": [1, 4]
+        }"#;
+
+        std::fs::write(path, config.replace("[1, 4]", "[1, \"4\"]")).unwrap();
+        let err = read_json_file_validated::<DeviceConfigJson>(path).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}: field `code_block`", path)), "{}", err);
+
+        std::fs::write(path, config.replace("\"class\": 2", "\"class\": \"two\"")).unwrap();
+        let err = read_json_file_validated::<DeviceConfigJson>(path).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}: field `class`: invalid type: string \"two\", expected u8", path)), "{}", err);
+
+        std::fs::write(path, config.replace("\"class\": 2,", "")).unwrap();
+        let err = read_json_file_validated::<DeviceConfigJson>(path).unwrap_err().to_string();
+        assert!(err.contains("missing field `class`"), "{}", err);
+
+        std::fs::write(path, config).unwrap();
+        assert_eq!(read_json_file_validated::<DeviceConfigJson>(path).unwrap().class, 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sha2_hash_lower_32bit_encodings() {
+        // The decimal text and the 8 big-endian bytes of a value hash differently
+        assert_eq!(sha2_hash_lower_32bit("1678321"), 924796516);
+        assert_eq!(sha2_hash_lower_32bit_parts(&[&1678321u64.to_be_bytes()]), 3989645028);
+
+        // Fixed outputs: the byte encoding doesn't depend on the platform
+        assert_eq!(sha2_hash_lower_32bit_parts(&[&0u64.to_be_bytes()]), 3773316604);
+        assert_eq!(sha2_hash_lower_32bit_parts(&[&1u64.to_be_bytes()]), 2211449424);
+        assert_eq!(sha2_hash_lower_32bit_parts(&[&u64::MAX.to_be_bytes()]), 3148673226);
+
+        // Hashing in parts is hashing the concatenation
+        assert_eq!(
+            sha2_hash_lower_32bit_parts(&[b"alpha", b":", &7u64.to_be_bytes()]),
+            sha2_hash_lower_32bit_parts(&[b"alpha:\0\0\0\0\0\0\0\x07"])
+        );
     }
 }
@@ -0,0 +1,4 @@
+addi    a0, a0, 5
+mul     a1, a0, a2
+addi    a2, a1, 9
+add     a0, a1, a2
@@ -0,0 +1,163 @@
+// Copyright 2024 Fidesinnova, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs setup, commitment, proof and verification on the sample program in
+//! `tests/fixtures`, passing every stage's output through its file on disk.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use zk_iot::ahp::commitment_generation::Commitment;
+use zk_iot::ahp::proof_generation::ProofGeneration;
+use zk_iot::ahp::proof_generation::ProofGenerationJson;
+use zk_iot::ahp::proof_verification::LightVerifier;
+use zk_iot::ahp::setup::Setup;
+use zk_iot::field::fmath;
+use zk_iot::json_file::ClassDataJson;
+use zk_iot::json_file::DeviceConfigJson;
+use zk_iot::json_file::ProgramParamsJson;
+use zk_iot::parser::parse_from_lines;
+use zk_iot::parser::Gate;
+use zk_iot::parser::Instructions;
+use zk_iot::parser::RiscvReg;
+use zk_iot::utils::read_json_file;
+
+const CLASS_TABLE: &str = "class.json";
+const PROGRAM_PATH: &str = "tests/fixtures/sample.s";
+const DEVICE_CONFIG_PATH: &str = "tests/fixtures/device_config.json";
+
+/// Stage outputs of one run, all stored in a private temporary directory
+struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("zkiot_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Executes `gates` the way the device would and returns z = [1, registers, gate outputs]
+fn execute(gates: &[Gate], n_i: usize, p: u64) -> Vec<u64> {
+    let mut z_vec = vec![1];
+    z_vec.extend((1..=n_i as u64).map(|i| (11 * i + 4) % p));
+
+    let mut regs_data: HashMap<RiscvReg, usize> = HashMap::new();
+    for (counter, gate) in gates.iter().enumerate() {
+        let value_of = |reg: RiscvReg| z_vec[*regs_data.get(&reg).unwrap_or(&(reg as usize + 1))];
+        let left = gate.val_left.unwrap_or_else(|| value_of(gate.reg_left));
+        let right = gate.val_right.unwrap_or_else(|| value_of(gate.reg_right));
+
+        z_vec.push(match gate.instr {
+            Instructions::Add | Instructions::Addi => fmath::add(left, right, p),
+            Instructions::Mul => fmath::mul(left, right, p),
+        });
+        regs_data.insert(gate.des_reg, 1 + n_i + counter);
+    }
+    z_vec
+}
+
+/// Runs every stage for the sample program and returns the stored proof with what verifies it
+fn run_pipeline(ws: &Workspace) -> Result<(ProofGenerationJson, impl Fn(&ProofGenerationJson) -> Result<bool>)> {
+    let device_config: DeviceConfigJson = read_json_file(DEVICE_CONFIG_PATH)?;
+    let class_number = device_config.class;
+    let class_data = ClassDataJson::get_class_data(CLASS_TABLE, class_number)?;
+    let (n_i, p) = (class_data.n_i as usize, class_data.p);
+
+    // Setup
+    let mut setup = Setup::default();
+    setup.generate_keys(class_data.d_ahp(), p, class_data.g);
+    setup.store(&ws.path("setup.json"), class_number)?;
+
+    // Commitment
+    let lines = DeviceConfigJson::convert_lines(&device_config.code_block)?;
+    let gates = Commitment::process_gates(parse_from_lines(lines, &PathBuf::from(PROGRAM_PATH))?);
+    Commitment::ensure_gates(&gates)?;
+    let z_vec = execute(&gates, n_i, p);
+
+    let commitment = Commitment::new(class_data).gen_matrices(gates, n_i, p).gen_polynomials(p)?.build();
+    ProgramParamsJson::new(&commitment.matrices, &commitment.points_px, class_data, p)
+        .store(&ws.path("program_params.json"))?;
+    commitment.store(&ws.path("program_commitment.json"), class_number, class_data, device_config.clone())?;
+
+    // Proof generation
+    let setup_json = Setup::restore(&ws.path("setup.json"))?;
+    let commitment_json = Commitment::restore(&ws.path("program_commitment.json"))?;
+    commitment_json.verify_id(&device_config)?;
+    let program_params = ProgramParamsJson::restore(&ws.path("program_params.json"))?;
+
+    let proof_generation = ProofGeneration::new();
+    let proof_data = proof_generation.generate_proof(
+        &setup_json.get_ck(),
+        class_data,
+        program_params,
+        commitment_json.clone(),
+        z_vec,
+        ProofGeneration::gen_random_b(&class_data),
+        p,
+    )?;
+    proof_generation.store(
+        &ws.path("proof.json"),
+        proof_data,
+        class_number,
+        commitment_json.info.commitment_id.clone(),
+        p,
+    )?;
+
+    // Verification
+    let proof = ProofGeneration::restore(&ws.path("proof.json"))?;
+    let vk = setup_json.get_vk();
+    let polys_px = commitment_json.get_polys_px();
+    let verify = move |proof: &ProofGenerationJson| {
+        let class_data = ClassDataJson::get_class_data(CLASS_TABLE, proof.class)?;
+        LightVerifier::new(proof).verify(vk, class_data, polys_px.clone(), proof.get_x_vec(), class_data.g, class_data.p)
+    };
+    Ok((proof, verify))
+}
+
+#[test]
+fn pipeline_verifies_sample_program() {
+    let ws = Workspace::new("pipeline_ok");
+    let (proof, verify) = run_pipeline(&ws).unwrap();
+
+    assert!(verify(&proof).unwrap());
+}
+
+#[test]
+fn pipeline_rejects_flipped_proof_field() {
+    let ws = Workspace::new("pipeline_flipped");
+    let (proof, verify) = run_pipeline(&ws).unwrap();
+
+    let mut tampered = proof.clone();
+    tampered.set_value(2, fmath::add(proof.get_value(2), 1, proof.p.unwrap()));
+    assert!(!matches!(verify(&tampered), Ok(true)));
+
+    let mut tampered = proof.clone();
+    tampered.set_sigma(2, fmath::add(proof.get_sigma(2), 1, proof.p.unwrap()));
+    assert!(!matches!(verify(&tampered), Ok(true)));
+}